@@ -4,15 +4,25 @@ const ZEST_PORT = 6969;
 const ZEST_ADDRESS = window.location.hostname; // same machine
 const CHUNK_SIZE = 1024 * 128; // 128 kb
 const AUTOPLAY_ENABLED = true;
+const EVENTS_RETRY_DELAY = 30 * 1000; // 30 seconds
+// MediaSource can only be fed slices of these. Others, like MP4 that isn't
+// fragmented, are streamed by the audio element itself.
+const SLICEABLE_CONTAINERS = ["mpeg", "adts"];
 
 const MUSIC_ENDPOINT =
     "http://" +
     `${ZEST_ADDRESS}:${ZEST_PORT}` +
     "/api/v1/music";
 
+const EVENTS_ENDPOINT =
+    "http://" +
+    `${ZEST_ADDRESS}:${ZEST_PORT}` +
+    "/api/v1/events";
+
 class AudioPlayer {
     constructor(audioPlayer) {
         this.audioPlayer = audioPlayer;
+        this.mimeType = "audio/mpeg";
 
         this.initQueue();
         this.initFetch();
@@ -48,7 +58,7 @@ class AudioPlayer {
 
     setupAudioPreprocessor() {
         this.mediaSource.addEventListener("sourceopen", () => {
-            this.audioBuffer = this.mediaSource.addSourceBuffer("audio/mpeg");
+            this.audioBuffer = this.mediaSource.addSourceBuffer(this.mimeType);
             this.audioBuffer.mode = "sequence";
             this.audioBuffer.addEventListener("update", () => {
                 if (this.audioQueue.length > 0 && this.audioBuffer && !this.audioBuffer.updating) {
@@ -109,8 +119,8 @@ class AudioPlayer {
         }
     }
 
-    fetchTrack(trackName) {
-        const url = MUSIC_ENDPOINT + `/get?name=${encodeURIComponent(trackName)}&chunk=0`;
+    fetchTrack(track) {
+        const url = MUSIC_ENDPOINT + `/get?id=${encodeURIComponent(track.id)}&chunk=0`;
 
         let fetchChunks = (url) => new Promise((resolve, reject) => {
             if (!this.shouldFetch) {
@@ -175,27 +185,49 @@ class AudioPlayer {
             .catch(() => "hii :3");
     }
 
-    resetAndPlayTrack(trackName) {
+    resetAndPlayTrack(track) {
+        // The source buffer is made for this type once the player is reset.
+        this.mimeType = track.mime;
+
         this.reset().then(() => {
-            this.playTrack(trackName);
+            if (isSliceable(track)) {
+                this.playTrack(track);
+            } else {
+                this.streamTrack(track);
+            }
         });
     }
 
-    playTrack(trackName) {
-        console.log(`Playing ${trackName}...`)
+    playTrack(track) {
+        console.log(`Playing ${track.path}...`)
 
-        this.fetchTrack(trackName);
-        this.setPlayingTrackName(trackName);
+        this.fetchTrack(track);
+        this.setPlayingTrackName(track.name);
 
         this.resume();
 
         this.waitForDuration();
     }
 
+    streamTrack(track) {
+        console.log(`Streaming ${track.path}...`)
+
+        this.audioPlayer.src = MUSIC_ENDPOINT + `/stream?id=${encodeURIComponent(track.id)}`;
+        this.setPlayingTrackName(track.name);
+
+        if (AUTOPLAY_ENABLED) {
+            this.audioPlayer.onended = () => this.nextTrack();
+        }
+
+        this.resume();
+    }
+
     reset() {
         this.shouldFetch = false;
 
         const resetPlayer = () => {
+            this.audioPlayer.onwaiting = null;
+            this.audioPlayer.onended = null;
             this.audioPlayer.src = "";
             this.audioPlayer.currentTime = 0;
             this.resetPlayingTrackName();
@@ -230,6 +262,10 @@ class AudioPlayer {
     }
 }
 
+function isSliceable(track) {
+    return SLICEABLE_CONTAINERS.includes(track.container) && MediaSource.isTypeSupported(track.mime);
+}
+
 class MusicList {
     constructor (trackListDivElement, searchInputElement, player) {
         this.searchInputElement = searchInputElement;
@@ -241,11 +277,31 @@ class MusicList {
     fetchTrackList() {
         fetch(MUSIC_ENDPOINT + "/all")
             .then((response) => response.json())
-            .then((trackNames) => {
-                this.trackList = trackNames;
+            .then((tracks) => {
+                this.trackList = tracks.filter((track) => isSliceable(track) || this.player.audioPlayer.canPlayType(track.mime) !== "");
                 this.updateTrackListElement(this.trackList);
             })
-            .catch(error => console.error("Error fetching track names:", error));
+            .catch(error => console.error("Error fetching tracks:", error));
+    }
+
+    subscribeToLibraryEvents() {
+        const events = new EventSource(EVENTS_ENDPOINT);
+
+        // EventSource reconnects by itself, sending the last event id it got.
+        ["index-reloaded", "track-added", "track-removed", "resync"].forEach((name) => {
+            events.addEventListener(name, () => this.fetchTrackList());
+        });
+
+        // Except when the server turns it away, for example when too many
+        // streams are open.
+        events.addEventListener("error", () => {
+            if (events.readyState === EventSource.CLOSED) {
+                setTimeout(() => {
+                    this.fetchTrackList();
+                    this.subscribeToLibraryEvents();
+                }, EVENTS_RETRY_DELAY);
+            }
+        });
     }
 
     updateTrackListElement(trackArray) {
@@ -255,19 +311,20 @@ class MusicList {
 
         this.trackListDivElement.innerHTML = null;
 
-        trackArray.forEach(trackName => {
+        trackArray.forEach(track => {
             const trackLink = document.createElement("a");
 
             trackLink.href = "#";
             trackLink.className = "main-trackList-trackEntry";
-            trackLink.innerText = trackName;
+            trackLink.innerText = track.name;
+            trackLink.title = track.path;
 
             trackLink.addEventListener("click", () => {
                 event.preventDefault();
 
-                this.player.resetAndPlayTrack(trackName);
+                this.player.resetAndPlayTrack(track);
 
-                const clickedSongIndex = trackArray.indexOf(trackName);
+                const clickedSongIndex = trackArray.indexOf(track);
 
                 this.player.queue = [
                     ...trackArray.slice(clickedSongIndex),
@@ -291,7 +348,7 @@ class MusicList {
             this.updateTrackListElement(this.trackList);
         } else if (event.key === "Enter") {
             const filteredTracks = this.trackList
-                .filter((track) => track.toLowerCase().includes(searchTerm));
+                .filter((track) => track.name.toLowerCase().includes(searchTerm));
 
             this.updateTrackListElement(filteredTracks);
         }
@@ -308,6 +365,7 @@ window.onload = () => {
     const musicList = new MusicList(trackList, searchInput, zestPlayer);
 
     musicList.fetchTrackList();
+    musicList.subscribeToLibraryEvents();
 
     searchInput.addEventListener("input", (event) => musicList.searchTracks(event));
     searchInput.addEventListener("keyup", (event) => musicList.searchTracks(event));
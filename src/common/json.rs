@@ -0,0 +1,496 @@
+use std::fmt::{self, Display, Write};
+use std::io::{Error, ErrorKind};
+
+/// Nesting deeper than this is rejected, instead of running out of stack.
+const MAX_DEPTH: usize = 128;
+
+/// Value of a JSON text, RFC 8259. Written back as compact JSON by `Display`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    /// Members in the order they were written. Duplicate keys are kept, and
+    /// `get` finds the first one.
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn parse(input: &str) -> Result<JsonValue, Error> {
+        let mut tokenizer = Tokenizer::new(input);
+
+        let value = parse_value(&mut tokenizer, 0)?;
+
+        match tokenizer.next_token()? {
+            None => Ok(value),
+            Some(_) => Err(tokenizer.error("Unexpected data after the value")),
+        }
+    }
+
+    /// Member of an object.
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members.iter().find(|(x, _)| x == key).map(|(_, x)| x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// Numbers that are whole and not negative.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            JsonValue::Number(x) if x.fract() == 0.0 && *x >= 0.0 && *x <= u64::MAX as f64 => Some(*x as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&[(String, JsonValue)]> {
+        match self {
+            JsonValue::Object(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self {
+        JsonValue::String(value.to_owned())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self {
+        JsonValue::String(value)
+    }
+}
+
+impl From<u64> for JsonValue {
+    fn from(value: u64) -> Self {
+        JsonValue::Number(value as f64)
+    }
+}
+
+impl Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonValue::Null => f.write_str("null"),
+            JsonValue::Bool(x) => write!(f, "{}", x),
+            // JSON has no infinities and NaNs.
+            JsonValue::Number(x) if !x.is_finite() => f.write_str("null"),
+            JsonValue::Number(x) => write!(f, "{}", x),
+            JsonValue::String(x) => write_quoted(f, x),
+            JsonValue::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            JsonValue::Object(members) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        f.write_char(',')?;
+                    }
+                    write_quoted(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+/// Quotes and escapes a string, so it can be put in JSON as is.
+pub fn quote(input: &str) -> String {
+    let mut quoted = String::with_capacity(input.len() + 2);
+    let _ = write_quoted(&mut quoted, input);
+    quoted
+}
+
+fn write_quoted<W: Write>(writer: &mut W, input: &str) -> fmt::Result {
+    writer.write_char('"')?;
+
+    for c in input.chars() {
+        match c {
+            '"' => writer.write_str("\\\"")?,
+            '\\' => writer.write_str("\\\\")?,
+            '\n' => writer.write_str("\\n")?,
+            '\r' => writer.write_str("\\r")?,
+            '\t' => writer.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => writer.write_char(c)?,
+        }
+    }
+
+    writer.write_char('"')
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    BeginObject,
+    EndObject,
+    BeginArray,
+    EndArray,
+    Colon,
+    Comma,
+    String(String),
+    Number(f64),
+    Bool(bool),
+    Null,
+}
+
+/// Splits JSON text into tokens. Strings come unescaped.
+pub struct Tokenizer<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Tokenizer { input, position: 0 }
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("{} at position {}", message, self.position))
+    }
+
+    fn peek_byte(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.position).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek_byte() {
+            self.position += 1;
+        }
+    }
+
+    /// `None` once the input is over.
+    pub fn next_token(&mut self) -> Result<Option<Token>, Error> {
+        self.skip_whitespace();
+
+        let Some(byte) = self.peek_byte() else {
+            return Ok(None);
+        };
+
+        let token = match byte {
+            b'{' => Token::BeginObject,
+            b'}' => Token::EndObject,
+            b'[' => Token::BeginArray,
+            b']' => Token::EndArray,
+            b':' => Token::Colon,
+            b',' => Token::Comma,
+            b'"' => return self.read_string().map(|x| Some(Token::String(x))),
+            b'-' | b'0'..=b'9' => return self.read_number().map(|x| Some(Token::Number(x))),
+            b't' => return self.read_literal("true", Token::Bool(true)).map(Some),
+            b'f' => return self.read_literal("false", Token::Bool(false)).map(Some),
+            b'n' => return self.read_literal("null", Token::Null).map(Some),
+            _ => return Err(self.error("Unexpected character")),
+        };
+
+        self.position += 1;
+
+        Ok(Some(token))
+    }
+
+    fn read_literal(&mut self, literal: &str, token: Token) -> Result<Token, Error> {
+        if !self.input[self.position..].starts_with(literal) {
+            return Err(self.error("Invalid literal"));
+        }
+
+        self.position += literal.len();
+
+        Ok(token)
+    }
+
+    fn read_number(&mut self) -> Result<f64, Error> {
+        let start = self.position;
+        let bytes = self.input.as_bytes();
+        let digits = |position: &mut usize| {
+            let from = *position;
+            while bytes.get(*position).is_some_and(|x| x.is_ascii_digit()) {
+                *position += 1;
+            }
+            *position > from
+        };
+
+        let mut position = start;
+
+        if bytes.get(position) == Some(&b'-') {
+            position += 1;
+        }
+
+        // No leading zeros, "0" itself is fine.
+        if bytes.get(position) == Some(&b'0') {
+            position += 1;
+        } else if !digits(&mut position) {
+            return Err(self.error("Invalid number"));
+        }
+
+        if bytes.get(position) == Some(&b'.') {
+            position += 1;
+            if !digits(&mut position) {
+                return Err(self.error("Invalid fraction"));
+            }
+        }
+
+        if let Some(b'e' | b'E') = bytes.get(position) {
+            position += 1;
+            if let Some(b'+' | b'-') = bytes.get(position) {
+                position += 1;
+            }
+            if !digits(&mut position) {
+                return Err(self.error("Invalid exponent"));
+            }
+        }
+
+        self.position = position;
+
+        self.input[start..position].parse::<f64>()
+            .map_err(|_| self.error("Invalid number"))
+    }
+
+    fn read_string(&mut self) -> Result<String, Error> {
+        // Opening quote.
+        self.position += 1;
+
+        let mut output = String::new();
+
+        loop {
+            let rest = &self.input[self.position..];
+
+            let Some(end) = rest.find(['"', '\\']) else {
+                return Err(self.error("Unterminated string"));
+            };
+
+            if rest[..end].chars().any(|x| (x as u32) < 0x20) {
+                return Err(self.error("Control character in a string"));
+            }
+
+            output.push_str(&rest[..end]);
+            self.position += end;
+
+            if rest.as_bytes()[end] == b'"' {
+                self.position += 1;
+                return Ok(output);
+            }
+
+            output.push(self.read_escape()?);
+        }
+    }
+
+    /// Escape sequence, including the backslash.
+    fn read_escape(&mut self) -> Result<char, Error> {
+        let Some(escaped) = self.input.as_bytes().get(self.position + 1).copied() else {
+            return Err(self.error("Unterminated escape"));
+        };
+
+        self.position += 2;
+
+        let c = match escaped {
+            b'"' => '"',
+            b'\\' => '\\',
+            b'/' => '/',
+            b'b' => '\u{8}',
+            b'f' => '\u{c}',
+            b'n' => '\n',
+            b'r' => '\r',
+            b't' => '\t',
+            b'u' => return self.read_unicode_escape(),
+            _ => return Err(self.error("Invalid escape")),
+        };
+
+        Ok(c)
+    }
+
+    /// Rest of `\uXXXX`. Characters outside the BMP come as a surrogate pair,
+    /// `\uD83C\uDFB5`.
+    fn read_unicode_escape(&mut self) -> Result<char, Error> {
+        let high = self.read_hex4()?;
+
+        if !(0xD800..0xDC00).contains(&high) {
+            return char::from_u32(high).ok_or_else(|| self.error("Lone low surrogate"));
+        }
+
+        if !self.input[self.position..].starts_with("\\u") {
+            return Err(self.error("Lone high surrogate"));
+        }
+
+        self.position += 2;
+
+        let low = self.read_hex4()?;
+
+        if !(0xDC00..0xE000).contains(&low) {
+            return Err(self.error("Invalid low surrogate"));
+        }
+
+        char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00))
+            .ok_or_else(|| self.error("Invalid surrogate pair"))
+    }
+
+    fn read_hex4(&mut self) -> Result<u32, Error> {
+        let hex = self.input.get(self.position..self.position + 4)
+            .filter(|x| x.bytes().all(|x| x.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("Invalid unicode escape"))?;
+
+        self.position += 4;
+
+        Ok(u32::from_str_radix(hex, 16).expect("Digits should be hexadecimal"))
+    }
+}
+
+fn parse_value(tokenizer: &mut Tokenizer, depth: usize) -> Result<JsonValue, Error> {
+    let Some(token) = tokenizer.next_token()? else {
+        return Err(tokenizer.error("Unexpected end of input"));
+    };
+
+    parse_from(token, tokenizer, depth)
+}
+
+fn parse_from(token: Token, tokenizer: &mut Tokenizer, depth: usize) -> Result<JsonValue, Error> {
+    if depth > MAX_DEPTH {
+        return Err(tokenizer.error("Nesting is too deep"));
+    }
+
+    let value = match token {
+        Token::Null => JsonValue::Null,
+        Token::Bool(x) => JsonValue::Bool(x),
+        Token::Number(x) => JsonValue::Number(x),
+        Token::String(x) => JsonValue::String(x),
+        Token::BeginArray => {
+            let mut items = Vec::new();
+
+            let mut token = tokenizer.next_token()?;
+
+            if token != Some(Token::EndArray) {
+                loop {
+                    let Some(item) = token else {
+                        return Err(tokenizer.error("Unterminated array"));
+                    };
+
+                    items.push(parse_from(item, tokenizer, depth + 1)?);
+
+                    match tokenizer.next_token()? {
+                        Some(Token::Comma) => token = tokenizer.next_token()?,
+                        Some(Token::EndArray) => break,
+                        _ => return Err(tokenizer.error("Expected ',' or ']'")),
+                    }
+                }
+            }
+
+            JsonValue::Array(items)
+        }
+        Token::BeginObject => {
+            let mut members = Vec::new();
+
+            let mut token = tokenizer.next_token()?;
+
+            if token != Some(Token::EndObject) {
+                loop {
+                    let Some(Token::String(key)) = token else {
+                        return Err(tokenizer.error("Expected a key"));
+                    };
+
+                    if tokenizer.next_token()? != Some(Token::Colon) {
+                        return Err(tokenizer.error("Expected ':'"));
+                    }
+
+                    members.push((key, parse_value(tokenizer, depth + 1)?));
+
+                    match tokenizer.next_token()? {
+                        Some(Token::Comma) => token = tokenizer.next_token()?,
+                        Some(Token::EndObject) => break,
+                        _ => return Err(tokenizer.error("Expected ',' or '}'")),
+                    }
+                }
+            }
+
+            JsonValue::Object(members)
+        }
+        _ => return Err(tokenizer.error("Unexpected token")),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_parse() {
+        let value = JsonValue::parse(" {\"a\": [1, -2.5e1, true, false, null], \"b\": {}, \"c\": []} ").unwrap();
+
+        assert_eq!(value.get("a").unwrap().as_array().unwrap(), [
+            JsonValue::Number(1.0),
+            JsonValue::Number(-25.0),
+            JsonValue::Bool(true),
+            JsonValue::Bool(false),
+            JsonValue::Null,
+        ]);
+        assert_eq!(value.get("b"), Some(&JsonValue::Object(vec![])));
+        assert_eq!(value.get("c").unwrap().as_array().unwrap().len(), 0);
+        assert_eq!(value.to_string(), "{\"a\":[1,-25,true,false,null],\"b\":{},\"c\":[]}");
+    }
+
+    #[test]
+    fn test_json_unescape() {
+        let value = JsonValue::parse(r#""q\"b\\s\/n\nt\tu\u00e9\ud83c\udfb5""#).unwrap();
+
+        assert_eq!(value.as_str(), Some("q\"b\\s/n\nt\tu\u{e9}\u{1f3b5}"));
+    }
+
+    #[test]
+    fn test_json_invalid() {
+        for input in [
+            "", "{", "[1,]", "{\"a\" 1}", "{1:2}", "01", "1.", "-", "tru", "\"a", "\"\\x\"",
+            "\"\\ud83c\"", "\"\\udfb5\"", "\"\\ud83c\\u0041\"", "\"\u{1}\"", "[] []", "nul",
+        ] {
+            assert!(JsonValue::parse(input).is_err(), "{:?} should be rejected", input);
+        }
+
+        let deep = "[".repeat(MAX_DEPTH + 2) + &"]".repeat(MAX_DEPTH + 2);
+        assert!(JsonValue::parse(&deep).is_err());
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        for name in [
+            "He said \"hi\"", "back\\slash", "tab\tand\nnewline", "\u{1}\u{1f}", "Мелодия",
+            "🎵 notes", "C:\\Music\\a.mp3", "", "{\"not\":\"json\"}",
+        ] {
+            let quoted = quote(name);
+            assert_eq!(JsonValue::parse(&quoted).unwrap().as_str(), Some(name));
+
+            let object = JsonValue::Object(vec![(name.to_owned(), JsonValue::from(name))]);
+            assert_eq!(JsonValue::parse(&object.to_string()).unwrap(), object);
+        }
+    }
+
+    #[test]
+    fn test_json_numbers() {
+        assert_eq!(JsonValue::parse("1792294995").unwrap().as_u64(), Some(1792294995));
+        assert_eq!(JsonValue::parse("0").unwrap().as_u64(), Some(0));
+        assert_eq!(JsonValue::parse("-1").unwrap().as_u64(), None);
+        assert_eq!(JsonValue::parse("1.5").unwrap().as_u64(), None);
+        assert_eq!(JsonValue::from(300000).to_string(), "300000");
+    }
+}
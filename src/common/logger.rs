@@ -165,6 +165,10 @@ impl Logger {
             use_verbosity: verbosity,
         };
     }
+
+    pub fn set_verbosity(&mut self, verbosity: Verbosity) {
+        self.use_verbosity = verbosity;
+    }
 }
 
 impl Log for Logger {
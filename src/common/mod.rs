@@ -1,3 +1,5 @@
+pub mod json;
 pub mod logger;
+pub mod sha1;
 pub mod threads;
 pub mod util;
@@ -1,113 +1,349 @@
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::any::Any;
+use std::io::{Error, ErrorKind};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread::{Builder, JoinHandle};
+use std::time::Duration;
 
-use crate::{common::logger::{Log, Logger}, DEFAULT_THREAD_COUNT};
+use crate::{common::logger::{Log, Logger}, DEFAULT_MAX_THREAD_COUNT};
 use crate::common::util::Am;
 use crate::log;
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
-struct Worker {
-    id: usize,
-    handle: Option<JoinHandle<()>>,
+/// Workers above the minimum exit after waiting this long for a job. Only one
+/// of them waits at a time, so the pool shrinks gradually.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Load of a pool, shared with whoever monitors it.
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    workers: AtomicUsize,
+    idle: AtomicUsize,
+    queue_limit: AtomicUsize,
+    /// Jobs waiting for a free worker.
+    queued: AtomicUsize,
+    /// Jobs that were turned away, since the queue was full.
+    rejected: AtomicUsize,
+    /// Jobs that panicked, and took their worker with them.
+    panicked: AtomicUsize,
 }
 
-impl Worker {
-    fn new(id: usize, receiver: Am<Receiver<Job>>, logger: Am<Logger>) -> Self {
-        let receiver_clone = receiver.clone();
+impl PoolStats {
+    pub fn workers(&self) -> usize {
+        self.workers.load(Ordering::Relaxed)
+    }
+
+    /// Workers running a job.
+    pub fn busy(&self) -> usize {
+        self.workers().saturating_sub(self.idle.load(Ordering::Relaxed))
+    }
+
+    pub fn queue_limit(&self) -> usize {
+        self.queue_limit.load(Ordering::Relaxed)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
 
-        let builder = Builder::new().name("worker".to_string());
+    pub fn panicked(&self) -> usize {
+        self.panicked.load(Ordering::Relaxed)
+    }
 
-        let worker_loop = move || loop {
-            let to_exec = match receiver_clone.lock() {
-                Ok(queue) => queue.recv(),
-                Err(err) => {
-                    log!(logger, "Shutting down: {}", err);
-                    break;
+    pub fn count_rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{\"workers\":{},\"busy\":{},\"queued\":{},\"queue_limit\":{},\"rejected\":{},\"panicked\":{}}}",
+            self.workers(), self.busy(), self.queued(), self.queue_limit(), self.rejected(), self.panicked())
+    }
+}
+
+/// Message of a panic, if it has one.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|x| x.as_str()))
+        .unwrap_or("Unknown reason")
+}
+
+/// Everything workers share with the pool.
+struct Shared {
+    receiver: Mutex<Receiver<Job>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    min_size: usize,
+    max_size: usize,
+    stats: Arc<PoolStats>,
+    logger: Am<Logger>,
+}
+
+impl Shared {
+    /// Adds a worker, unless there are `max_size` already.
+    fn grow(self: &Arc<Self>) {
+        let reserved = self.stats.workers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| (x < self.max_size).then_some(x + 1))
+            .is_ok();
+
+        if reserved {
+            self.spawn_worker();
+        }
+    }
+
+    /// Removes the calling worker from the count, unless there are only
+    /// `min_size` left. Returns whether it should exit.
+    fn retire(&self) -> bool {
+        self.stats.workers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| (x > self.min_size).then_some(x - 1))
+            .is_ok()
+    }
+
+    /// Starts a thread for a worker that was already counted.
+    fn spawn_worker(self: &Arc<Self>) {
+        let shared = self.clone();
+
+        match Builder::new().name("worker".to_string()).spawn(move || shared.worker_loop()) {
+            Ok(handle) => {
+                let mut handles = self.handles.lock().unwrap_or_else(|x| x.into_inner());
+                handles.retain(|x| !x.is_finished());
+                handles.push(handle);
+            }
+            Err(err) => {
+                self.stats.workers.fetch_sub(1, Ordering::Relaxed);
+                log!(self.logger, "*** An error occured while creating worker thread: {}", err);
+            }
+        }
+    }
+
+    fn worker_loop(self: Arc<Self>) {
+        loop {
+            self.stats.idle.fetch_add(1, Ordering::Relaxed);
+
+            // Receiving never panics, so a poisoned lock is still usable.
+            let to_exec = self.receiver.lock()
+                .unwrap_or_else(|x| x.into_inner())
+                .recv_timeout(IDLE_TIMEOUT);
+
+            self.stats.idle.fetch_sub(1, Ordering::Relaxed);
+
+            let job = match to_exec {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) if self.retire() => return,
+                Err(RecvTimeoutError::Timeout) => continue,
+                // Pool was dropped, there will be no more jobs.
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.stats.workers.fetch_sub(1, Ordering::Relaxed);
+                    return;
                 }
             };
 
-            if let Ok(job) = to_exec {
-                job()
-            }
-        };
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+
+            if let Err(payload) = catch_unwind(AssertUnwindSafe(job)) {
+                self.stats.panicked.fetch_add(1, Ordering::Relaxed);
+
+                log!(self.logger, "*** A job has panicked, replacing its worker: {}", panic_message(&*payload));
 
-        match builder.spawn(move || worker_loop()) {
-            Ok(thread) => Worker {
-                id: id,
-                handle: Some(thread),
-            },
-            Err(err) => panic!("*** An error occured while creating worker thread: {}", err),
+                // Whatever the job left in this thread can't be trusted, so a
+                // fresh one takes its place.
+                self.spawn_worker();
+                return;
+            }
         }
     }
 }
 
+/// Workers are added when jobs are waiting and there are no idle ones, up to
+/// the maximum size, and leave after being idle for a while, down to the
+/// minimum size.
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<Sender<Job>>,
-    logger: Am<Logger>,
-    size: usize,
+    sender: Option<SyncSender<Job>>,
+    shared: Arc<Shared>,
 }
 
 const MAX_THREAD_AMOUNT: usize = 1024 * 8;
 
 impl ThreadPool {
-    pub fn new(mut size: usize, logger: Am<Logger>) -> Self {
-        if size <= 0 || size > MAX_THREAD_AMOUNT {
-            log!(logger, "*** Thread pool size is invalid. Using default: {}", DEFAULT_THREAD_COUNT);
+    /// At most `queue_size` jobs can wait for a free worker, see `enqueue`.
+    /// Load is reported to `stats`.
+    pub fn new(min_size: usize, mut max_size: usize, queue_size: usize, stats: Arc<PoolStats>, logger: Am<Logger>) -> Self {
+        if max_size == 0 || max_size > MAX_THREAD_AMOUNT {
+            log!(logger, "*** Thread pool size is invalid. Using default: {}", DEFAULT_MAX_THREAD_COUNT);
 
-            size = 8;
+            max_size = DEFAULT_MAX_THREAD_COUNT;
         }
 
-        let mut workers = Vec::with_capacity(size);
+        let min_size = min_size.clamp(1, max_size);
+        let queue_size = queue_size.max(1);
 
-        let (sender, receiver) = channel();
-        let receiver_ref = Arc::new(Mutex::new(receiver));
+        let (sender, receiver) = sync_channel(queue_size);
+
+        stats.workers.store(0, Ordering::Relaxed);
+        stats.queue_limit.store(queue_size, Ordering::Relaxed);
+
+        let shared = Arc::new(Shared {
+            receiver: Mutex::new(receiver),
+            handles: Mutex::new(Vec::with_capacity(max_size)),
+            min_size,
+            max_size,
+            stats,
+            logger,
+        });
 
-        for id in 0..size {
-            workers.push(Worker::new(id, receiver_ref.clone(), logger.clone()));
+        for _ in 0..min_size {
+            shared.grow();
         }
 
         ThreadPool {
-            workers: workers,
             sender: Some(sender),
-            logger: logger,
-            size: size,
+            shared,
         }
     }
 
-    pub fn size(&self) -> usize {
-        self.size
+    pub fn min_size(&self) -> usize {
+        self.shared.min_size
     }
 
-    pub fn enqueue<F>(&self, func: F)
+    pub fn max_size(&self) -> usize {
+        self.shared.max_size
+    }
+
+    pub fn stats(&self) -> &PoolStats {
+        &self.shared.stats
+    }
+
+    /// Jobs waiting for a free worker.
+    pub fn queue_depth(&self) -> usize {
+        self.shared.stats.queued()
+    }
+
+    /// Whether `enqueue` would block until a worker takes a job.
+    pub fn is_full(&self) -> bool {
+        self.shared.stats.queued() >= self.shared.stats.queue_limit()
+    }
+
+    /// Blocks while the queue is full. `Err` if every worker is gone.
+    pub fn enqueue<F>(&self, func: F) -> Result<(), Error>
     where
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(func);
 
-        if let Some(sender) = &self.sender {
-            match sender.send(job) {
-                Ok(()) => {}
-                Err(_val) => todo!(),
-            }
-        } else {
+        let Some(sender) = &self.sender else {
             panic!("*** Dropped thread pool cannot execute more jobs.");
+        };
+
+        let stats = &self.shared.stats;
+        let queued = stats.queued.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if queued > stats.idle.load(Ordering::Relaxed) {
+            self.shared.grow();
         }
+
+        sender.send(job).map_err(|_| {
+            stats.queued.fetch_sub(1, Ordering::Relaxed);
+            Error::new(ErrorKind::BrokenPipe, "Every worker of the pool has stopped")
+        })
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        log!(self.logger, "Dropping thread pool...");
+        log!(self.shared.logger, "Dropping thread pool...");
         drop(self.sender.take());
 
-        for worker in &mut self.workers {
-            log!(self.logger, "Dropping worker {}...", worker.id);
-            if let Some(thread) = worker.handle.take() {
-                thread.join().unwrap();
-            }
+        let handles = std::mem::take(&mut *self.shared.handles.lock().unwrap_or_else(|x| x.into_inner()));
+
+        for handle in handles {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::logger::Verbosity;
+    use std::sync::mpsc::channel;
+
+    fn logger() -> Am<Logger> {
+        Arc::new(Mutex::new(Logger::new(0, false, Verbosity::Default)))
+    }
+
+    #[test]
+    fn test_pool_queue_depth() {
+        let stats = Arc::new(PoolStats::default());
+        let pool = ThreadPool::new(1, 1, 1, stats.clone(), logger());
+
+        let (started_sender, started) = channel();
+        let (release, released) = channel::<()>();
+
+        pool.enqueue(move || {
+            started_sender.send(()).unwrap();
+            released.recv().unwrap();
+        }).unwrap();
+        started.recv().unwrap();
+
+        assert!(!pool.is_full());
+
+        pool.enqueue(|| {}).unwrap();
+
+        assert_eq!(pool.queue_depth(), 1);
+        assert!(pool.is_full());
+        assert_eq!(stats.to_json(),
+            "{\"workers\":1,\"busy\":1,\"queued\":1,\"queue_limit\":1,\"rejected\":0,\"panicked\":0}");
+
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_pool_grows_when_busy() {
+        let stats = Arc::new(PoolStats::default());
+        let pool = ThreadPool::new(1, 3, 8, stats.clone(), logger());
+
+        let (started_sender, started) = channel();
+        let (release, released) = channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+
+        for _ in 0..3 {
+            let started_sender = started_sender.clone();
+            let released = released.clone();
+
+            pool.enqueue(move || {
+                started_sender.send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+            }).unwrap();
         }
+
+        // Every job runs at once, so each has its own worker.
+        for _ in 0..3 {
+            started.recv().unwrap();
+        }
+
+        assert_eq!(stats.workers(), 3);
+
+        drop(release);
+    }
+
+    #[test]
+    fn test_pool_replaces_panicked_worker() {
+        let stats = Arc::new(PoolStats::default());
+        let pool = ThreadPool::new(1, 1, 1, stats.clone(), logger());
+
+        pool.enqueue(|| panic!("Job has failed")).unwrap();
+
+        let (sender, receiver) = channel();
+        pool.enqueue(move || sender.send(()).unwrap()).unwrap();
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(stats.panicked(), 1);
+        assert_eq!(stats.workers(), 1);
     }
 }
@@ -2,6 +2,8 @@
 
 use std::{io::{Error, ErrorKind}, sync::{Arc, Mutex}, collections::HashMap, fmt::Display};
 
+use crate::common::json::{quote, JsonValue};
+
 pub type Am<T> = Arc<Mutex<T>>;
 
 pub type FilePath = String;
@@ -9,23 +11,23 @@ pub type FileName = String;
 
 pub type IndexMap = HashMap<FileName, FilePath>;
 
-pub fn iter_to_json_string<I: Iterator<Item = impl AsRef<str>>>(iter: I) -> String {
-    let mut s = String::from("[");
-
-    let mut peekable = iter.peekable();
-
-    while let Some(entry) = peekable.next() {
-        s += "\"";
-        s += entry.as_ref();
-        s += "\"";
-        if peekable.peek().is_some() {
-            s += ",";
-        }
+/// Result of a libc call, with `errno` as the error if it failed.
+#[cfg(target_os = "linux")]
+pub fn check_os_result(result: libc::c_int) -> Result<libc::c_int, Error> {
+    if result < 0 {
+        Err(Error::last_os_error())
+    } else {
+        Ok(result)
     }
+}
 
-    s += "]";
+pub fn iter_to_json_string<I: Iterator<Item = impl AsRef<str>>>(iter: I) -> String {
+    JsonValue::Array(iter.map(|x| JsonValue::from(x.as_ref())).collect()).to_string()
+}
 
-    s
+/// Quotes and escapes a string, so it can be put in JSON as is.
+pub fn json_string<S: AsRef<str>>(input: S) -> String {
+    quote(input.as_ref())
 }
 
 pub fn url_encode<S: Display>(input: S) -> String {
@@ -82,11 +84,36 @@ pub fn url_decode<S: AsRef<str>>(input: S) -> Result<String, Error> {
     })
 }
 
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Standard base64 with padding, RFC 4648, section 4.
+pub fn base64_encode<B: AsRef<[u8]>>(input: B) -> String {
+    let input = input.as_ref();
+    let mut encoded = String::with_capacity(input.len().div_ceil(3) * 4);
+
+    for chunk in input.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - i * 6)) & 0x3F;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
 #[cfg(test)]
 mod tests {
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    use super::{url_encode, url_decode, iter_to_json_string};
+    use super::{url_encode, url_decode, iter_to_json_string, base64_encode, json_string};
 
     fn rand(max: u32) -> usize {
         let start = SystemTime::now();
@@ -133,5 +160,21 @@ mod tests {
         let iter = vec!["Hello", "World"].into_iter();
 
         assert_eq!(iter_to_json_string(iter), "[\"Hello\",\"World\"]");
+        assert_eq!(iter_to_json_string(["He said \"hi\""].iter()), "[\"He said \\\"hi\\\"\"]");
     }
-}
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a \"b\"\n\\"), "\"a \\\"b\\\"\\n\\\\\"");
+        assert_eq!(json_string("\u{1}"), "\"\\u0001\"");
+    }
+
+    #[test]
+    fn base64_encode_padding() {
+        assert_eq!(base64_encode(""), "");
+        assert_eq!(base64_encode("f"), "Zg==");
+        assert_eq!(base64_encode("fo"), "Zm8=");
+        assert_eq!(base64_encode("foo"), "Zm9v");
+        assert_eq!(base64_encode("foobar"), "Zm9vYmFy");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,164 @@
+use std::fs::File;
+use std::io::{copy, Error, Read, Seek, SeekFrom, Write};
+
+const STREAM_BUFFER_SIZE: usize = 1024 * 64; // 64 kb
+
+pub type BodyChunks<'a> = Box<dyn Iterator<Item = Result<Vec<u8>, Error>> + Send + 'a>;
+
+/// Body of an `HttpResponse`. Everything except `Bytes` is streamed to the
+/// connection without being held in memory.
+pub enum ResponseBody<'a> {
+    Bytes(&'a [u8]),
+    Owned(Vec<u8>),
+    /// Reads until EOF. When the length is unknown, the body is sent with
+    /// `Transfer-Encoding: chunked`.
+    Reader(Box<dyn Read + Send + 'a>, Option<u64>),
+    /// Buffers produced one after another, always of unknown length.
+    Chunks(BodyChunks<'a>),
+}
+
+impl std::fmt::Debug for ResponseBody<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResponseBody::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            ResponseBody::Owned(bytes) => write!(f, "Owned({} bytes)", bytes.len()),
+            ResponseBody::Reader(_, length) => write!(f, "Reader({:?})", length),
+            ResponseBody::Chunks(_) => write!(f, "Chunks"),
+        }
+    }
+}
+
+impl<'a> ResponseBody<'a> {
+    /// Length in bytes, if it is known before sending.
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            ResponseBody::Bytes(bytes) => Some(bytes.len() as u64),
+            ResponseBody::Owned(bytes) => Some(bytes.len() as u64),
+            ResponseBody::Reader(_, length) => *length,
+            ResponseBody::Chunks(_) => None,
+        }
+    }
+
+    /// Writes the body as is.
+    pub fn write_to(self, writer: &mut impl Write) -> Result<(), Error> {
+        match self {
+            ResponseBody::Bytes(bytes) => writer.write_all(bytes),
+            ResponseBody::Owned(bytes) => writer.write_all(&bytes),
+            ResponseBody::Reader(mut reader, _) => copy(&mut reader, writer).map(|_| ()),
+            ResponseBody::Chunks(chunks) => {
+                for chunk in chunks {
+                    writer.write_all(&chunk?)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the body with chunked transfer coding, RFC 9112, section 7.1.
+    pub fn write_chunked_to(self, writer: &mut impl Write) -> Result<(), Error> {
+        match self {
+            ResponseBody::Bytes(bytes) => write_chunk(writer, bytes)?,
+            ResponseBody::Owned(bytes) => write_chunk(writer, &bytes)?,
+            ResponseBody::Reader(mut reader, _) => {
+                let mut buffer = vec![0; STREAM_BUFFER_SIZE];
+
+                loop {
+                    let bytes_read = reader.read(&mut buffer)?;
+
+                    if bytes_read == 0 {
+                        break;
+                    }
+
+                    write_chunk(writer, &buffer[..bytes_read])?;
+                }
+            }
+            ResponseBody::Chunks(chunks) => {
+                for chunk in chunks {
+                    write_chunk(writer, &chunk?)?;
+                }
+            }
+        }
+
+        writer.write_all(b"0\r\n\r\n")
+    }
+}
+
+fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> Result<(), Error> {
+    // A zero-sized chunk would end the body.
+    if chunk.is_empty() {
+        return Ok(());
+    }
+
+    write!(writer, "{:X}\r\n", chunk.len())?;
+    writer.write_all(chunk)?;
+    writer.write_all(b"\r\n")
+}
+
+/// Part of a file, read from `start` for `length` bytes.
+///
+/// The seek is delayed until the first read, so several regions can share
+/// one file descriptor as long as they are read one after another.
+pub struct FileRegion {
+    file: File,
+    start: u64,
+    remaining: u64,
+    seeked: bool,
+}
+
+impl FileRegion {
+    pub fn new(file: File, start: u64, length: u64) -> Self {
+        FileRegion {
+            file,
+            start,
+            remaining: length,
+            seeked: false,
+        }
+    }
+}
+
+impl Read for FileRegion {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.seeked {
+            self.file.seek(SeekFrom::Start(self.start))?;
+            self.seeked = true;
+        }
+
+        let max = buf.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+
+        if max == 0 {
+            return Ok(0);
+        }
+
+        let bytes_read = self.file.read(&mut buf[..max])?;
+        self.remaining -= bytes_read as u64;
+
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_write_chunked_reader() {
+        let body = ResponseBody::Reader(Box::new(Cursor::new(b"hello".to_vec())), None);
+        let mut output = Vec::new();
+
+        body.write_chunked_to(&mut output).unwrap();
+
+        assert_eq!(output, b"5\r\nhello\r\n0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_write_chunked_iterator() {
+        let chunks = vec![Ok(b"ab".to_vec()), Ok(Vec::new()), Ok(b"cdefghijklmnopq".to_vec())];
+        let body = ResponseBody::Chunks(Box::new(chunks.into_iter()));
+        let mut output = Vec::new();
+
+        body.write_chunked_to(&mut output).unwrap();
+
+        assert_eq!(output, b"2\r\nab\r\nF\r\ncdefghijklmnopq\r\n0\r\n\r\n");
+    }
+}
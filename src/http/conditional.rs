@@ -0,0 +1,242 @@
+use std::fs::Metadata;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Validators of a representation, used to answer conditional requests.
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    /// Strong entity tag, including the quotes.
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// Derives validators from size and modification time of a file.
+    pub fn for_file(metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok();
+        let mtime = modified
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |x| x.as_secs());
+
+        Validators {
+            etag: Some(format!("\"{:x}-{:x}\"", mtime, metadata.len())),
+            last_modified: modified,
+        }
+    }
+
+    /// Derives validators from a generation number, which changes every time
+    /// the underlying data does. Generation is nanoseconds since the epoch.
+    pub fn for_generation(generation: u64) -> Self {
+        Validators {
+            etag: Some(format!("\"g{:x}\"", generation)),
+            last_modified: Some(UNIX_EPOCH + Duration::from_nanos(generation)),
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
+
+/// Formats time as IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn format_http_date(time: SystemTime) -> String {
+    let secs = unix_seconds(time);
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAY_NAMES[(days % 7) as usize],
+        day,
+        MONTH_NAMES[month as usize - 1],
+        year,
+        (secs / 3600) % 24,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+/// Parses IMF-fixdate. Obsolete formats are not supported, and dates in them
+/// are treated as invalid, which makes conditions that use them be ignored.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.split_whitespace();
+
+    let _day_name = parts.next()?.strip_suffix(',')?;
+    let day = parts.next()?.parse::<u32>().ok()?;
+    let month_name = parts.next()?;
+    let month = MONTH_NAMES.iter().position(|&x| x == month_name)? as u32 + 1;
+    let year = parts.next()?.parse::<i64>().ok()?;
+
+    let mut time = parts.next()?.split(':').map(|x| x.parse::<u64>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next()??);
+
+    if parts.next()? != "GMT" || parts.next().is_some() || time.next().is_some() {
+        return None;
+    }
+
+    if !(1..=31).contains(&day) || hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hours * 3600 + minutes * 60 + seconds))
+}
+
+/// Converts days since the epoch to a date, using Howard Hinnant's algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+fn opaque_tag(tag: &str) -> &str {
+    tag.trim().trim_start_matches("W/")
+}
+
+/// Whether `If-None-Match` matches, using weak comparison.
+fn none_match(header: &str, etag: &str) -> bool {
+    header.trim() == "*"
+        || header.split(',').any(|tag| opaque_tag(tag) == opaque_tag(etag))
+}
+
+/// Whether the client's cached copy is still fresh, so a GET or HEAD can be
+/// answered with 304. RFC 9110, section 13.2.2.
+///
+/// `If-Modified-Since` is only evaluated when `If-None-Match` is absent, and
+/// ignored when its date is in the future.
+pub fn is_not_modified(
+    if_none_match: Option<&String>,
+    if_modified_since: Option<&String>,
+    validators: &Validators,
+) -> bool {
+    if let Some(header) = if_none_match {
+        return validators.etag.as_ref().is_some_and(|etag| none_match(header, etag));
+    }
+
+    let since = if_modified_since
+        .and_then(|x| parse_http_date(x))
+        .filter(|x| *x <= SystemTime::now());
+
+    match (since, validators.last_modified) {
+        (Some(since), Some(modified)) => unix_seconds(modified) <= unix_seconds(since),
+        _ => false,
+    }
+}
+
+/// Whether `If-Range` allows the `Range` header to be honoured. Entity tags
+/// are compared strongly, dates have to match exactly.
+pub fn if_range_matches(if_range: Option<&String>, validators: &Validators) -> bool {
+    let Some(if_range) = if_range.map(|x| x.trim()) else {
+        return true;
+    };
+
+    if if_range.starts_with('"') {
+        validators.etag.as_deref() == Some(if_range)
+    } else if if_range.starts_with("W/") {
+        false
+    } else {
+        match (parse_http_date(if_range), validators.last_modified) {
+            (Some(date), Some(modified)) => unix_seconds(date) == unix_seconds(modified),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+        }
+    }
+
+    #[test]
+    fn test_http_date_round_trip() {
+        let date = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let time = parse_http_date(date).unwrap();
+
+        assert_eq!(unix_seconds(time), 784111777);
+        assert_eq!(format_http_date(time), date);
+
+        let leap = "Thu, 29 Feb 2024 23:59:59 GMT";
+        assert_eq!(format_http_date(parse_http_date(leap).unwrap()), leap);
+    }
+
+    #[test]
+    fn test_http_date_invalid() {
+        assert!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT").is_none());
+        assert!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST").is_none());
+        assert!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT").is_none());
+        assert!(parse_http_date("").is_none());
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let validators = validators();
+
+        assert!(is_not_modified(Some(&"\"abc\"".into()), None, &validators));
+        assert!(is_not_modified(Some(&"\"x\", W/\"abc\"".into()), None, &validators));
+        assert!(is_not_modified(Some(&"*".into()), None, &validators));
+        assert!(!is_not_modified(Some(&"\"x\"".into()), None, &validators));
+
+        // If-Modified-Since is ignored when If-None-Match is present.
+        let since = "Mon, 07 Nov 1994 08:49:37 GMT".to_string();
+        assert!(!is_not_modified(Some(&"\"x\"".into()), Some(&since), &validators));
+    }
+
+    #[test]
+    fn test_if_modified_since() {
+        let validators = validators();
+
+        let later = "Mon, 07 Nov 1994 08:49:37 GMT".to_string();
+        let same = "Sun, 06 Nov 1994 08:49:37 GMT".to_string();
+        let earlier = "Sat, 05 Nov 1994 08:49:37 GMT".to_string();
+
+        assert!(is_not_modified(None, Some(&later), &validators));
+        assert!(is_not_modified(None, Some(&same), &validators));
+        assert!(!is_not_modified(None, Some(&earlier), &validators));
+        assert!(!is_not_modified(None, Some(&"garbage".into()), &validators));
+
+        let future = format_http_date(SystemTime::now() + Duration::from_secs(86400));
+        assert!(!is_not_modified(None, Some(&future), &validators));
+    }
+
+    #[test]
+    fn test_if_range() {
+        let validators = validators();
+
+        assert!(if_range_matches(None, &validators));
+        assert!(if_range_matches(Some(&"\"abc\"".into()), &validators));
+        assert!(!if_range_matches(Some(&"W/\"abc\"".into()), &validators));
+        assert!(!if_range_matches(Some(&"\"x\"".into()), &validators));
+        assert!(if_range_matches(Some(&"Sun, 06 Nov 1994 08:49:37 GMT".into()), &validators));
+        assert!(!if_range_matches(Some(&"Mon, 07 Nov 1994 08:49:37 GMT".into()), &validators));
+    }
+}
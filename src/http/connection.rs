@@ -1,10 +1,17 @@
 use crate::common::util::url_decode;
+use crate::http::conditional::{if_range_matches, is_not_modified, Validators};
+use crate::http::cors::CorsPolicy;
+use crate::http::response::HttpResponse;
+use crate::http::stream::ConnectionStream;
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind, Read, Write};
-use std::net::{TcpStream, Shutdown};
 use std::str;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[repr(u8)]
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HttpMethod {
     Unknown,
@@ -13,6 +20,8 @@ pub enum HttpMethod {
     PUT,
     PATCH,
     DELETE,
+    HEAD,
+    OPTIONS,
 }
 
 impl Default for HttpMethod {
@@ -21,7 +30,7 @@ impl Default for HttpMethod {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum HttpVersion {
     V0_9,
     V1,
@@ -39,14 +48,37 @@ type Headers = HashMap<String, String>;
 type Parameters = HashMap<String, String>;
 type Path = String;
 
+/// Values of `:name` segments of the route a request was matched to.
+#[derive(Debug, Default, Clone)]
+pub struct PathParams(HashMap<String, String>);
+
+impl PathParams {
+    pub fn insert<S: Into<String>>(&mut self, name: S, value: S) {
+        self.0.insert(name.into(), value.into());
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|x| x.as_str())
+    }
+
+    /// `None` if there is no such segment, or it is not a valid `T`.
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get_str(name).and_then(|x| x.parse::<T>().ok())
+    }
+}
+
 #[derive(Debug, Default)]
 struct HttpRequest {
     method: HttpMethod,
     path: Path,
     raw_path: String,
+    /// Request target as it was sent, not decoded.
+    target: String,
     version: HttpVersion,
     headers: Headers,
     parameters: Option<Parameters>,
+    path_params: PathParams,
+    body: Vec<u8>,
 }
 
 fn parse_request_line(line: &str, request: &mut HttpRequest) -> Result<(), Error> {
@@ -64,6 +96,8 @@ fn parse_request_line(line: &str, request: &mut HttpRequest) -> Result<(), Error
         "put" => HttpMethod::PUT,
         "patch" => HttpMethod::PATCH,
         "delete" => HttpMethod::DELETE,
+        "head" => HttpMethod::HEAD,
+        "options" => HttpMethod::OPTIONS,
         _ => {
             let message = "Invalid method";
             let err = Error::new(ErrorKind::InvalidInput, message);
@@ -74,6 +108,14 @@ fn parse_request_line(line: &str, request: &mut HttpRequest) -> Result<(), Error
     let raw_path = parts.next().unwrap().to_owned();
     let decoded_raw_path = url_decode(&raw_path)?;
 
+    // Decoded line breaks would let the target end up as headers of
+    // responses that include it, like redirects.
+    if decoded_raw_path.chars().any(|x| x.is_control()) {
+        let message = "Request target contains control characters";
+        let err = Error::new(ErrorKind::InvalidInput, message);
+        return Err(err);
+    }
+
     let mut path_split = raw_path.split('?');
 
     let path = path_split.next().unwrap_or("/").to_owned();
@@ -92,7 +134,8 @@ fn parse_request_line(line: &str, request: &mut HttpRequest) -> Result<(), Error
                 continue;
             }
 
-            let value = key_value.next().unwrap();
+            // Parameters without `=`, like `?foo`, are flags with an empty value.
+            let value = key_value.next().unwrap_or("");
             let key = key.unwrap();
 
             let decoded_key = url_decode(key)?;
@@ -123,79 +166,555 @@ fn parse_request_line(line: &str, request: &mut HttpRequest) -> Result<(), Error
     request.method = method;
     request.path = decoded_path;
     request.raw_path = decoded_raw_path;
+    request.target = raw_path;
     request.version = version;
     request.parameters = parameters;
 
     Ok(())
 }
 
-fn parse_header_line(line: &str, headers: &mut HashMap<String, String>) -> Result<(), Error> {
-    if let Some((key, value)) = line.split_once(':') {
+/// Field names are tokens, RFC 9110, section 5.6.2.
+fn is_token(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|&byte| {
+        byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+    })
+}
 
-        let trimmed_key = key.trim().to_lowercase().to_owned();
-        let trimmed_value = value.trim().to_owned();
+fn trim_whitespace(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
 
-        headers.insert(trimmed_key, trimmed_value);
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
 
-        Ok(())
-    } else {
+    bytes
+}
+
+/// Header values are mostly ASCII. UTF-8 is kept as is, anything else is
+/// decoded as ISO-8859-1, so no bytes are lost.
+fn decode_header_value(value: &[u8]) -> String {
+    match str::from_utf8(value) {
+        Ok(value) => value.to_owned(),
+        Err(_) => value.iter().map(|&byte| byte as char).collect(),
+    }
+}
+
+fn parse_header_line<L: AsRef<[u8]>>(line: L, headers: &mut Headers) -> Result<(), Error> {
+    let line = line.as_ref();
+
+    let Some(colon) = line.iter().position(|&x| x == b':') else {
         let message = "Invalid header line";
         let err = Error::new(ErrorKind::InvalidInput, message);
-        Err(err)
+        return Err(err);
+    };
+
+    let key = trim_whitespace(&line[..colon]);
+
+    if !is_token(key) {
+        let message = "Invalid header name";
+        let err = Error::new(ErrorKind::InvalidInput, message);
+        return Err(err);
+    }
+
+    let key = String::from_utf8_lossy(key).to_ascii_lowercase();
+    let value = decode_header_value(trim_whitespace(&line[colon + 1..]));
+
+    // Repeated fields are equivalent to one field with a comma-separated list.
+    match headers.get_mut(&key) {
+        Some(existing) => {
+            existing.push_str(", ");
+            existing.push_str(&value);
+        }
+        None => {
+            headers.insert(key, value);
+        }
     }
+
+    Ok(())
 }
 
 const MAX_HEADER_SIZE: usize = 1024 * 4;
 
-fn parse_http_request(stream: &mut TcpStream) -> Result<HttpRequest, Error> {
-    let mut total_bytes_read = 0;
-    let mut current_line = String::new();
-    let mut prev_character: Option<char> = None;
-    let mut buffer = [0; 1];
+pub const DEFAULT_KEEP_ALIVE_TIMEOUT: u64 = 5;
+pub const DEFAULT_MAX_REQUESTS: usize = 100;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024; // 1 mb
+pub const DEFAULT_HEADER_TIMEOUT: u64 = 10;
+pub const DEFAULT_BODY_TIMEOUT: u64 = 30;
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+pub const DEFAULT_WRITE_TIMEOUT: u64 = 30;
+
+/// Limits and policies applied to every connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionConfig {
+    /// How long to wait for the next request on an idle persistent connection.
+    pub keep_alive_timeout: Duration,
+    /// How many requests can be served with one connection before it is closed.
+    pub max_requests: usize,
+    /// Requests with larger bodies are rejected with 413.
+    pub max_body_size: usize,
+    /// How long the client has to send the request line and headers.
+    pub header_timeout: Duration,
+    /// How long the client has to send the body, after the headers.
+    pub body_timeout: Duration,
+    /// How long the client has to send the whole request.
+    pub request_timeout: Duration,
+    /// How long a single write can be blocked by a client not reading.
+    pub write_timeout: Duration,
+    pub cors: Arc<CorsPolicy>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        ConnectionConfig {
+            keep_alive_timeout: Duration::from_secs(DEFAULT_KEEP_ALIVE_TIMEOUT),
+            max_requests: DEFAULT_MAX_REQUESTS,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            header_timeout: Duration::from_secs(DEFAULT_HEADER_TIMEOUT),
+            body_timeout: Duration::from_secs(DEFAULT_BODY_TIMEOUT),
+            request_timeout: Duration::from_secs(DEFAULT_REQUEST_TIMEOUT),
+            write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT),
+            cors: Arc::new(CorsPolicy::default()),
+        }
+    }
+}
+
+/// Whether the client wants the connection to persist after this request.
+/// HTTP/1.1 connections persist unless `Connection: close` is sent, HTTP/1.0
+/// ones only with `Connection: keep-alive`.
+fn wants_keep_alive(request: &HttpRequest) -> bool {
+    let has_token = |token: &str| {
+        request.headers.get("connection").is_some_and(|value| {
+            value.split(',').any(|x| x.trim().eq_ignore_ascii_case(token))
+        })
+    };
+
+    match request.version {
+        HttpVersion::V1_1 => !has_token("close"),
+        HttpVersion::V1 => has_token("keep-alive"),
+        _ => false,
+    }
+}
+
+fn timed_out<S: AsRef<str>>(message: S) -> Error {
+    Error::new(ErrorKind::TimedOut, message.as_ref())
+}
+
+/// Stream that fails reads with `TimedOut` once the deadline passes, no
+/// matter how slowly the client trickles bytes in.
+struct DeadlineStream<'a> {
+    stream: &'a mut ConnectionStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return Err(timed_out("Request was not received in time"));
+        }
+
+        self.stream.tcp().set_read_timeout(Some(remaining))?;
+
+        self.stream.read(buf).map_err(|err| match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                timed_out("Request was not received in time")
+            }
+            _ => err,
+        })
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+const READ_BUFFER_SIZE: usize = 1024 * 8;
+
+/// Bytes received from the client, but not parsed yet. Whatever is left after
+/// a request, be it a part of its body or the next pipelined request, stays
+/// here until it is needed.
+#[derive(Debug, Default)]
+struct ReadBuffer {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl ReadBuffer {
+    fn unread(&self) -> &[u8] {
+        &self.bytes[self.position..]
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+
+        if self.position >= self.bytes.len() {
+            self.bytes.clear();
+            self.position = 0;
+        }
+    }
+
+    /// Appends whatever the stream has at the moment. Returns amount of bytes
+    /// read, 0 meaning EOF.
+    fn fill(&mut self, stream: &mut impl Read) -> Result<usize, Error> {
+        if self.position > 0 {
+            self.bytes.drain(..self.position);
+            self.position = 0;
+        }
+
+        let length = self.bytes.len();
+        self.bytes.resize(length + READ_BUFFER_SIZE, 0);
+
+        let result = stream.read(&mut self.bytes[length..]);
+        self.bytes.truncate(length + *result.as_ref().unwrap_or(&0));
+
+        result
+    }
+}
+
+/// Reads buffered bytes first, and then the stream itself.
+struct BufferedStream<'a, S> {
+    buffer: &'a mut ReadBuffer,
+    stream: S,
+}
+
+impl<S: Read> Read for BufferedStream<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.buffer.unread().is_empty() {
+            // Large reads don't need to be copied twice.
+            if buf.len() >= READ_BUFFER_SIZE {
+                return self.stream.read(buf);
+            }
+
+            if self.buffer.fill(&mut self.stream)? == 0 {
+                return Ok(0);
+            }
+        }
+
+        let unread = self.buffer.unread();
+        let amount = unread.len().min(buf.len());
+
+        buf[..amount].copy_from_slice(&unread[..amount]);
+        self.buffer.consume(amount);
+
+        Ok(amount)
+    }
+}
+
+impl<S: Write> Write for BufferedStream<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+fn malformed<S: AsRef<str>>(message: S) -> Error {
+    Error::new(ErrorKind::InvalidData, message.as_ref())
+}
+
+/// Finds the empty line that ends the head of the request, and returns the
+/// length of the head including it. Search starts from `from`, so bytes that
+/// were already searched are not looked at again.
+fn find_head_end(bytes: &[u8], from: usize) -> Option<usize> {
+    let mut position = from;
 
-    let mut request: HttpRequest = Default::default();
+    while let Some(offset) = bytes[position..].iter().position(|&x| x == b'\n') {
+        let newline = position + offset;
+
+        match &bytes[newline + 1..] {
+            [b'\n', ..] => return Some(newline + 2),
+            [b'\r', b'\n', ..] => return Some(newline + 3),
+            _ => position = newline + 1,
+        }
+    }
+
+    None
+}
+
+/// Parses the request line and header fields, without the final empty line.
+fn parse_head(head: &[u8]) -> Result<HttpRequest, Error> {
+    let mut request = HttpRequest::default();
+
+    for (index, line) in head.split(|&x| x == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        if line.contains(&b'\r') {
+            return Err(malformed("Bare CR in the request head"));
+        }
+
+        if index == 0 {
+            let line = str::from_utf8(line)
+                .map_err(|_| malformed("Request line is not valid UTF-8"))?;
+
+            parse_request_line(line, &mut request)?;
+        } else if line.is_empty() {
+            break;
+        } else if line[0] == b' ' || line[0] == b'\t' {
+            return Err(malformed("Obsolete line folding is not allowed"));
+        } else {
+            parse_header_line(line, &mut request.headers)?;
+        }
+    }
+
+    Ok(request)
+}
+
+fn header_too_large() -> Error {
+    Error::new(ErrorKind::OutOfMemory, format!("Header size exceeded {} bytes", MAX_HEADER_SIZE))
+}
+
+/// Parses the head of the next request, buffering the stream as needed.
+/// Bytes after the head are left in the buffer.
+///
+/// Returns `None` if the stream was closed before any bytes of the request
+/// arrived.
+fn parse_http_request(buffer: &mut ReadBuffer, stream: &mut impl Read) -> Result<Option<HttpRequest>, Error> {
+    let mut searched = 0;
 
     loop {
-        match stream.read(&mut buffer)? {
-            0 => break,
-            _ => {
-                let character = buffer[0] as char;
-                total_bytes_read += 1;
-
-                if character == '\n' {
-                    if prev_character == Some('\r') && current_line.is_empty() {
-                        return Ok(request);
-                    } else if request.path.is_empty() {
-                        parse_request_line(&current_line, &mut request)?;
-                    } else {
-                        parse_header_line(&current_line, &mut request.headers)?;
-                    }
-
-                    current_line.clear();
-                } else if character != '\r' {
-                    current_line.push(character);
+        // Empty lines before the request line are ignored, RFC 9112, section 2.2.
+        let leading = buffer.unread().iter().take_while(|&&x| x == b'\r' || x == b'\n').count();
+        buffer.consume(leading);
+
+        let unread = buffer.unread();
+
+        if !unread.is_empty() {
+            if let Some(end) = find_head_end(unread, searched) {
+                // Same limit however the head was split between reads.
+                if end > MAX_HEADER_SIZE {
+                    return Err(header_too_large());
                 }
 
-                prev_character = Some(character);
+                let request = parse_head(&unread[..end])?;
+                buffer.consume(end);
+
+                return Ok(Some(request));
+            }
+
+            searched = unread.len().saturating_sub(2);
+        }
+
+        if unread.len() > MAX_HEADER_SIZE {
+            return Err(header_too_large());
+        }
+
+        let was_empty = unread.is_empty();
+
+        if buffer.fill(stream)? == 0 {
+            if was_empty {
+                return Ok(None);
             }
+
+            return Err(malformed("Malformed headers"));
         }
+    }
+}
 
-        if total_bytes_read > MAX_HEADER_SIZE {
-            let message = format!("Header size exceeded {} bytes", MAX_HEADER_SIZE);
-            let err = std::io::Error::new(ErrorKind::OutOfMemory, message);
-            return Err(err);
+fn invalid_body<S: AsRef<str>>(message: S) -> Error {
+    malformed(message)
+}
+
+fn body_too_large(max_body_size: usize) -> Error {
+    let message = format!("Body size exceeded {} bytes", max_body_size);
+    Error::new(ErrorKind::FileTooLarge, message)
+}
+
+/// Reads a CRLF-terminated line, used for chunk sizes and trailers.
+fn read_line(stream: &mut impl Read) -> Result<String, Error> {
+    let mut line = Vec::new();
+    let mut buffer = [0; 1];
+
+    loop {
+        stream.read_exact(&mut buffer)?;
+
+        match buffer[0] {
+            b'\n' => break,
+            byte => line.push(byte),
         }
+
+        if line.len() > MAX_HEADER_SIZE {
+            return Err(invalid_body("Chunk line is too long"));
+        }
+    }
+
+    if line.pop() != Some(b'\r') {
+        return Err(invalid_body("Chunk line is not terminated with CRLF"));
     }
 
-    let message = format!("Malformed headers");
-    let err = std::io::Error::new(ErrorKind::InvalidData, message);
-    return Err(err);
+    String::from_utf8(line).map_err(|err| invalid_body(err.to_string()))
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body. Trailer fields are discarded.
+fn read_chunked_body(stream: &mut impl Read, max_body_size: usize) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+
+    loop {
+        let line = read_line(stream)?;
+        let size = line.split(';').next().unwrap_or("").trim();
+
+        // `from_str_radix` would also take a sign.
+        if size.is_empty() || !size.bytes().all(|x| x.is_ascii_hexdigit()) {
+            return Err(invalid_body(format!("Invalid chunk size '{}'", size)));
+        }
+
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| body_too_large(max_body_size))?;
+
+        if size == 0 {
+            break;
+        }
+
+        // Sizes near `usize::MAX` would overflow the sum.
+        if size > max_body_size - body.len() {
+            return Err(body_too_large(max_body_size));
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..])?;
+
+        if !read_line(stream)?.is_empty() {
+            return Err(invalid_body("Chunk data is longer than its size"));
+        }
+    }
+
+    while !read_line(stream)?.is_empty() {}
+
+    Ok(body)
+}
+
+/// How the length of the request body is determined, RFC 9112, section 6.3.
+#[derive(Debug, PartialEq)]
+enum BodyLength {
+    Empty,
+    Fixed(usize),
+    Chunked,
+}
+
+fn body_length(request: &HttpRequest) -> Result<BodyLength, Error> {
+    let transfer_encoding = request.headers.get("transfer-encoding");
+    let content_length = request.headers.get("content-length");
+
+    match (transfer_encoding, content_length) {
+        (Some(_), Some(_)) => {
+            // Ambiguous framing is how requests get smuggled past proxies.
+            Err(invalid_body("Both Transfer-Encoding and Content-Length are present"))
+        }
+        (Some(encoding), None) => {
+            let last = encoding.rsplit(',').next().unwrap_or("").trim();
+
+            if last.eq_ignore_ascii_case("chunked") {
+                Ok(BodyLength::Chunked)
+            } else {
+                Err(invalid_body(format!("Unsupported transfer coding '{}'", encoding)))
+            }
+        }
+        (None, Some(length)) => {
+            let invalid_length = || invalid_body(format!("Invalid Content-Length '{}'", length));
+            let digits = length.trim();
+
+            // `parse` would also take a sign.
+            if digits.is_empty() || !digits.bytes().all(|x| x.is_ascii_digit()) {
+                return Err(invalid_length());
+            }
+
+            let length = digits.parse::<usize>().map_err(|_| invalid_length())?;
+
+            Ok(if length == 0 { BodyLength::Empty } else { BodyLength::Fixed(length) })
+        }
+        (None, None) => Ok(BodyLength::Empty),
+    }
+}
+
+/// Reads the body of the request, if there is one.
+///
+/// When the client sent `Expect: 100-continue`, the interim response is only
+/// sent if the body is going to be accepted.
+///
+/// `Err`:
+/// - the framing headers are invalid or the chunked encoding is malformed.
+/// - `Expect` contains something other than `100-continue`.
+/// - the body is larger than `max_body_size`.
+fn read_body(
+    stream: &mut (impl Read + Write),
+    request: &mut HttpRequest,
+    max_body_size: usize,
+) -> Result<(), Error> {
+    let length = body_length(request)?;
+
+    if let BodyLength::Fixed(length) = length {
+        if length > max_body_size {
+            return Err(body_too_large(max_body_size));
+        }
+    }
+
+    if let Some(expect) = request.headers.get("expect") {
+        if !expect.eq_ignore_ascii_case("100-continue") {
+            let message = format!("Unsupported expectation '{}'", expect);
+            return Err(Error::new(ErrorKind::Unsupported, message));
+        }
+
+        if length != BodyLength::Empty && request.version == HttpVersion::V1_1 {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            stream.flush()?;
+        }
+    }
+
+    request.body = match length {
+        BodyLength::Empty => Vec::new(),
+        BodyLength::Fixed(length) => {
+            let mut body = vec![0; length];
+            stream.read_exact(&mut body)?;
+            body
+        }
+        BodyLength::Chunked => read_chunked_body(stream, max_body_size)?,
+    };
+
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct HttpConnection {
-    stream: TcpStream,
+    stream: ConnectionStream,
+    buffer: ReadBuffer,
     request: HttpRequest,
+    config: ConnectionConfig,
+    requests_served: usize,
+    keep_alive: bool,
+    response_hooks: ResponseHooks,
+    /// Status of the response sent for the current request, 0 if none was.
+    response_status: u16,
+}
+
+/// Changes a response right before it is sent, see `add_response_hook`.
+pub type ResponseHook = Box<dyn for<'a> FnOnce(HttpResponse<'a>, &HttpConnection) -> HttpResponse<'a> + Send>;
+
+#[derive(Default)]
+struct ResponseHooks(Vec<ResponseHook>);
+
+impl std::fmt::Debug for ResponseHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ResponseHooks({})", self.0.len())
+    }
+}
+
+/// Reads whatever follows the current request, starting with bytes that were
+/// already buffered. Used by protocols the connection was upgraded to.
+impl Read for HttpConnection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        BufferedStream { buffer: &mut self.buffer, stream: &mut self.stream }.read(buf)
+    }
 }
 
 impl Write for HttpConnection {
@@ -210,39 +729,168 @@ impl Write for HttpConnection {
 
 impl Drop for HttpConnection {
     fn drop(&mut self) {
-        let _ = self.stream.shutdown(Shutdown::Both);
+        self.stream.shutdown();
     }
 }
 
 #[allow(unused)]
 impl HttpConnection {
-    /// Parses and consumes TcpStream.
+    /// Consumes the stream. Requests are read with `next_request`.
+    pub fn new<S: Into<ConnectionStream>>(stream: S, config: ConnectionConfig) -> Self {
+        HttpConnection {
+            stream: stream.into(),
+            buffer: ReadBuffer::default(),
+            request: HttpRequest::default(),
+            config,
+            requests_served: 0,
+            keep_alive: true,
+            response_hooks: ResponseHooks::default(),
+            response_status: 0,
+        }
+    }
+
+    /// Waits for the next request and parses it, including the body.
+    /// Requests that were pipelined by the client are already buffered.
+    ///
+    /// Returns `Ok(false)` when the connection should be closed: either side
+    /// did not want to keep it alive, the client closed it, or it has been
+    /// idle for longer than `keep_alive_timeout`.
     ///
     /// `Err`:
     /// - size of headers exceeded `MAX_HEADER_SIZE`.
     /// - the request line is malformed.
     /// - a header is malformed.
-    pub fn new(mut stream: TcpStream) -> Result<Self, Error> {
-        let request = parse_http_request(&mut stream)?;
+    /// - the body could not be read, see `read_body`.
+    /// - the request was not received before one of the timeouts, with
+    ///   `ErrorKind::TimedOut`.
+    ///
+    /// After an error, the connection will be closed after the next response.
+    pub fn next_request(&mut self) -> Result<bool, Error> {
+        if !self.keep_alive {
+            return Ok(false);
+        }
+
+        if self.requests_served == 0 {
+            self.stream.tcp().set_write_timeout(Some(self.config.write_timeout))?;
+        } else if !self.wait_for_request()? {
+            return Ok(false);
+        }
+
+        let result = self.read_request();
 
-        let mut connection = HttpConnection {
-            stream: stream,
-            request: request,
+        self.stream.tcp().set_read_timeout(None)?;
+
+        match result {
+            Ok(Some(())) => Ok(true),
+            Ok(None) => Ok(false),
+            Err(err) => {
+                self.keep_alive = false;
+                Err(err)
+            }
+        }
+    }
+
+    /// Waits for the first byte of the next request on an idle connection.
+    fn wait_for_request(&mut self) -> Result<bool, Error> {
+        if !self.buffer.unread().is_empty() {
+            return Ok(true);
+        }
+
+        self.stream.tcp().set_read_timeout(Some(self.config.keep_alive_timeout))?;
+
+        // Reading instead of peeking at the socket, since a TLS session may
+        // already hold decrypted bytes.
+        match self.buffer.fill(&mut self.stream) {
+            Ok(0) => Ok(false),
+            Ok(_) => Ok(true),
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_request(&mut self) -> Result<Option<()>, Error> {
+        let start = Instant::now();
+        let request_deadline = start + self.config.request_timeout;
+
+        let mut stream = DeadlineStream {
+            stream: &mut self.stream,
+            deadline: request_deadline.min(start + self.config.header_timeout),
         };
 
-        Ok(connection)
+        let Some(mut request) = parse_http_request(&mut self.buffer, &mut stream)? else {
+            return Ok(None);
+        };
+
+        self.requests_served += 1;
+
+        stream.deadline = request_deadline.min(Instant::now() + self.config.body_timeout);
+
+        let mut buffered = BufferedStream {
+            buffer: &mut self.buffer,
+            stream,
+        };
+
+        let body_result = read_body(&mut buffered, &mut request, self.config.max_body_size);
+
+        self.keep_alive = wants_keep_alive(&request)
+            && self.requests_served < self.config.max_requests;
+        self.request = request;
+        self.response_hooks = ResponseHooks::default();
+        self.response_status = 0;
+
+        body_result.map(Some)
+    }
+
+    /// Whether a part of the next request was already received, so it may
+    /// be read without waiting for the socket.
+    pub fn has_buffered_input(&mut self) -> bool {
+        !self.buffer.unread().is_empty() || self.stream.has_buffered_input()
+    }
+
+    /// Reads whatever has already arrived, without waiting for more. Tells
+    /// a readable socket that only carried a TLS handshake apart from one
+    /// with a request: `WouldBlock` if there are no bytes of a request yet.
+    /// `Ok(0)` means the client closed the connection.
+    pub fn read_available(&mut self) -> Result<usize, Error> {
+        self.stream.tcp().set_nonblocking(true)?;
+        let result = self.buffer.fill(&mut self.stream);
+        self.stream.tcp().set_nonblocking(false)?;
+
+        result
+    }
+
+    /// Whether the connection will be kept open after the current response.
+    pub fn keep_alive(&self) -> bool {
+        self.keep_alive
+    }
+
+    /// Makes the current response the last one sent over this connection.
+    pub fn close_after_response(&mut self) {
+        self.keep_alive = false;
+    }
+
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
     }
 
-    pub fn stream_mut(&mut self) -> &mut TcpStream {
+    pub fn cors(&self) -> &CorsPolicy {
+        &self.config.cors
+    }
+
+    pub fn requests_served(&self) -> usize {
+        self.requests_served
+    }
+
+    pub fn stream_mut(&mut self) -> &mut ConnectionStream {
         &mut self.stream
     }
 
-    pub fn stream(&self) -> &TcpStream {
+    pub fn stream(&self) -> &ConnectionStream {
         &self.stream
     }
 
     pub fn peer_string(&self) -> String {
-        if let Ok(ip) = self.stream().peer_addr() {
+        if let Ok(ip) = self.stream().tcp().peer_addr() {
             ip.to_string()
         } else {
             "Unknown address".into()
@@ -253,6 +901,10 @@ impl HttpConnection {
         self.request.method
     }
 
+    pub fn version(&self) -> HttpVersion {
+        self.request.version
+    }
+
     pub fn path(&self) -> &String {
         &self.request.path
     }
@@ -261,18 +913,81 @@ impl HttpConnection {
         &self.request.raw_path
     }
 
+    /// Path and query as the client sent them, still percent-encoded. For
+    /// sending back, since decoding changes what `%2F` and `%3F` mean.
+    pub fn target(&self) -> &str {
+        &self.request.target
+    }
+
     pub fn headers(&self) -> &Headers {
         &self.request.headers
     }
 
+    /// Header names are lowercase.
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.request.headers
+    }
+
+    /// Registers a hook that gets the response to the current request before
+    /// it is sent, and returns the one to send instead. Hooks run in the
+    /// order they were added, and are dropped after the response.
+    pub fn add_response_hook(&mut self, hook: ResponseHook) {
+        self.response_hooks.0.push(hook);
+    }
+
+    pub fn take_response_hooks(&mut self) -> Vec<ResponseHook> {
+        std::mem::take(&mut self.response_hooks.0)
+    }
+
+    pub fn response_status(&self) -> u16 {
+        self.response_status
+    }
+
+    /// Called by `HttpResponse::send`.
+    pub fn set_response_status(&mut self, status: u16) {
+        self.response_status = status;
+    }
+
     pub fn params(&self) -> Option<&Parameters> {
         self.request.parameters.as_ref()
     }
+
+    pub fn path_params(&self) -> &PathParams {
+        &self.request.path_params
+    }
+
+    /// Set by the router once the request is matched to a route.
+    pub fn set_path_params(&mut self, params: PathParams) {
+        self.request.path_params = params;
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.request.body
+    }
+
+    /// Whether the client already has the representation with these
+    /// validators, and can be answered with 304. Only GET and HEAD requests
+    /// qualify.
+    pub fn is_not_modified(&self, validators: &Validators) -> bool {
+        matches!(self.request.method, HttpMethod::GET | HttpMethod::HEAD) && is_not_modified(
+            self.headers().get("if-none-match"),
+            self.headers().get("if-modified-since"),
+            validators,
+        )
+    }
+
+    /// Value of the `Range` header, unless `If-Range` says that the client's
+    /// partial copy is outdated and the whole representation should be sent.
+    pub fn range(&self, validators: &Validators) -> Option<&String> {
+        self.headers().get("range")
+            .filter(|_| if_range_matches(self.headers().get("if-range"), validators))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::TcpStream;
 
     #[test]
     fn test_parse_request_line_http0_9() {
@@ -304,6 +1019,16 @@ mod tests {
         assert_eq!(request.version, HttpVersion::V1_1);
     }
 
+    #[test]
+    fn test_parse_request_line_head_and_options() {
+        let mut request = HttpRequest::default();
+        parse_request_line("HEAD /path HTTP/1.1", &mut request).unwrap();
+        assert_eq!(request.method, HttpMethod::HEAD);
+
+        parse_request_line("OPTIONS * HTTP/1.1", &mut request).unwrap();
+        assert_eq!(request.method, HttpMethod::OPTIONS);
+    }
+
     #[test]
     fn test_parse_request_line_unknown_method() {
         let mut request = HttpRequest::default();
@@ -312,6 +1037,34 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_request_line_keeps_target() {
+        let mut request = HttpRequest::default();
+        parse_request_line("GET /a%2Fb?q=%3F HTTP/1.1", &mut request).unwrap();
+
+        assert_eq!(request.path, "/a/b");
+        assert_eq!(request.target, "/a%2Fb?q=%3F");
+    }
+
+    #[test]
+    fn test_parse_request_line_parameter_without_value() {
+        let mut request = HttpRequest::default();
+        parse_request_line("GET /path?foo&bar=1 HTTP/1.1", &mut request).unwrap();
+
+        let parameters = request.parameters.unwrap();
+        assert_eq!(parameters.get("foo").unwrap(), "");
+        assert_eq!(parameters.get("bar").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_parse_encoded_line_break_in_target() {
+        let payload = b"GET /%0D%0ASet-Cookie:%20x=1 HTTP/1.1\r\nHost: a\r\n\r\n";
+        let mut connection = HttpConnection::new(mock_listener(payload), ConnectionConfig::default());
+
+        let err = connection.next_request().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn test_parse_request_line_unknown_version() {
         let mut request = HttpRequest::default();
@@ -378,7 +1131,7 @@ mod tests {
 
         let mut writer = std::net::TcpStream::connect(addr).unwrap();
 
-        writer.write(payload).unwrap();
+        writer.write_all(payload).unwrap();
 
         let (reader, _) = listener.accept().unwrap();
 
@@ -392,8 +1145,8 @@ mod tests {
 
         let mut stream = mock_listener(payload);
 
-        match parse_http_request(&mut stream) {
-            Ok(request) => {
+        match parse_http_request(&mut ReadBuffer::default(), &mut stream) {
+            Ok(Some(request)) => {
                 assert_eq!(request.method, HttpMethod::PATCH);
                 assert_eq!(request.path, "/api/v1/music/all");
                 assert_eq!(request.version, HttpVersion::V1);
@@ -401,7 +1154,195 @@ mod tests {
                 assert_eq!(request.parameters.as_ref().unwrap().get("hello").unwrap(), "world");
                 assert_eq!(request.parameters.as_ref().unwrap().get("what").unwrap(), "nothing");
             }
+            Ok(None) => panic!("Test failed: no request"),
             Err(e) => panic!("Test failed: {:?}", e),
         }
     }
+
+    #[test]
+    fn test_parse_header_too_large_in_one_read() {
+        let head = format!("GET / HTTP/1.1\r\nX-Large: {}\r\n\r\n", "a".repeat(MAX_HEADER_SIZE));
+        assert!(head.len() < READ_BUFFER_SIZE);
+
+        let err = parse_http_request(&mut ReadBuffer::default(), &mut head.as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn test_parse_pipelined_requests() {
+        let payload =
+            b"GET /first HTTP/1.1\r\nHost: a\r\n\r\nGET /second HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let stream = mock_listener(payload);
+        let mut connection = HttpConnection::new(stream, ConnectionConfig::default());
+
+        assert!(connection.next_request().unwrap());
+        assert_eq!(connection.path(), "/first");
+        assert!(connection.keep_alive());
+
+        assert!(connection.next_request().unwrap());
+        assert_eq!(connection.path(), "/second");
+        assert!(!connection.keep_alive());
+
+        assert!(!connection.next_request().unwrap());
+    }
+
+    #[test]
+    fn test_parse_head_rejects_obsolete_folding() {
+        let result = parse_head(b"GET / HTTP/1.1\r\nX-Folded: a\r\n  b\r\n");
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_head_rejects_bare_cr() {
+        assert!(parse_head(b"GET / HTTP/1.1\r\nX-Bad: a\rb\r\n").is_err());
+        assert!(parse_head(b"GET / HTTP/1.1\rHost: a\r\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_head_non_ascii_header_value() {
+        let request = parse_head("GET / HTTP/1.1\r\nX-Name: Привет\r\n".as_bytes()).unwrap();
+        assert_eq!(request.headers.get("x-name").unwrap(), "Привет");
+
+        let request = parse_head(b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\n").unwrap();
+        assert_eq!(request.headers.get("x-name").unwrap(), "café");
+    }
+
+    #[test]
+    fn test_parse_head_repeated_headers() {
+        let request = parse_head(b"GET / HTTP/1.1\nAccept: a\nAccept: b\n").unwrap();
+
+        assert_eq!(request.headers.get("accept").unwrap(), "a, b");
+    }
+
+    #[test]
+    fn test_find_head_end() {
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nrest", 0), Some(27));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\n\nrest", 0), Some(16));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n", 0), None);
+    }
+
+    #[test]
+    fn test_parse_content_length_body() {
+        let payload =
+            b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\n\r\n";
+
+        let mut connection = HttpConnection::new(mock_listener(payload), ConnectionConfig::default());
+
+        assert!(connection.next_request().unwrap());
+        assert_eq!(connection.body(), b"hello");
+
+        assert!(connection.next_request().unwrap());
+        assert_eq!(connection.path(), "/b");
+        assert!(connection.body().is_empty());
+    }
+
+    #[test]
+    fn test_parse_chunked_body() {
+        let payload =
+            b"PUT /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nTrailer: x\r\n\r\n";
+
+        let mut connection = HttpConnection::new(mock_listener(payload), ConnectionConfig::default());
+
+        assert!(connection.next_request().unwrap());
+        assert_eq!(connection.body(), b"hello, world");
+    }
+
+    #[test]
+    fn test_parse_body_too_large() {
+        let payload = b"POST /a HTTP/1.1\r\nContent-Length: 100\r\n\r\n";
+
+        let config = ConnectionConfig { max_body_size: 10, ..Default::default() };
+        let mut connection = HttpConnection::new(mock_listener(payload), config);
+
+        let err = connection.next_request().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+        assert!(!connection.keep_alive());
+    }
+
+    #[test]
+    fn test_parse_chunk_size_overflow() {
+        let payload = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n";
+
+        let mut connection = HttpConnection::new(mock_listener(payload), ConnectionConfig::default());
+
+        let err = connection.next_request().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+    }
+
+    #[test]
+    fn test_parse_header_timeout() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut writer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+        // Headers are never finished, but the writer stays open.
+        writer.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+
+        let (reader, _) = listener.accept().unwrap();
+
+        let config = ConnectionConfig {
+            header_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let mut connection = HttpConnection::new(reader, config);
+
+        let err = connection.next_request().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(!connection.keep_alive());
+
+        drop(writer);
+    }
+
+    #[test]
+    fn test_parse_ambiguous_body_length() {
+        let mut request = HttpRequest::default();
+        request.headers.insert("content-length".into(), "5".into());
+        request.headers.insert("transfer-encoding".into(), "chunked".into());
+
+        assert!(body_length(&request).is_err());
+    }
+
+    #[test]
+    fn test_parse_signed_body_length() {
+        let mut request = HttpRequest::default();
+        request.headers.insert("content-length".into(), "+5".into());
+
+        assert_eq!(body_length(&request).unwrap_err().kind(), ErrorKind::InvalidData);
+
+        request.headers.insert("content-length".into(), " 5 ".into());
+
+        assert_eq!(body_length(&request).unwrap(), BodyLength::Fixed(5));
+    }
+
+    #[test]
+    fn test_parse_signed_chunk_size() {
+        let payload = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nhello\r\n0\r\n\r\n";
+
+        let mut connection = HttpConnection::new(mock_listener(payload), ConnectionConfig::default());
+
+        let err = connection.next_request().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_keep_alive_defaults() {
+        let mut request = HttpRequest::default();
+        parse_request_line("GET / HTTP/1.1", &mut request).unwrap();
+        assert!(wants_keep_alive(&request));
+
+        request.headers.insert("connection".into(), "Upgrade, Close".into());
+        assert!(!wants_keep_alive(&request));
+
+        let mut request = HttpRequest::default();
+        parse_request_line("GET / HTTP/1.0", &mut request).unwrap();
+        assert!(!wants_keep_alive(&request));
+
+        request.headers.insert("connection".into(), "keep-alive".into());
+        assert!(wants_keep_alive(&request));
+
+        let mut request = HttpRequest::default();
+        parse_request_line("GET /", &mut request).unwrap();
+        assert!(!wants_keep_alive(&request));
+    }
 }
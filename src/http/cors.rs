@@ -0,0 +1,139 @@
+use std::io::{Error, ErrorKind};
+
+use super::connection::{HttpConnection, HttpMethod};
+use super::response::HttpResponse;
+
+pub const DEFAULT_CORS_MAX_AGE: u64 = 600;
+
+/// Response headers scripts from other origins are allowed to read, besides
+/// the CORS-safelisted ones.
+const EXPOSED_HEADERS: &str = "Content-Length, Content-Range, Accept-Ranges, ETag";
+
+/// Which origins are allowed to make cross-origin requests.
+#[derive(Debug, Clone)]
+pub struct CorsPolicy {
+    /// `None` allows any origin.
+    pub allowed_origins: Option<Vec<String>>,
+    /// Whether requests with cookies or `Authorization` are allowed.
+    pub allow_credentials: bool,
+    /// How long preflight results can be cached by the browser, in seconds.
+    pub max_age: u64,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: None,
+            allow_credentials: false,
+            max_age: DEFAULT_CORS_MAX_AGE,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// Makes a policy from a comma-separated list of origins. An empty list
+    /// or `*` allows any origin.
+    ///
+    /// `Err` if credentials are allowed for any origin, since then every
+    /// website could make requests on behalf of the user.
+    pub fn from_list<S: AsRef<str>>(origins: S, allow_credentials: bool) -> Result<Self, Error> {
+        let origins: Vec<String> = origins.as_ref()
+            .split(',')
+            .map(|x| x.trim().trim_end_matches('/').to_owned())
+            .filter(|x| !x.is_empty())
+            .collect();
+
+        let allowed_origins = (!origins.is_empty() && !origins.iter().any(|x| x == "*"))
+            .then_some(origins);
+
+        if allow_credentials && allowed_origins.is_none() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Credentials can only be allowed for listed origins"));
+        }
+
+        Ok(CorsPolicy {
+            allowed_origins,
+            allow_credentials,
+            ..Default::default()
+        })
+    }
+
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        self.allowed_origins.as_ref()
+            .is_none_or(|origins| origins.iter().any(|x| x.eq_ignore_ascii_case(origin)))
+    }
+
+    /// Adds CORS headers to a response if the request came from an allowed
+    /// origin. Requests without `Origin` are not cross-origin, and are left
+    /// alone.
+    ///
+    /// Responses to preflight requests, `OPTIONS` with
+    /// `Access-Control-Request-Method`, additionally get the methods from
+    /// their `Allow` header and the requested headers.
+    pub fn apply<'a>(&self, response: HttpResponse<'a>, connection: &HttpConnection) -> HttpResponse<'a> {
+        let Some(origin) = connection.headers().get("origin") else {
+            return response;
+        };
+
+        // The response depends on Origin either way, so caches have to know.
+        let response = response.set_header("Vary", "Origin");
+
+        if !self.is_allowed(origin) {
+            return response;
+        }
+
+        let mut response = response
+            .set_header("Access-Control-Allow-Origin", origin)
+            .set_header("Access-Control-Expose-Headers", EXPOSED_HEADERS);
+
+        if self.allow_credentials && self.allowed_origins.is_some() {
+            response = response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+
+        let is_preflight = connection.method() == HttpMethod::OPTIONS
+            && connection.headers().contains_key("access-control-request-method");
+
+        if !is_preflight {
+            return response;
+        }
+
+        if let Some(allowed_methods) = response.header("Allow").map(|x| x.to_owned()) {
+            response = response.set_header("Access-Control-Allow-Methods", allowed_methods);
+        }
+
+        response = response.set_header("Access-Control-Max-Age", self.max_age);
+
+        // Any header is fine, since handlers ignore the ones they don't need.
+        if let Some(headers) = connection.headers().get("access-control-request-headers") {
+            response = response.set_header("Access-Control-Allow-Headers", headers);
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cors_policy_from_list() {
+        let policy = CorsPolicy::from_list("https://a.com, https://b.com/", true).unwrap();
+
+        assert!(policy.is_allowed("https://a.com"));
+        assert!(policy.is_allowed("https://b.com"));
+        assert!(!policy.is_allowed("https://c.com"));
+        assert!(policy.allow_credentials);
+    }
+
+    #[test]
+    fn test_cors_policy_any_origin() {
+        assert!(CorsPolicy::from_list("", false).unwrap().is_allowed("https://c.com"));
+        assert!(CorsPolicy::from_list("https://a.com,*", false).unwrap().is_allowed("https://c.com"));
+    }
+
+    #[test]
+    fn test_cors_policy_credentials_need_origins() {
+        assert!(CorsPolicy::from_list("", true).is_err());
+        assert!(CorsPolicy::from_list("https://a.com,*", true).is_err());
+    }
+}
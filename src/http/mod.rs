@@ -1,2 +1,9 @@
+pub mod body;
+pub mod conditional;
 pub mod connection;
-pub mod response;
\ No newline at end of file
+pub mod cors;
+pub mod range;
+pub mod response;
+pub mod sse;
+pub mod stream;
+pub mod websocket;
\ No newline at end of file
@@ -0,0 +1,184 @@
+use std::ops::RangeInclusive;
+
+pub type ByteRange = RangeInclusive<u64>;
+
+/// More ranges than this are not worth the multipart overhead, the whole
+/// file is served instead.
+const MAX_RANGE_COUNT: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// Header is absent, malformed or not worth honouring. Serve the whole
+    /// representation with 200.
+    Full,
+    /// None of the ranges overlap the representation. Respond with 416.
+    Unsatisfiable,
+    /// One or more satisfiable ranges, sorted and coalesced.
+    Partial(Vec<ByteRange>),
+}
+
+/// Parses the value of a `Range` header against a representation of
+/// `length` bytes, as described in RFC 9110, section 14.
+///
+/// Syntactically invalid headers and units other than `bytes` are ignored,
+/// as the RFC allows.
+pub fn parse_range_header(value: &str, length: u64) -> RangeRequest {
+    let Some((unit, specs)) = value.split_once('=') else {
+        return RangeRequest::Full;
+    };
+
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return RangeRequest::Full;
+    }
+
+    // Without a single range, like `bytes=,`, the header is invalid rather
+    // than unsatisfiable.
+    if specs.split(',').all(|x| x.trim().is_empty()) {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+
+        if spec.is_empty() {
+            continue;
+        }
+
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // Suffix range, "-500" means the last 500 bytes.
+            let Ok(suffix) = last.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+
+            if suffix == 0 || length == 0 {
+                continue;
+            }
+
+            length.saturating_sub(suffix)..=length - 1
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return RangeRequest::Full;
+            };
+
+            let last = if last.is_empty() {
+                u64::MAX
+            } else {
+                match last.parse::<u64>() {
+                    Ok(last) if last >= first => last,
+                    _ => return RangeRequest::Full,
+                }
+            };
+
+            if first >= length {
+                continue;
+            }
+
+            first..=last.min(length - 1)
+        };
+
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    if ranges.len() > MAX_RANGE_COUNT {
+        return RangeRequest::Full;
+    }
+
+    RangeRequest::Partial(coalesce(ranges))
+}
+
+/// Sorts ranges and merges the ones that overlap or touch each other.
+fn coalesce(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| *range.start());
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        if let Some(previous) = merged.last_mut() {
+            if *range.start() <= previous.end().saturating_add(1) {
+                let end = *previous.end().max(range.end());
+                *previous = *previous.start()..=end;
+                continue;
+            }
+        }
+
+        merged.push(range);
+    }
+
+    merged
+}
+
+/// Value of `Content-Range` for a satisfied range.
+pub fn content_range(range: &ByteRange, length: u64) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), length)
+}
+
+/// Value of `Content-Range` for a 416 response.
+pub fn unsatisfied_content_range(length: u64) -> String {
+    format!("bytes */{}", length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range_closed() {
+        assert_eq!(parse_range_header("bytes=0-499", 1000),
+                   RangeRequest::Partial(vec![0..=499]));
+    }
+
+    #[test]
+    fn test_parse_range_open_ended() {
+        assert_eq!(parse_range_header("bytes=900-", 1000),
+                   RangeRequest::Partial(vec![900..=999]));
+    }
+
+    #[test]
+    fn test_parse_range_suffix() {
+        assert_eq!(parse_range_header("bytes=-100", 1000),
+                   RangeRequest::Partial(vec![900..=999]));
+        assert_eq!(parse_range_header("bytes=-5000", 1000),
+                   RangeRequest::Partial(vec![0..=999]));
+    }
+
+    #[test]
+    fn test_parse_range_clamps_last_byte() {
+        assert_eq!(parse_range_header("bytes=500-5000", 1000),
+                   RangeRequest::Partial(vec![500..=999]));
+    }
+
+    #[test]
+    fn test_parse_range_multiple_coalesced() {
+        assert_eq!(parse_range_header("bytes=500-599, 0-99,100-199, 550-700", 1000),
+                   RangeRequest::Partial(vec![0..=199, 500..=700]));
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(parse_range_header("bytes=1000-", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range_header("bytes=0-10", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_parse_range_invalid_is_ignored() {
+        assert_eq!(parse_range_header("bytes=abc", 1000), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=10-5", 1000), RangeRequest::Full);
+        assert_eq!(parse_range_header("items=0-10", 1000), RangeRequest::Full);
+        assert_eq!(parse_range_header("0-10", 1000), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=,", 1000), RangeRequest::Full);
+        assert_eq!(parse_range_header("bytes=", 1000), RangeRequest::Full);
+    }
+}
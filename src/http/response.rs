@@ -1,37 +1,90 @@
 use std::{
     fmt::Display,
-    io::{Error, Write},
+    fs::File,
+    io::{Error, Read, Write},
 };
 
-use super::connection::HttpConnection;
+use super::body::{BodyChunks, FileRegion, ResponseBody};
+use super::conditional::{format_http_date, Validators};
+use super::connection::{HttpConnection, HttpMethod, HttpVersion};
 
 #[derive(Debug)]
 pub struct HttpResponse<'a> {
     status: u16,
     status_message: String,
     headers: Option<String>,
-    body: Option<&'a [u8]>,
+    has_content_length: bool,
+    body: Option<ResponseBody<'a>>,
 }
 
+#[allow(unused)]
 impl<'a> HttpResponse<'a> {
     pub fn new<S: Display>(status: u16, status_message: S) -> Self {
         return Self {
             status: status,
             status_message: status_message.to_string(),
             headers: None,
+            has_content_length: false,
             body: None,
         };
     }
 
     pub fn set_header<K: Display, V: Display>(mut self, key: K, value: V) -> Self {
+        let key = key.to_string();
+
+        if key.eq_ignore_ascii_case("content-length") {
+            self.has_content_length = true;
+        }
+
         let mut headers = self.headers.take().unwrap_or_default();
         headers.push_str(format!("{}: {}\r\n", key, value).as_str());
         self.headers = Some(headers);
         self
     }
 
+    /// 304 response for a conditional request whose condition held.
+    pub fn not_modified(validators: &Validators) -> Self {
+        Self::new(304, "Not Modified").set_validators(validators)
+    }
+
+    /// Adds `ETag` and `Last-Modified` headers.
+    pub fn set_validators(mut self, validators: &Validators) -> Self {
+        if let Some(etag) = &validators.etag {
+            self = self.set_header("ETag", etag);
+        }
+
+        if let Some(last_modified) = validators.last_modified {
+            self = self.set_header("Last-Modified", format_http_date(last_modified));
+        }
+
+        self
+    }
+
     pub fn set_body(mut self, body: &'a [u8]) -> Self {
-        self.body = Some(body);
+        self.body = Some(ResponseBody::Bytes(body));
+        self
+    }
+
+    pub fn set_owned_body(mut self, body: Vec<u8>) -> Self {
+        self.body = Some(ResponseBody::Owned(body));
+        self
+    }
+
+    /// Streams the body from `reader`. Pass `None` as length if it is not
+    /// known in advance.
+    pub fn set_reader_body<R: Read + Send + 'a>(mut self, reader: R, length: Option<u64>) -> Self {
+        self.body = Some(ResponseBody::Reader(Box::new(reader), length));
+        self
+    }
+
+    /// Streams `length` bytes of `file`, starting from `start`.
+    pub fn set_file_body(self, file: File, start: u64, length: u64) -> Self {
+        self.set_reader_body(FileRegion::new(file, start, length), Some(length))
+    }
+
+    /// Streams buffers from an iterator. The length is never known in advance.
+    pub fn set_chunks_body(mut self, chunks: BodyChunks<'a>) -> Self {
+        self.body = Some(ResponseBody::Chunks(chunks));
         self
     }
 
@@ -40,12 +93,68 @@ impl<'a> HttpResponse<'a> {
             .set_body(body.as_ref().as_bytes())
     }
 
-    pub fn allow_all_origins(self, connection: &HttpConnection) -> Self {
-        let host = connection.headers().get("origin");
-        self.set_header("Access-Control-Allow-Origin", host.unwrap_or(&"*".to_string()))
+    /// Adds CORS headers, according to the connection's policy. Responses
+    /// sent through the `Cors` middleware get them automatically.
+    pub fn allow_origin(self, connection: &HttpConnection) -> Self {
+        connection.cors().apply(self, connection)
     }
 
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Value of the first header with this name, if it was set.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.as_deref()?
+            .split("\r\n")
+            .filter_map(|x| x.split_once(": "))
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    /// Writes the response to the connection.
+    ///
+    /// `Content-Length` is added when it wasn't set and the length of the body
+    /// is known, so the client can find the end of the response on a persistent
+    /// connection. Otherwise, the body is sent chunked to HTTP/1.1 clients, and
+    /// older clients get it delimited by closing the connection.
+    ///
+    /// Responses to HEAD requests get the same headers, but no body. Response
+    /// hooks of the connection are applied first.
     pub fn send(mut self, connection: &mut HttpConnection) -> Result<(), Error> {
+        for hook in connection.take_response_hooks() {
+            self = hook(self, connection);
+        }
+
+        connection.set_response_status(self.status);
+
+        let length = self.body.as_ref().map_or(Some(0), |x| x.length());
+        let mut is_chunked = false;
+
+        // These responses never have a body, and their Content-Length would
+        // describe the representation instead.
+        let has_no_body = self.status == 204 || self.status == 304;
+        // Informational responses, like 101, set their own Connection header.
+        let is_informational = (100..200).contains(&self.status);
+
+        if !self.has_content_length && !has_no_body && !is_informational {
+            match length {
+                Some(length) => {
+                    self = self.set_header("Content-Length", length);
+                }
+                None if connection.version() == HttpVersion::V1_1 => {
+                    self = self.set_header("Transfer-Encoding", "chunked");
+                    is_chunked = true;
+                }
+                None => connection.close_after_response(),
+            }
+        }
+
+        if !is_informational {
+            let connection_header = if connection.keep_alive() { "keep-alive" } else { "close" };
+            self = self.set_header("Connection", connection_header);
+        }
+
         connection.write_all(
             format!("HTTP/1.1 {} {}\r\n", self.status, self.status_message).as_bytes(),
         )?;
@@ -56,8 +165,14 @@ impl<'a> HttpResponse<'a> {
 
         connection.write_all(b"\r\n")?;
 
-        if let Some(body) = self.body {
-            connection.write_all(body)?;
+        let is_head = connection.method() == HttpMethod::HEAD;
+
+        if let Some(body) = self.body.take().filter(|_| !is_head) {
+            if is_chunked {
+                body.write_chunked_to(connection)?;
+            } else {
+                body.write_to(connection)?;
+            }
         }
 
         connection.flush()?;
@@ -0,0 +1,61 @@
+use super::connection::HttpConnection;
+
+pub const EVENT_STREAM_CONTENT_TYPE: &str = "text/event-stream";
+
+/// How long browsers wait before reconnecting, in milliseconds.
+const RETRY_INTERVAL: u64 = 3000;
+
+/// Formats one event of a `text/event-stream`. Every line of `data` becomes
+/// its own `data:` field, so clients get it back unchanged. Events without
+/// data are not dispatched by browsers, so empty data is still sent.
+pub fn format_event(id: Option<u64>, event: &str, data: &str) -> String {
+    let mut formatted = String::new();
+
+    if let Some(id) = id {
+        formatted += &format!("id: {}\n", id);
+    }
+
+    formatted += &format!("event: {}\n", event);
+
+    for line in data.split('\n') {
+        formatted += &format!("data: {}\n", line);
+    }
+
+    formatted + "\n"
+}
+
+/// Comments are ignored by clients, and are used to keep idle streams open.
+pub fn format_comment(text: &str) -> String {
+    format!(": {}\n\n", text)
+}
+
+/// First thing to send, sets the reconnection delay.
+pub fn format_retry() -> String {
+    format!("retry: {}\n\n", RETRY_INTERVAL)
+}
+
+/// Id of the last event the client received before reconnecting. Taken from
+/// `Last-Event-ID`, or `lastEventId` query parameter for clients that can't
+/// set headers. Parameter names are case-insensitive.
+pub fn last_event_id(connection: &HttpConnection) -> Option<u64> {
+    connection.headers().get("last-event-id")
+        .or_else(|| connection.params().and_then(|x| x.get("lasteventid")))
+        .and_then(|x| x.trim().parse::<u64>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_event() {
+        assert_eq!(format_event(Some(7), "track-added", "{\"name\":\"a\"}"),
+                   "id: 7\nevent: track-added\ndata: {\"name\":\"a\"}\n\n");
+    }
+
+    #[test]
+    fn test_format_event_multiline() {
+        assert_eq!(format_event(None, "message", "first\nsecond"),
+                   "event: message\ndata: first\ndata: second\n\n");
+    }
+}
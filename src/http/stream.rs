@@ -0,0 +1,98 @@
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+#[cfg(feature = "tls")]
+use rustls::{ServerConnection, StreamOwned};
+
+/// Transport an `HttpConnection` talks over.
+pub enum ConnectionStream {
+    Plain(TcpStream),
+    /// TLS session over a socket. The handshake happens during the first read
+    /// or write, so it is subject to the same timeouts as the request.
+    #[cfg(feature = "tls")]
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl std::fmt::Debug for ConnectionStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionStream::Plain(stream) => write!(f, "Plain({:?})", stream),
+            #[cfg(feature = "tls")]
+            ConnectionStream::Tls(stream) => write!(f, "Tls({:?})", stream.sock),
+        }
+    }
+}
+
+impl From<TcpStream> for ConnectionStream {
+    fn from(stream: TcpStream) -> Self {
+        ConnectionStream::Plain(stream)
+    }
+}
+
+#[allow(unused)]
+impl ConnectionStream {
+    /// Underlying socket, for timeouts and addresses. Reading or writing it
+    /// directly would corrupt a TLS session.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            ConnectionStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            ConnectionStream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, ConnectionStream::Plain(_))
+    }
+
+    /// Whether a TLS session holds decrypted bytes that were not read yet. The
+    /// socket won't become readable for those.
+    pub fn has_buffered_input(&mut self) -> bool {
+        match self {
+            ConnectionStream::Plain(_) => false,
+            #[cfg(feature = "tls")]
+            ConnectionStream::Tls(stream) => stream.conn.process_new_packets()
+                .map_or(true, |x| x.plaintext_bytes_to_read() > 0),
+        }
+    }
+
+    /// Sends TLS close notification if there is a session, and shuts the
+    /// socket down.
+    pub fn shutdown(&mut self) {
+        #[cfg(feature = "tls")]
+        if let ConnectionStream::Tls(stream) = self {
+            stream.conn.send_close_notify();
+            let _ = stream.conn.complete_io(&mut stream.sock);
+        }
+
+        let _ = self.tcp().shutdown(Shutdown::Both);
+    }
+}
+
+impl Read for ConnectionStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            ConnectionStream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            ConnectionStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ConnectionStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ConnectionStream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            ConnectionStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ConnectionStream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            ConnectionStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
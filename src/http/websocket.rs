@@ -0,0 +1,466 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use crate::common::sha1::sha1;
+use crate::common::util::base64_encode;
+
+use super::connection::{HttpConnection, HttpMethod};
+use super::response::HttpResponse;
+
+/// Appended to the client's key to compute `Sec-WebSocket-Accept`, RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const WEBSOCKET_VERSION: &str = "13";
+
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl Opcode {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(Debug)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// Already unmasked.
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// Pong has already been sent in reply.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// Close has already been sent in reply, the socket should not be used anymore.
+    Close(Option<u16>, String),
+}
+
+/// Value of `Sec-WebSocket-Accept` for the client's `Sec-WebSocket-Key`.
+pub fn accept_key<S: AsRef<str>>(key: S) -> String {
+    base64_encode(sha1(format!("{}{}", key.as_ref(), WEBSOCKET_GUID)))
+}
+
+fn has_token(value: Option<&String>, token: &str) -> bool {
+    value.is_some_and(|x| x.split(',').any(|x| x.trim().eq_ignore_ascii_case(token)))
+}
+
+/// Whether the request asks to switch to the WebSocket protocol.
+pub fn is_upgrade_request(connection: &HttpConnection) -> bool {
+    let headers = connection.headers();
+
+    connection.method() == HttpMethod::GET
+        && has_token(headers.get("upgrade"), "websocket")
+        && has_token(headers.get("connection"), "upgrade")
+}
+
+fn protocol_error<S: AsRef<str>>(message: S) -> Error {
+    Error::new(ErrorKind::InvalidData, message.as_ref())
+}
+
+/// Writes a single unmasked frame, as servers do.
+fn write_frame(writer: &mut impl Write, fin: bool, opcode: Opcode, payload: &[u8]) -> Result<(), Error> {
+    let mut header = Vec::with_capacity(10);
+    header.push(if fin { 0x80 } else { 0 } | opcode as u8);
+
+    match payload.len() {
+        length @ 0..=125 => header.push(length as u8),
+        length @ 126..=0xFFFF => {
+            header.push(126);
+            header.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            header.push(127);
+            header.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+
+    writer.write_all(&header)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Reads a single frame sent by a client, and unmasks it.
+///
+/// `Err`, with the close code that should be sent:
+/// - the frame is not masked, or reserved bits are set.
+/// - the opcode is unknown.
+/// - a control frame is fragmented or too long.
+/// - the payload is longer than `max_payload`.
+fn read_frame(reader: &mut impl Read, max_payload: usize) -> Result<Frame, (u16, Error)> {
+    // Read timeout means the client went quiet, not that it broke the protocol.
+    let io_error = |err: Error| match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => (CLOSE_GOING_AWAY, err),
+        _ => (CLOSE_PROTOCOL_ERROR, err),
+    };
+
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).map_err(io_error)?;
+
+    let fin = header[0] & 0x80 != 0;
+    let is_masked = header[1] & 0x80 != 0;
+
+    if header[0] & 0x70 != 0 {
+        return Err((CLOSE_PROTOCOL_ERROR, protocol_error("Reserved bits are set")));
+    }
+
+    let opcode = Opcode::from_u8(header[0] & 0x0F)
+        .ok_or_else(|| (CLOSE_PROTOCOL_ERROR, protocol_error("Unknown opcode")))?;
+
+    if !is_masked {
+        return Err((CLOSE_PROTOCOL_ERROR, protocol_error("Client frames have to be masked")));
+    }
+
+    let length = match header[1] & 0x7F {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length).map_err(io_error)?;
+            u16::from_be_bytes(length) as u64
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length).map_err(io_error)?;
+            u64::from_be_bytes(length)
+        }
+        length => length as u64,
+    };
+
+    if opcode.is_control() && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+        return Err((CLOSE_PROTOCOL_ERROR, protocol_error("Invalid control frame")));
+    }
+
+    if length > max_payload as u64 {
+        return Err((CLOSE_TOO_BIG, protocol_error("Frame is too big")));
+    }
+
+    let mut mask = [0; 4];
+    reader.read_exact(&mut mask).map_err(io_error)?;
+
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload).map_err(io_error)?;
+
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    Ok(Frame { fin, opcode, payload })
+}
+
+/// Connection that has switched to the WebSocket protocol, RFC 6455.
+pub struct WebSocket<'a> {
+    connection: &'a mut HttpConnection,
+    max_message_size: usize,
+    /// Opcode and payload of a fragmented message that isn't finished yet.
+    fragments: Option<(Opcode, Vec<u8>)>,
+    is_closed: bool,
+}
+
+#[allow(unused)]
+impl<'a> WebSocket<'a> {
+    /// Completes the opening handshake. If the request is not a valid upgrade
+    /// request, an error response is sent and `None` is returned.
+    ///
+    /// Messages are limited to the connection's maximum body size.
+    pub fn accept(connection: &'a mut HttpConnection) -> Result<Option<Self>, Error> {
+        let key = connection.headers().get("sec-websocket-key")
+            .filter(|x| x.len() == 24 && x.ends_with("=="))
+            .cloned();
+
+        if !is_upgrade_request(connection) || key.is_none() {
+            HttpResponse::new(400, "Bad Request")
+                .set_json_body(&"{ \"message\": \"Expected a WebSocket upgrade request\" }")
+                .send(connection)?;
+
+            return Ok(None);
+        }
+
+        if connection.headers().get("sec-websocket-version").map(|x| x.trim()) != Some(WEBSOCKET_VERSION) {
+            HttpResponse::new(426, "Upgrade Required")
+                .set_header("Sec-WebSocket-Version", WEBSOCKET_VERSION)
+                .send(connection)?;
+
+            return Ok(None);
+        }
+
+        // The connection is not HTTP anymore after this.
+        connection.close_after_response();
+
+        HttpResponse::new(101, "Switching Protocols")
+            .set_header("Upgrade", "websocket")
+            .set_header("Connection", "Upgrade")
+            .set_header("Sec-WebSocket-Accept", accept_key(key.unwrap_or_default()))
+            .send(connection)?;
+
+        let max_message_size = connection.config().max_body_size;
+
+        Ok(Some(WebSocket {
+            connection,
+            max_message_size,
+            fragments: None,
+            is_closed: false,
+        }))
+    }
+
+    pub fn connection(&self) -> &HttpConnection {
+        self.connection
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.is_closed
+    }
+
+    /// Reads the next frame as is. Most of the time `read_message` should be
+    /// used instead, which deals with fragmentation and control frames.
+    pub fn read_frame(&mut self) -> Result<Frame, Error> {
+        read_frame(self.connection, self.max_message_size)
+            .or_else(|(code, err)| self.fail(code, err))
+    }
+
+    /// Closes the connection because of an error, and returns it.
+    fn fail<T>(&mut self, code: u16, err: Error) -> Result<T, Error> {
+        if !self.is_closed {
+            let _ = self.close(code, &err.to_string());
+        }
+
+        Err(err)
+    }
+
+    /// Reads frames until a complete message arrives. Pings are answered, and
+    /// closing handshake is completed automatically.
+    ///
+    /// `Err`:
+    /// - the client violated the protocol. Connection is closed with an
+    ///   appropriate code.
+    /// - the connection was already closed.
+    pub fn read_message(&mut self) -> Result<Message, Error> {
+        if self.is_closed {
+            return Err(Error::new(ErrorKind::NotConnected, "WebSocket is closed"));
+        }
+
+        loop {
+            let frame = self.read_frame()?;
+
+            match frame.opcode {
+                Opcode::Ping => {
+                    self.send_frame(true, Opcode::Pong, &frame.payload)?;
+                    return Ok(Message::Ping(frame.payload));
+                }
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => return self.handle_close(frame.payload),
+                Opcode::Continuation => {
+                    let Some((_, payload)) = self.fragments.as_mut() else {
+                        return self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("Unexpected continuation frame"));
+                    };
+
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return self.fail(CLOSE_TOO_BIG, protocol_error("Message is too big"));
+                    }
+
+                    payload.extend_from_slice(&frame.payload);
+
+                    if frame.fin {
+                        let (opcode, payload) = self.fragments.take().unwrap_or((Opcode::Binary, vec![]));
+                        return self.make_message(opcode, payload);
+                    }
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.fragments.is_some() {
+                        return self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("Expected a continuation frame"));
+                    }
+
+                    if frame.fin {
+                        return self.make_message(frame.opcode, frame.payload);
+                    }
+
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+            }
+        }
+    }
+
+    fn make_message(&mut self, opcode: Opcode, payload: Vec<u8>) -> Result<Message, Error> {
+        if opcode == Opcode::Binary {
+            return Ok(Message::Binary(payload));
+        }
+
+        match String::from_utf8(payload) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => self.fail(CLOSE_INVALID_DATA, protocol_error("Text message is not valid UTF-8")),
+        }
+    }
+
+    fn handle_close(&mut self, payload: Vec<u8>) -> Result<Message, Error> {
+        let (code, reason) = match payload.as_slice() {
+            [] => (None, String::new()),
+            [high, low, reason @ ..] => {
+                let Ok(reason) = String::from_utf8(reason.to_vec()) else {
+                    return self.fail(CLOSE_INVALID_DATA, protocol_error("Close reason is not valid UTF-8"));
+                };
+
+                (Some(u16::from_be_bytes([*high, *low])), reason)
+            }
+            _ => return self.fail(CLOSE_PROTOCOL_ERROR, protocol_error("Invalid close frame")),
+        };
+
+        if !self.is_closed {
+            self.close(code.unwrap_or(CLOSE_NORMAL), "")?;
+        }
+
+        Ok(Message::Close(code, reason))
+    }
+
+    /// Writes a single frame. Messages can be fragmented by sending the first
+    /// frame with `Opcode::Text` or `Opcode::Binary` and `fin` unset, followed
+    /// by `Opcode::Continuation` frames, the last one with `fin` set.
+    pub fn send_frame(&mut self, fin: bool, opcode: Opcode, payload: &[u8]) -> Result<(), Error> {
+        if self.is_closed {
+            return Err(Error::new(ErrorKind::NotConnected, "WebSocket is closed"));
+        }
+
+        write_frame(self.connection, fin, opcode, payload)
+    }
+
+    pub fn send_text<S: AsRef<str>>(&mut self, text: S) -> Result<(), Error> {
+        self.send_frame(true, Opcode::Text, text.as_ref().as_bytes())
+    }
+
+    pub fn send_binary<B: AsRef<[u8]>>(&mut self, bytes: B) -> Result<(), Error> {
+        self.send_frame(true, Opcode::Binary, bytes.as_ref())
+    }
+
+    pub fn send_ping<B: AsRef<[u8]>>(&mut self, payload: B) -> Result<(), Error> {
+        self.send_frame(true, Opcode::Ping, payload.as_ref())
+    }
+
+    /// Sends a close frame. Nothing can be sent afterwards.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), Error> {
+        let mut payload = code.to_be_bytes().to_vec();
+
+        // The reason has to fit in a control frame.
+        let mut reason_length = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(reason_length) {
+            reason_length -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..reason_length]);
+
+        let result = self.send_frame(true, Opcode::Close, &payload);
+        self.is_closed = true;
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn masked_frame(first_byte: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+
+        let mut frame = vec![first_byte, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, x)| x ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455, section 1.3.
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_read_masked_frame() {
+        let mut reader = Cursor::new(masked_frame(0x81, b"Hello"));
+        let frame = read_frame(&mut reader, 1024).unwrap();
+
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, Opcode::Text);
+        assert_eq!(frame.payload, b"Hello");
+    }
+
+    #[test]
+    fn test_read_unmasked_frame() {
+        let mut reader = Cursor::new(vec![0x81, 0x05, b'H', b'e', b'l', b'l', b'o']);
+        let (code, _) = read_frame(&mut reader, 1024).unwrap_err();
+
+        assert_eq!(code, CLOSE_PROTOCOL_ERROR);
+    }
+
+    #[test]
+    fn test_read_invalid_control_frame() {
+        // Fragmented ping.
+        let mut reader = Cursor::new(masked_frame(0x09, b""));
+        assert!(read_frame(&mut reader, 1024).is_err());
+
+        // Reserved bit set.
+        let mut reader = Cursor::new(masked_frame(0xC1, b"a"));
+        assert!(read_frame(&mut reader, 1024).is_err());
+    }
+
+    #[test]
+    fn test_read_frame_timed_out() {
+        struct Idle;
+
+        impl Read for Idle {
+            fn read(&mut self, _: &mut [u8]) -> Result<usize, Error> {
+                Err(ErrorKind::WouldBlock.into())
+            }
+        }
+
+        let (code, _) = read_frame(&mut Idle, 1024).unwrap_err();
+
+        assert_eq!(code, CLOSE_GOING_AWAY);
+    }
+
+    #[test]
+    fn test_read_frame_too_big() {
+        let mut reader = Cursor::new(masked_frame(0x82, &[0; 100]));
+        let (code, _) = read_frame(&mut reader, 10).unwrap_err();
+
+        assert_eq!(code, CLOSE_TOO_BIG);
+    }
+
+    #[test]
+    fn test_write_frame_lengths() {
+        let mut output = Vec::new();
+        write_frame(&mut output, true, Opcode::Text, b"Hi").unwrap();
+        assert_eq!(output, [0x81, 0x02, b'H', b'i']);
+
+        let mut output = Vec::new();
+        write_frame(&mut output, false, Opcode::Binary, &[0; 300]).unwrap();
+        assert_eq!(output[..4], [0x02, 126, 0x01, 0x2C]);
+        assert_eq!(output.len(), 304);
+    }
+}
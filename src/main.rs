@@ -1,7 +1,7 @@
 use std::env::args;
 use std::process::ExitCode;
 use std::sync::{Arc, Mutex};
-use std::thread::{sleep, Builder};
+use std::thread::{sleep, Builder, JoinHandle};
 use std::time::Duration;
 
 extern crate toiletcli;
@@ -17,12 +17,19 @@ mod music;
 mod server;
 
 use common::logger::{Log, Logger, Verbosity};
+use common::threads::PoolStats;
+use common::util::Am;
 
-use server::dispatcher::start_dispatcher;
-use server::router::handle_routes;
+use server::config::{load_config, make_settings, SettingValues, Settings, SharedConfig};
+use server::dispatcher::{start_dispatcher, DispatchMode, DispatcherConfig, OverloadPolicy, Transport};
+use server::routes::make_app;
+use server::events::{event_bus, ServerEvent};
+use server::signals::{drain, install_signal_handlers, reload_requests, shutdown_requested};
+use server::state::ServerState;
+#[cfg(feature = "tls")]
+use server::tls::{HttpsRedirect, TlsAcceptor};
 
-use music::index::init_music_index;
-use music::index::make_index;
+use music::index::{make_index, update_index, Library};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -45,9 +52,160 @@ fn print_header(message: &str) {
 
 pub const DEFAULT_ADDRESS: &str = "0.0.0.0";
 pub const DEFAULT_PORT: u32 = 6969;
-pub const DEFAULT_THREAD_COUNT: usize = 8;
+pub const DEFAULT_MIN_THREAD_COUNT: usize = 2;
+pub const DEFAULT_MAX_THREAD_COUNT: usize = 32;
+pub const DEFAULT_QUEUE_SIZE: usize = 64;
 pub const DEFAULT_UTC: i8 = 0;
 pub const DEFAULT_VERBOSITY: u8 = 0;
+/// Threads serving redirects from plain HTTP to HTTPS.
+pub const REDIRECT_THREAD_COUNT: usize = 2;
+/// Seconds requests in flight get to finish after shutdown is requested.
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30;
+
+/// Chooses plain HTTP or HTTPS, depending on whether a certificate was given.
+#[cfg(feature = "tls")]
+fn make_transport(cert_path: String, key_path: String) -> Result<Transport, String> {
+    if cert_path.is_empty() && key_path.is_empty() {
+        return Ok(Transport::Plain);
+    }
+
+    if cert_path.is_empty() || key_path.is_empty() {
+        return Err("Both certificate and private key have to be specified".into());
+    }
+
+    TlsAcceptor::new(cert_path, key_path)
+        .map(|x| Transport::Tls(Arc::new(x)))
+        .map_err(|err| format!("Could not load TLS certificate: {}", err))
+}
+
+#[cfg(not(feature = "tls"))]
+fn make_transport(cert_path: String, key_path: String) -> Result<Transport, String> {
+    if cert_path.is_empty() && key_path.is_empty() {
+        Ok(Transport::Plain)
+    } else {
+        Err("Zest was built without TLS support, rebuild it with '--features tls'".into())
+    }
+}
+
+/// Starts redirecting plain HTTP on `address` to HTTPS on `port`. Returns
+/// whether the redirector stopped cleanly once it is joined.
+#[cfg(feature = "tls")]
+fn start_redirector(
+    address: String,
+    port: u32,
+    dispatcher_config: &DispatcherConfig,
+    connection_config: Arc<SharedConfig>,
+    logger: &Am<Logger>,
+) -> Result<JoinHandle<bool>, String> {
+    let redirect_logger = logger.clone();
+    let redirect_dispatcher_config = DispatcherConfig {
+        min_threads: 1,
+        max_threads: REDIRECT_THREAD_COUNT,
+        mode: DispatchMode::Threaded,
+        stats: Arc::new(PoolStats::default()),
+        ..dispatcher_config.clone()
+    };
+
+    log!(logger, "Starting the redirector ({} threads)...", REDIRECT_THREAD_COUNT);
+
+    Builder::new()
+        .name("redirector".into())
+        .spawn(move || {
+            let result = start_dispatcher(
+                address,
+                redirect_dispatcher_config,
+                Transport::Plain,
+                connection_config,
+                &redirect_logger,
+                Arc::new(HttpsRedirect { port }),
+            );
+
+            if let Err(err) = &result {
+                log!(redirect_logger, "*** A fatal error occured: {}", err);
+            }
+
+            result.is_ok()
+        })
+        .map_err(|err| format!("Could not start the redirector: {}", err))
+}
+
+#[cfg(not(feature = "tls"))]
+fn start_redirector(_: String, _: u32, _: &DispatcherConfig, _: Arc<SharedConfig>, _: &Am<Logger>)
+    -> Result<JoinHandle<bool>, String> {
+    Err("Zest was built without TLS support, rebuild it with '--features tls'".into())
+}
+
+/// Loads the index file again, and tells clients about it.
+/// Settings given by flags, overridden by the ones in the config file, if any.
+fn load_settings(flag_values: &SettingValues, config_path: &str) -> Result<Settings, String> {
+    let mut values = flag_values.clone();
+
+    if !config_path.is_empty() {
+        let file_values = load_config(config_path)
+            .map_err(|err| format!("Could not load '{}': {}", config_path, err))?;
+
+        values.extend(file_values);
+    }
+
+    make_settings(&values).map_err(|err| format!("Invalid settings: {}", err))
+}
+
+/// Applies the config file to new connections and the logger. Keeps the
+/// old settings if the file can't be used.
+fn reload_settings(flag_values: &SettingValues, config_path: &str, config: &SharedConfig, logger: &Am<Logger>) {
+    match load_settings(flag_values, config_path) {
+        Ok(settings) => {
+            config.replace(settings.connection);
+
+            if let Ok(mut logger) = logger.lock() {
+                logger.set_verbosity(settings.verbosity);
+            }
+
+            log!(logger, "Reloaded the settings from '{}'", config_path);
+        }
+        Err(err) => {
+            log!(logger, "*** Could not reload the settings, keeping the old ones: {}", err);
+        }
+    }
+}
+
+fn reload_index(library: &Library, logger: &Am<Logger>) {
+    match library.reload() {
+        Ok(count) => {
+            log!(logger, "Reloaded the index: {} tracks", count);
+            event_bus().publish(ServerEvent::IndexReloaded(count));
+        }
+        Err(err) => {
+            log!(logger, "*** Could not reload the index, keeping the old one: {}", err);
+        }
+    }
+}
+
+/// Keeps the index in sync with the library directory.
+#[cfg(target_os = "linux")]
+fn start_watcher(state: Arc<ServerState>, logger: Am<Logger>) -> Result<(), String> {
+    music::watcher::spawn_watcher(state, logger)
+        .map_err(|err| format!("Could not watch the library: {}", err))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn start_watcher(_: Arc<ServerState>, _: Am<Logger>) -> Result<(), String> {
+    Err("Watching the library is only available on Linux".into())
+}
+
+#[cfg(target_os = "linux")]
+fn make_dispatch_mode(epoll: bool) -> Result<DispatchMode, String> {
+    Ok(if epoll { DispatchMode::Epoll } else { DispatchMode::Threaded })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn make_dispatch_mode(epoll: bool) -> Result<DispatchMode, String> {
+    if epoll {
+        Err("Epoll is only available on Linux".into())
+    } else {
+        Ok(DispatchMode::Threaded)
+    }
+}
 
 fn entry() -> Result<(), String> {
     let mut args = args();
@@ -71,8 +229,8 @@ fn entry() -> Result<(), String> {
         eprintln!("    Music-streaming web-server.");
         eprintln!("");
         print_header("SUBCOMMANDS");
-        eprintln!("    serve [-ptaulvv] <index file>\tServe the music.");
-        eprintln!("    index [-v]       <directory> \tIndex directory and make an index file.");
+        eprintln!("    serve [-ptaukrboqclvv] <index file>\tServe the music.");
+        eprintln!("    index [-uv]             <directory> \tIndex directory and make an index file.");
         eprintln!("");
         print_header("OPTIONS");
         eprintln!("    --help                       \tDisplay this message.");
@@ -97,19 +255,61 @@ fn entry() -> Result<(), String> {
         "serve" => {
             let mut port_flag;
             let mut address_flag;
-            let mut thread_count_flag;
+            let mut min_threads_flag;
+            let mut max_threads_flag;
             let mut utc_flag;
             let mut log_file_flag;
             let mut verbosity_flag;
+            let mut keep_alive_flag;
+            let mut max_requests_flag;
+            let mut max_body_size_flag;
+            let mut origins_flag;
+            let mut credentials_flag;
+            let mut header_timeout_flag;
+            let mut body_timeout_flag;
+            let mut request_timeout_flag;
+            let mut write_timeout_flag;
+            let mut cert_flag;
+            let mut key_flag;
+            let mut redirect_port_flag;
+            let mut epoll_flag;
+            let mut watch_flag;
+            let mut queue_size_flag;
+            let mut overload_flag;
+            let mut drain_timeout_flag;
+            let mut websocket_echo_flag;
+            let mut max_streams_flag;
+            let mut config_flag;
 
             let mut show_help;
 
             let mut flags = flags!(
                 show_help: BoolFlag,           ["--help"],
-                thread_count_flag: StringFlag, ["-t", "--threads"],
+                max_threads_flag: StringFlag,  ["-t", "--threads", "--max-threads"],
+                min_threads_flag: StringFlag,  ["--min-threads"],
                 utc_flag: StringFlag,          ["-u", "--utc"],
                 port_flag: StringFlag,         ["-p", "--port"],
                 address_flag: StringFlag,      ["-a", "--address"],
+                keep_alive_flag: StringFlag,   ["-k", "--keep-alive"],
+                max_requests_flag: StringFlag, ["-r", "--max-requests"],
+                max_body_size_flag: StringFlag, ["-b", "--max-body"],
+                origins_flag: StringFlag,      ["-o", "--origins"],
+                credentials_flag: BoolFlag,    ["-c", "--credentials"],
+                header_timeout_flag: StringFlag,  ["--header-timeout"],
+                body_timeout_flag: StringFlag,    ["--body-timeout"],
+                request_timeout_flag: StringFlag, ["--request-timeout"],
+                write_timeout_flag: StringFlag,   ["--write-timeout"],
+                cert_flag: StringFlag,            ["--cert"],
+                key_flag: StringFlag,             ["--key"],
+                redirect_port_flag: StringFlag,   ["--redirect-port"],
+                epoll_flag: BoolFlag,             ["--epoll"],
+                watch_flag: BoolFlag,             ["--watch"],
+                queue_size_flag: StringFlag,      ["-q", "--queue"],
+                overload_flag: StringFlag,        ["--overload"],
+                drain_timeout_flag: StringFlag,   ["--drain-timeout"],
+                websocket_echo_flag: BoolFlag,    ["--ws-echo"],
+                max_streams_flag: StringFlag,     ["--max-streams"],
+                config_flag: StringFlag,          ["--config"],
                 log_file_flag: BoolFlag,       ["-l", "--log-file"],
                 verbosity_flag: RepeatFlag,    ["-v", "--verbose"]
             );
@@ -122,15 +322,32 @@ fn entry() -> Result<(), String> {
             let port = port_flag
                 .parse::<u32>()
                 .unwrap_or(DEFAULT_PORT);
-            let thread_count = thread_count_flag
+            let max_threads = max_threads_flag
+                .parse::<usize>()
+                .unwrap_or(DEFAULT_MAX_THREAD_COUNT);
+            let min_threads = min_threads_flag
                 .parse::<usize>()
-                .unwrap_or(DEFAULT_THREAD_COUNT);
+                .unwrap_or(DEFAULT_MIN_THREAD_COUNT)
+                .min(max_threads);
             let utc_offset = utc_flag
                 .parse::<i8>()
                 .unwrap_or(DEFAULT_UTC);
-            let verbosity: Verbosity =
-                (verbosity_flag as u8)
-                .into();
+            let seconds = |flag: &str, default: u64| {
+                Duration::from_secs(flag.parse::<u64>().unwrap_or(default).max(1))
+            };
+            let flag_values: SettingValues = [
+                ("origins", origins_flag),
+                ("credentials", if credentials_flag { "true".to_owned() } else { String::new() }),
+                ("keep-alive", keep_alive_flag),
+                ("max-requests", max_requests_flag),
+                ("max-body", max_body_size_flag),
+                ("header-timeout", header_timeout_flag),
+                ("body-timeout", body_timeout_flag),
+                ("request-timeout", request_timeout_flag),
+                ("write-timeout", write_timeout_flag),
+                ("verbose", verbosity_flag.to_string()),
+            ].into_iter().map(|(name, value)| (name.to_owned(), value)).collect();
+            let drain_timeout = seconds(&drain_timeout_flag, DEFAULT_DRAIN_TIMEOUT);
 
             if show_help {
                 print_header("USAGE");
@@ -140,8 +357,29 @@ fn entry() -> Result<(), String> {
                 print_header("OPTIONS");
                 eprintln!("    -p, --port <port>      \tSet server's port.");
                 eprintln!("    -a, --address <adress> \tSet server's address.");
-                eprintln!("    -t, --threads <count>  \tAmount of threads to create.");
+                eprintln!("    -t, --threads <count>  \tMaximum amount of threads to create under load.");
+                eprintln!("        --min-threads <count>   \tThreads to keep when idle.");
                 eprintln!("    -u, --utc <hours>      \tUTC adjustment for logger.");
+                eprintln!("    -k, --keep-alive <secs>\tClose idle connections after this many seconds.");
+                eprintln!("    -r, --max-requests <n> \tClose connections after this many requests.");
+                eprintln!("    -b, --max-body <bytes> \tReject requests with larger bodies.");
+                eprintln!("    -o, --origins <list>   \tComma-separated origins allowed to use the API.");
+                eprintln!("    -c, --credentials      \tAllow cross-origin requests with credentials from --origins.");
+                eprintln!("        --header-timeout <secs> \tTime limit for receiving request headers.");
+                eprintln!("        --body-timeout <secs>   \tTime limit for receiving request body.");
+                eprintln!("        --request-timeout <secs>\tTime limit for receiving the whole request.");
+                eprintln!("        --write-timeout <secs>  \tTime limit for a client to accept sent data.");
+                eprintln!("        --cert <file>           \tServe HTTPS with this PEM certificate chain.");
+                eprintln!("        --key <file>            \tPEM private key of the certificate.");
+                eprintln!("        --redirect-port <port>  \tRedirect plain HTTP on this port to HTTPS.");
+                eprintln!("        --epoll                 \tKeep idle connections in epoll instead of threads.");
+                eprintln!("        --watch                 \tUpdate the index when the library directory changes.");
+                eprintln!("    -q, --queue <count>    \tConnections that may wait for a free thread.");
+                eprintln!("        --overload <policy>     \tWhen the queue is full: 'reject' with 503, or 'block'.");
+                eprintln!("        --drain-timeout <secs>  \tTime requests get to finish on shutdown.");
+                eprintln!("        --max-streams <count>   \tEvent streams open at once, half of threads by default.");
+                eprintln!("        --ws-echo               \tServe WebSocket echo, for debugging.");
+                eprintln!("        --config <file>         \tRead settings from this file, and again on SIGHUP.");
                 eprintln!("    -l, --log-file         \tCreate a log file.");
                 eprintln!("    -v[v]                  \tLogging verbosity.");
                 eprintln!("        --help             \tDisplay this message.");
@@ -149,12 +387,53 @@ fn entry() -> Result<(), String> {
                 return Ok(());
             }
 
-            if let Some(filepath) = parsed_args.next() {
-                init_music_index(filepath)?;
-            } else {
+            let Some(filepath) = parsed_args.next() else {
                 return Err("Invalid amount of arguments".into());
+            };
+
+            let settings = load_settings(&flag_values, &config_flag)?;
+            let verbosity = settings.verbosity;
+            let connection_config = Arc::new(SharedConfig::new(settings.connection));
+
+            let library = Library::load(filepath.clone())
+                .map_err(|err| format!("Could not load '{}': {}", filepath, err))?;
+
+            let transport = make_transport(cert_flag, key_flag)?;
+            let overload = match overload_flag.as_str() {
+                "" | "reject" => OverloadPolicy::Reject,
+                "block" => OverloadPolicy::Block,
+                _ => return Err("Invalid overload policy".into()),
+            };
+            let state = Arc::new(ServerState {
+                library,
+                stats: Arc::new(PoolStats::default()),
+                max_event_streams: max_streams_flag
+                    .parse::<usize>()
+                    .unwrap_or(max_threads / 2)
+                    .min(max_threads.saturating_sub(1)),
+            });
+            let dispatcher_config = DispatcherConfig {
+                min_threads,
+                max_threads,
+                queue_size: queue_size_flag
+                    .parse::<usize>()
+                    .unwrap_or(DEFAULT_QUEUE_SIZE),
+                mode: make_dispatch_mode(epoll_flag)?,
+                overload,
+                stats: state.stats.clone(),
+            };
+            let redirect_port = (!redirect_port_flag.is_empty())
+                .then(|| redirect_port_flag.parse::<u32>())
+                .transpose()
+                .map_err(|_| "Invalid redirect port")?;
+
+            if redirect_port.is_some() && matches!(transport, Transport::Plain) {
+                return Err("Redirecting to HTTPS requires a certificate".into());
             }
 
+            install_signal_handlers()
+                .map_err(|err| format!("Could not install signal handlers: {}", err))?;
+
             warn_unstable();
             ask_to_report_bugs();
 
@@ -163,36 +442,110 @@ fn entry() -> Result<(), String> {
                 ));
             let dispatcher_logger = logger.clone();
 
-            log!(logger, "Starting the dispatcher ({} threads)...", thread_count);
+            let redirector = match redirect_port {
+                Some(redirect_port) => Some(start_redirector(
+                    format!("{address}:{redirect_port}"),
+                    port,
+                    &dispatcher_config,
+                    connection_config.clone(),
+                    &logger,
+                )?),
+                None => None,
+            };
+
+            if watch_flag {
+                log!(logger, "Starting the watcher...");
+
+                start_watcher(state.clone(), logger.clone())?;
+            }
+
+            log!(logger, "Starting the dispatcher ({} to {} threads)...", min_threads, max_threads);
 
-            let _ = Builder::new()
+            let app_state = state.clone();
+            let app_config = connection_config.clone();
+            let dispatcher = Builder::new()
                 .name("dispatcher".into())
                 .spawn(move || {
-                    let err = start_dispatcher(
+                    let result = start_dispatcher(
                         format!("{address}:{port}"),
-                        thread_count,
+                        dispatcher_config,
+                        transport,
+                        app_config,
                         &dispatcher_logger,
-                        handle_routes,
+                        Arc::new(make_app(app_state, websocket_echo_flag)),
                     );
 
-                    log!(dispatcher_logger, "*** A fatal error occured: {}", err.unwrap_err());
-                });
+                    if let Err(err) = &result {
+                        log!(dispatcher_logger, "*** A fatal error occured: {}", err);
+                    }
+
+                    result.is_ok()
+                })
+                .map_err(|err| format!("Could not start the dispatcher: {}", err))?;
 
             log!(logger, "Starting the logger (mode: {}, logfile: {}, {} hour offset)...",
                  verbosity, log_file_flag, utc_offset);
 
-            loop {
+            let mut reloaded_at = reload_requests();
+
+            while !shutdown_requested() && !dispatcher.is_finished() {
                 let _ = flush!(logger);
+
+                if reload_requests() != reloaded_at {
+                    reloaded_at = reload_requests();
+                    reload_index(&state.library, &logger);
+
+                    if !config_flag.is_empty() {
+                        reload_settings(&flag_values, &config_flag, &connection_config, &logger);
+                    }
+                }
+
                 sleep(Duration::from_millis(10));
             }
+
+            if !shutdown_requested() {
+                flush!(logger);
+                return Err("Dispatcher has stopped".into());
+            }
+
+            log!(logger, "Shutting down, giving requests {} seconds to finish...", drain_timeout.as_secs());
+
+            // Wakes up event streams, so they can end.
+            event_bus().publish(ServerEvent::ShuttingDown);
+
+            let is_finished = || {
+                dispatcher.is_finished() && redirector.as_ref().is_none_or(|x| x.is_finished())
+            };
+
+            let drained = drain(drain_timeout, is_finished, || {
+                flush!(logger);
+            });
+
+            let result = if !drained {
+                log!(logger, "*** Drain timeout has passed, cutting off {} busy workers", state.stats.busy());
+                Err("Requests were cut off on shutdown".into())
+            } else if !dispatcher.join().unwrap_or(false) {
+                Err("Dispatcher has failed while shutting down".into())
+            } else if !redirector.is_none_or(|x| x.join().unwrap_or(false)) {
+                Err("Redirector has failed".into())
+            } else {
+                log!(logger, "Shut down cleanly");
+                Ok(())
+            };
+
+            flush!(logger);
+
+            result
         }
         "index" => {
             let mut be_verbose;
+            let mut update_flag;
             let mut show_help;
 
             let mut flags: Vec<Flag> = flags!(
-                show_help: BoolFlag,  ["--help"],
-                be_verbose: BoolFlag, ["-v", "--verbose"]
+                show_help: BoolFlag,   ["--help"],
+                update_flag: BoolFlag, ["-u", "--update"],
+                be_verbose: BoolFlag,  ["-v", "--verbose"]
             );
 
             let mut parsed_args = parse_flags(&mut args, &mut flags)?.into_iter();
@@ -201,10 +554,29 @@ fn entry() -> Result<(), String> {
                 print_header("USAGE");
                 eprintln!("    {} index [-options] <music directory>", program_name);
                 eprintln!("    Index a directory and generate index file.");
+                eprintln!("    {} index --update [-options] <index file>", program_name);
+                eprintln!("    Scan the directory of an index file again, and update it in place.");
                 eprintln!("");
                 print_header("OPTIONS");
-                eprintln!("    -v        \tVerbose output.");
-                eprintln!("        --help\tDisplay this message.");
+                eprintln!("    -u, --update\tUpdate an existing index file.");
+                eprintln!("    -v          \tVerbose output.");
+                eprintln!("        --help  \tDisplay this message.");
+
+                return Ok(());
+            }
+
+            if update_flag {
+                let Some(index_path) = parsed_args.next() else {
+                    return Err("Not enough arguments".into());
+                };
+
+                eprintln!("Updating '{}'...", index_path);
+
+                let update = update_index(&index_path, be_verbose)
+                    .map_err(|err| format!("While updating '{}': {}", index_path, err))?;
+
+                eprintln!("Successfully updated '{}': {} added, {} changed, {} removed, {} unchanged.",
+                    index_path, update.added, update.changed, update.removed, update.unchanged);
 
                 return Ok(());
             }
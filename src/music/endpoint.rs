@@ -1,92 +1,323 @@
 use std::fs::File;
-use std::io::{Error, Read, Seek, SeekFrom};
+use std::io::{empty, Cursor, Error, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::common::{logger::Verbosity, util::Am};
+use crate::http::body::FileRegion;
+use crate::http::conditional::Validators;
 use crate::http::connection::HttpConnection;
+use crate::http::range::{content_range, parse_range_header, unsatisfied_content_range, ByteRange, RangeRequest};
 use crate::http::response::HttpResponse;
-use crate::music::index::get_music_index;
+use crate::music::index::{MusicIndex, TrackId};
+use crate::server::events::{event_bus, ServerEvent};
+use crate::server::state::ServerState;
 use crate::{log, log_geq, Log, Logger};
 
 const CHUNK_SIZE: usize = 1024 * 128; // 128 kb
 
-pub fn list_handler(connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error> {
-    let index = get_music_index();
+/// Track id from the route, e.g. `/tracks/:id`, or from `id` parameter. Older
+/// clients give the `name` parameter instead. `None` if the request doesn't
+/// say which track, `Some(None)` if there is no such track.
+fn track_id(connection: &HttpConnection, index: &MusicIndex) -> Option<Option<TrackId>> {
+    let params = connection.params();
+
+    let id = connection.path_params().get_str("id")
+        .or_else(|| params.and_then(|x| x.get("id")).map(|x| x.as_str()));
+
+    if let Some(id) = id {
+        return Some(index.track(id).map(|_| id.to_owned()));
+    }
+
+    let name = params.and_then(|x| x.get("name"))?;
+
+    Some(index.find_by_name(name).cloned())
+}
+
+pub fn list_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), Error> {
+    let index = state.library.snapshot();
+
+    let validators = Validators::for_generation(index.generation());
+
+    if connection.is_not_modified(&validators) {
+        log!(logger, "{} <= 304 Music list", connection.peer_string());
+
+        return HttpResponse::not_modified(&validators)
+            .send(connection);
+    }
 
     log!(logger, "{} <= Music list", connection.peer_string());
 
-    return HttpResponse::new(200, "OK")
-        .allow_all_origins(connection)
-        .set_json_body(&index.key_json_array())
-        .send(connection);
+    HttpResponse::new(200, "OK")
+        .set_validators(&validators)
+        .set_header("Content-Type", "application/json; charset=utf-8")
+        .set_owned_body(index.list_json().into_bytes())
+        .send(connection)
 }
 
-pub fn chunk_handler(connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error> {
-    let params = connection.params();
+/// Format and tags of a track.
+pub fn info_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), Error> {
+    let index = state.library.snapshot();
+
+    let Some(id) = track_id(connection, &index) else {
+        log!(logger, "{} <= 400 No id parameter", connection.peer_string());
+
+        return HttpResponse::new(400, "Bad Request")
+            .set_json_body(&"{ \"message\": \"Please specify track with path parameters\" }")
+            .send(connection);
+    };
+
+    let Some(info) = id.and_then(|x| index.info_json(&x)) else {
+        log!(logger, "{} <= 404 No such track", connection.peer_string());
+
+        return HttpResponse::new(404, "Not Found")
+            .set_json_body(&"{ \"message\": \"Track specified was not found\" }")
+            .send(connection);
+    };
+
+    let validators = Validators::for_generation(index.generation());
+
+    if connection.is_not_modified(&validators) {
+        log!(logger, "{} <= 304 Track info", connection.peer_string());
+
+        return HttpResponse::not_modified(&validators)
+            .send(connection);
+    }
+
+    log!(logger, "{} <= Track info", connection.peer_string());
 
-    let chunk = params
-        .and_then(|x| x.get("chunk"))
-        .and_then(|x| x.parse::<usize>().ok())
-        .unwrap_or(0 as usize);
+    HttpResponse::new(200, "OK")
+        .set_validators(&validators)
+        .set_header("Content-Type", "application/json; charset=utf-8")
+        .set_owned_body(info.into_bytes())
+        .send(connection)
+}
 
-    let track_result = params.and_then(|x| x.get("name"));
+/// Tells everyone listening to the event stream which track is playing.
+pub fn playing_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), Error> {
+    let index = state.library.snapshot();
+
+    let Some(id) = track_id(connection, &index) else {
+        log!(logger, "{} <= 400 No id parameter", connection.peer_string());
+
+        return HttpResponse::new(400, "Bad Request")
+            .set_json_body(&"{ \"message\": \"Please specify track with path parameters\" }")
+            .send(connection);
+    };
 
-    if let Some(filename) = track_result {
-        let filepath = get_music_index().get(filename);
+    let Some((id, track)) = id.and_then(|id| index.track(&id).map(|x| (id, x))) else {
+        log!(logger, "{} <= 404 No such track", connection.peer_string());
 
-        if let Some(path) = filepath {
-            return serve_music_chunk(connection, logger, chunk, path);
+        return HttpResponse::new(404, "Not Found")
+            .set_json_body(&"{ \"message\": \"Track specified was not found\" }")
+            .send(connection);
+    };
+
+    let event_id = event_bus().publish(ServerEvent::NowPlaying { id, name: track.name.clone() });
+
+    log!(logger, "{} <= 204 Now playing, event #{}", connection.peer_string(), event_id);
+
+    HttpResponse::new(204, "No Content")
+        .send(connection)
+}
+
+pub fn chunk_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), Error> {
+    let chunk = connection.path_params().get::<usize>("chunk")
+        .or_else(|| connection.params().and_then(|x| x.get("chunk")).and_then(|x| x.parse::<usize>().ok()))
+        .unwrap_or(0);
+
+    let index = state.library.snapshot();
+
+    if let Some(id) = track_id(connection, &index) {
+        if let Some(track) = id.and_then(|x| index.track(&x)) {
+            return serve_music_chunk(connection, logger, chunk, index.path_of(track), track.format.mime_type());
         } else {
             log!(logger, "{} <= 404 No such track",
                  connection.peer_string());
 
             return Ok(HttpResponse::new(404, "Not Found")
                 .set_json_body(&"{ \"message\": \"Track specified was not found\" }")
-                .allow_all_origins(connection)
                 .send(connection)?);
         }
     }
 
-    log!(logger, "{} <= 400 No name parameter", connection.peer_string());
+    log!(logger, "{} <= 400 No id parameter", connection.peer_string());
 
     HttpResponse::new(400, "Bad Request")
         .set_json_body(&"{ \"message\": \"Please specify track and chunk with path parameters\" }")
-        .allow_all_origins(connection)
         .send(connection)
 }
 
+/// Serves the whole track, honouring `Range` headers.
+pub fn stream_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), Error> {
+    let index = state.library.snapshot();
+
+    if let Some(id) = track_id(connection, &index) {
+        if let Some(track) = id.and_then(|x| index.track(&x)) {
+            return serve_music_ranges(connection, logger, index.path_of(track), track.format.mime_type());
+        } else {
+            log!(logger, "{} <= 404 No such track",
+                 connection.peer_string());
+
+            return HttpResponse::new(404, "Not Found")
+                .set_json_body(&"{ \"message\": \"Track specified was not found\" }")
+                .send(connection);
+        }
+    }
+
+    log!(logger, "{} <= 400 No id parameter", connection.peer_string());
+
+    HttpResponse::new(400, "Bad Request")
+        .set_json_body(&"{ \"message\": \"Please specify track with path parameters\" }")
+        .send(connection)
+}
+
+fn serve_music_ranges(
+    connection: &mut HttpConnection,
+    logger: &Am<Logger>,
+    path: String,
+    content_type: &str,
+) -> Result<(), Error> {
+    log_geq!(logger, Verbosity::Debug, "Reading from '{}'...", path);
+
+    let file = File::open(&path)?;
+    let metadata = file.metadata()?;
+    let length = metadata.len();
+
+    let validators = Validators::for_file(&metadata);
+
+    if connection.is_not_modified(&validators) {
+        log!(logger, "{} <= 304 Track", connection.peer_string());
+
+        return HttpResponse::not_modified(&validators)
+            .set_header("Accept-Ranges", "bytes")
+            .send(connection);
+    }
+
+    let ranges = connection.range(&validators)
+        .map_or(RangeRequest::Full, |x| parse_range_header(x, length));
+
+    match ranges {
+        RangeRequest::Full => {
+            log!(logger, "{} <= Track, {} bytes", connection.peer_string(), length);
+
+            HttpResponse::new(200, "OK")
+                .set_header("Content-Type", content_type)
+                .set_header("Accept-Ranges", "bytes")
+                .set_validators(&validators)
+                .set_file_body(file, 0, length)
+                .send(connection)
+        }
+        RangeRequest::Unsatisfiable => {
+            log!(logger, "{} <= 416 Range is out of bounds", connection.peer_string());
+
+            HttpResponse::new(416, "Range Not Satisfiable")
+                .set_header("Content-Range", unsatisfied_content_range(length))
+                .set_header("Accept-Ranges", "bytes")
+                .set_json_body(&"{ \"message\": \"Range is out of bounds.\" }")
+                .send(connection)
+        }
+        RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+
+            log!(logger, "{} <= Track, {}..={}",
+                 connection.peer_string(), range.start(), range.end());
+
+            HttpResponse::new(206, "Partial Content")
+                .set_header("Content-Type", content_type)
+                .set_header("Content-Range", content_range(range, length))
+                .set_header("Accept-Ranges", "bytes")
+                .set_validators(&validators)
+                .set_file_body(file, *range.start(), range_length(range))
+                .send(connection)
+        }
+        RangeRequest::Partial(ranges) => {
+            log!(logger, "{} <= Track, {} ranges", connection.peer_string(), ranges.len());
+
+            let boundary = make_boundary();
+
+            let mut content_length = 0;
+            let mut body: Box<dyn Read + Send> = Box::new(empty());
+
+            for range in &ranges {
+                let part_header = format!(
+                    "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                    boundary, content_type, content_range(range, length)
+                );
+                content_length += part_header.len() as u64 + range_length(range);
+
+                // Regions are read one after another, so they can share the file.
+                let region = FileRegion::new(file.try_clone()?, *range.start(), range_length(range));
+
+                body = Box::new(body.chain(Cursor::new(part_header)).chain(region));
+            }
+
+            let closing = format!("\r\n--{}--\r\n", boundary);
+            content_length += closing.len() as u64;
+
+            body = Box::new(body.chain(Cursor::new(closing)));
+
+            HttpResponse::new(206, "Partial Content")
+                .set_header("Content-Type", format!("multipart/byteranges; boundary={}", boundary))
+                .set_header("Accept-Ranges", "bytes")
+                .set_validators(&validators)
+                .set_reader_body(body, Some(content_length))
+                .send(connection)
+        }
+    }
+}
+
+fn range_length(range: &ByteRange) -> u64 {
+    range.end() - range.start() + 1
+}
+
+fn make_boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or(0);
+
+    format!("zest-{:016x}", nanos as u64)
+}
+
 fn serve_music_chunk(
     connection: &mut HttpConnection,
     logger: &Am<Logger>,
     chunk_index: usize,
     path: String,
+    content_type: &str,
 ) -> Result<(), Error> {
     log_geq!(logger, Verbosity::Debug, "Reading from '{}'...", path);
 
-    let mut file = File::open(&path)?;
-    let max_size = file.metadata()
-        .map(|x| x.len()).unwrap_or(0) as usize;
+    let file = File::open(&path)?;
+    let metadata = file.metadata()?;
+    let max_size = metadata.len() as usize;
+
+    // Every chunk is a part of the same file, so they share validators.
+    let validators = Validators::for_file(&metadata);
+
+    if connection.is_not_modified(&validators) {
+        log!(logger, "{} <= 304 Chunk {}", connection.peer_string(), chunk_index);
+
+        return HttpResponse::not_modified(&validators)
+            .send(connection);
+    }
 
     let start_pos = chunk_index * CHUNK_SIZE;
 
     if max_size < start_pos {
         return HttpResponse::new(416, "Range Not Satisfiable")
             .set_json_body(&"{ \"message\": \"Chunk is out of bounds.\" }")
-            .allow_all_origins(connection)
             .send(connection);
     }
 
-    file.seek(SeekFrom::Start(start_pos as u64))?;
-
-    let mut buffer = vec![0; CHUNK_SIZE as usize];
-    let bytes_read = file.read(&mut buffer[..CHUNK_SIZE as usize])?;
+    let chunk_size = CHUNK_SIZE.min(max_size - start_pos);
 
     log!(logger, "{} <= Chunk {}, {}..{}",
-         connection.peer_string(), chunk_index, start_pos, start_pos + CHUNK_SIZE);
-
-    return HttpResponse::new(200, "OK")
-        .set_header("Content-Type", "audio/mpeg")
-        .set_header("Content-Length", bytes_read)
-        .set_body(&buffer[..bytes_read])
-        .allow_all_origins(connection)
-        .send(connection);
+         connection.peer_string(), chunk_index, start_pos, start_pos + chunk_size);
+
+    HttpResponse::new(200, "OK")
+        .set_header("Content-Type", content_type)
+        .set_validators(&validators)
+        .set_file_body(file, start_pos as u64, chunk_size as u64)
+        .send(connection)
 }
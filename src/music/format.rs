@@ -0,0 +1,419 @@
+use std::io::{Error, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// How much of the file is searched for the first MPEG frame, after tags.
+/// Some encoders leave a bit of padding before it.
+const FRAME_SEARCH_SIZE: u64 = 4096;
+/// Tags before the audio are skipped this many times at most.
+const MAX_ID3_TAGS: usize = 4;
+/// Atoms and chunks are skipped this many times at most, so broken files
+/// can't keep the indexer busy.
+const MAX_BOXES: usize = 1024;
+
+/// Name of an MP4 atom, and where its contents are in the file.
+pub type Atom = ([u8; 4], Range<u64>);
+
+/// How the audio is stored in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// Bare MPEG audio frames, possibly after ID3 tags.
+    Mpeg,
+    /// Bare AAC frames, with ADTS headers.
+    Adts,
+    Flac,
+    Ogg,
+    /// ISO base media file, e.g. `.m4a`.
+    Mp4,
+    Wav,
+}
+
+/// How the audio itself is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Mp3,
+    Aac,
+    Alac,
+    Flac,
+    Vorbis,
+    Opus,
+    Pcm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub container: Container,
+    pub codec: Codec,
+}
+
+impl Container {
+    pub fn name(self) -> &'static str {
+        match self {
+            Container::Mpeg => "mpeg",
+            Container::Adts => "adts",
+            Container::Flac => "flac",
+            Container::Ogg => "ogg",
+            Container::Mp4 => "mp4",
+            Container::Wav => "wav",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Container::Mpeg, Container::Adts, Container::Flac, Container::Ogg, Container::Mp4, Container::Wav]
+            .into_iter()
+            .find(|x| x.name() == name)
+    }
+}
+
+impl Codec {
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::Mp3 => "mp3",
+            Codec::Aac => "aac",
+            Codec::Alac => "alac",
+            Codec::Flac => "flac",
+            Codec::Vorbis => "vorbis",
+            Codec::Opus => "opus",
+            Codec::Pcm => "pcm",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Codec::Mp3, Codec::Aac, Codec::Alac, Codec::Flac, Codec::Vorbis, Codec::Opus, Codec::Pcm]
+            .into_iter()
+            .find(|x| x.name() == name)
+    }
+}
+
+impl Format {
+    pub const MP3: Format = Format { container: Container::Mpeg, codec: Codec::Mp3 };
+
+    /// What a file is expected to be, judging by its extension. `None` for
+    /// files that can't be tracks.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let (container, codec) = match extension.to_ascii_lowercase().as_str() {
+            "mp3" => (Container::Mpeg, Codec::Mp3),
+            "aac" => (Container::Adts, Codec::Aac),
+            "flac" => (Container::Flac, Codec::Flac),
+            "ogg" | "oga" => (Container::Ogg, Codec::Vorbis),
+            "opus" => (Container::Ogg, Codec::Opus),
+            "m4a" | "mp4" => (Container::Mp4, Codec::Aac),
+            "wav" => (Container::Wav, Codec::Pcm),
+            _ => return None,
+        };
+
+        Some(Format { container, codec })
+    }
+
+    /// Content type the file is served with.
+    pub fn mime_type(&self) -> &'static str {
+        match (self.container, self.codec) {
+            (Container::Mpeg, _) => "audio/mpeg",
+            (Container::Adts, _) => "audio/aac",
+            (Container::Flac, _) => "audio/flac",
+            (Container::Ogg, Codec::Opus) => "audio/ogg; codecs=opus",
+            (Container::Ogg, Codec::Flac) => "audio/ogg; codecs=flac",
+            (Container::Ogg, _) => "audio/ogg; codecs=vorbis",
+            (Container::Mp4, _) => "audio/mp4",
+            (Container::Wav, _) => "audio/wav",
+        }
+    }
+}
+
+/// Format of the file, by its first bytes. `None` if it is none of the
+/// supported ones.
+pub fn detect_format<R: Read + Seek>(reader: &mut R) -> Result<Option<Format>, Error> {
+    let mut offset = 0;
+
+    for _ in 0..=MAX_ID3_TAGS {
+        let header = read_at(reader, offset, 36)?;
+
+        if header.len() < 4 {
+            return Ok(None);
+        }
+
+        let format = match &header[..4] {
+            [b'I', b'D', b'3', _] => {
+                let Some(size) = id3_tag_size(&header) else {
+                    return Ok(None);
+                };
+
+                offset += size;
+                continue;
+            }
+            b"fLaC" => Some(Format { container: Container::Flac, codec: Codec::Flac }),
+            // Only FLAC and MPEG streams are expected after tags.
+            _ if offset > 0 => find_frame(&read_at(reader, offset, FRAME_SEARCH_SIZE)?),
+            b"OggS" => ogg_codec(reader, &header)?.map(|codec| Format { container: Container::Ogg, codec }),
+            b"RIFF" if header.get(8..12) == Some(b"WAVE") => wav_codec(reader)?
+                .map(|codec| Format { container: Container::Wav, codec }),
+            _ if header.get(4..8) == Some(b"ftyp") => mp4_codec(reader)?
+                .map(|codec| Format { container: Container::Mp4, codec }),
+            _ => find_frame(&read_at(reader, offset, FRAME_SEARCH_SIZE)?),
+        };
+
+        return Ok(format);
+    }
+
+    Ok(None)
+}
+
+/// Reads up to `length` bytes from `offset`. Fewer if the file ends before.
+pub fn read_at<R: Read + Seek + ?Sized>(reader: &mut R, offset: u64, length: u64) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+
+    reader.seek(SeekFrom::Start(offset))?;
+    (&mut *reader).take(length).read_to_end(&mut bytes)?;
+
+    Ok(bytes)
+}
+
+/// Size of an ID3v2 tag, with its header and footer.
+pub fn id3_tag_size(header: &[u8]) -> Option<u64> {
+    let size = header.get(6..10)?;
+
+    if size.iter().any(|x| x & 0x80 != 0) {
+        return None;
+    }
+
+    let size = size.iter().fold(0u64, |size, x| size << 7 | *x as u64);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+
+    Some(10 + size + footer)
+}
+
+/// Format of the first MPEG audio or ADTS frame in `bytes`.
+fn find_frame(bytes: &[u8]) -> Option<Format> {
+    bytes.windows(4).find_map(|x| frame_format(x.try_into().unwrap()))
+}
+
+fn frame_format(header: [u8; 4]) -> Option<Format> {
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    let layer = (header[1] >> 1) & 0b11;
+
+    // ADTS has a 12 bit sync word, and layer is always zero.
+    if layer == 0 {
+        let frequency = (header[2] >> 2) & 0b1111;
+
+        return (header[1] & 0xF6 == 0xF0 && frequency < 13)
+            .then_some(Format { container: Container::Adts, codec: Codec::Aac });
+    }
+
+    let version = (header[1] >> 3) & 0b11;
+    let bitrate = header[2] >> 4;
+    let frequency = (header[2] >> 2) & 0b11;
+
+    // Only layer III is supported, layers I and II are rare enough.
+    (version != 0b01 && layer == 0b01 && bitrate != 0b1111 && frequency != 0b11)
+        .then_some(Format::MP3)
+}
+
+/// Codec of the first packet, which starts right after the segment table of
+/// the first page.
+fn ogg_codec<R: Read + Seek>(reader: &mut R, header: &[u8]) -> Result<Option<Codec>, Error> {
+    let Some(segments) = header.get(26) else {
+        return Ok(None);
+    };
+
+    let packet = read_at(reader, 27 + *segments as u64, 8)?;
+
+    let codec = if packet.starts_with(b"\x01vorbis") {
+        Some(Codec::Vorbis)
+    } else if packet.starts_with(b"OpusHead") {
+        Some(Codec::Opus)
+    } else if packet.starts_with(b"\x7fFLAC") {
+        Some(Codec::Flac)
+    } else {
+        None
+    };
+
+    Ok(codec)
+}
+
+/// Codec from the `fmt ` chunk.
+fn wav_codec<R: Read + Seek>(reader: &mut R) -> Result<Option<Codec>, Error> {
+    let mut offset = 12;
+
+    for _ in 0..MAX_BOXES {
+        let header = read_at(reader, offset, 10)?;
+
+        if header.len() < 10 {
+            return Ok(None);
+        }
+
+        let size = u32::from_le_bytes(header[4..8].try_into().unwrap()) as u64;
+
+        if &header[..4] == b"fmt " {
+            let codec = match u16::from_le_bytes([header[8], header[9]]) {
+                // Integer, floating point, or either with a channel layout.
+                0x0001 | 0x0003 | 0xFFFE => Some(Codec::Pcm),
+                0x0055 => Some(Codec::Mp3),
+                _ => None,
+            };
+
+            return Ok(codec);
+        }
+
+        // Chunks are padded to an even size.
+        offset += 8 + size + size % 2;
+    }
+
+    Ok(None)
+}
+
+/// Codec of the first audio track, from its sample description.
+fn mp4_codec<R: Read + Seek>(reader: &mut R) -> Result<Option<Codec>, Error> {
+    let length = reader.seek(SeekFrom::End(0))?;
+
+    let Some(moov) = find_atom(reader, 0..length, b"moov")? else {
+        return Ok(None);
+    };
+
+    for (name, trak) in atoms(reader, moov)? {
+        if &name != b"trak" {
+            continue;
+        }
+
+        let mut range = Some(trak);
+
+        for path in [b"mdia", b"minf", b"stbl", b"stsd"] {
+            range = match range {
+                Some(x) => find_atom(reader, x, path)?,
+                None => break,
+            };
+        }
+
+        let Some(stsd) = range else {
+            continue;
+        };
+
+        // Version, flags and number of entries come before the first entry.
+        let entry = read_at(reader, stsd.start + 8, 8)?;
+
+        let codec = match entry.get(4..8) {
+            Some(b"mp4a") => Codec::Aac,
+            Some(b"alac") => Codec::Alac,
+            Some(b"fLaC") => Codec::Flac,
+            Some(b"Opus") => Codec::Opus,
+            _ => continue,
+        };
+
+        return Ok(Some(codec));
+    }
+
+    Ok(None)
+}
+
+/// Contents of the first atom called `name` within `range`.
+pub fn find_atom<R: Read + Seek + ?Sized>(
+    reader: &mut R,
+    range: Range<u64>,
+    name: &[u8; 4],
+) -> Result<Option<Range<u64>>, Error> {
+    Ok(atoms(reader, range)?.into_iter().find(|x| &x.0 == name).map(|x| x.1))
+}
+
+/// Names and contents of MP4 atoms one after another within `range`.
+pub fn atoms<R: Read + Seek + ?Sized>(reader: &mut R, range: Range<u64>) -> Result<Vec<Atom>, Error> {
+    let mut atoms = Vec::new();
+    let mut offset = range.start;
+
+    while offset + 8 <= range.end && atoms.len() < MAX_BOXES {
+        let header = read_at(reader, offset, 16)?;
+
+        if header.len() < 8 {
+            break;
+        }
+
+        let name: [u8; 4] = header[4..8].try_into().unwrap();
+
+        let (start, size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // Atom goes on until the end.
+            0 => (offset + 8, range.end - offset),
+            // Size doesn't fit in 32 bits, and follows the name.
+            1 if header.len() == 16 => (offset + 16, u64::from_be_bytes(header[8..16].try_into().unwrap())),
+            size => (offset + 8, size as u64),
+        };
+
+        let end = offset.saturating_add(size);
+
+        if end < start || end > range.end {
+            break;
+        }
+
+        atoms.push((name, start..end));
+        offset = end;
+    }
+
+    Ok(atoms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn detect(bytes: Vec<u8>) -> Option<Format> {
+        detect_format(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn atom(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u32 + 8).to_be_bytes()[..], name, body].concat()
+    }
+
+    #[test]
+    fn test_detect_mpeg_after_id3() {
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+        bytes.extend([0u8; 128]);
+        bytes.extend([0u8; 16]); // Padding before the first frame.
+        bytes.extend([0xFF, 0xFB, 0x90, 0x64]);
+
+        assert_eq!(detect(bytes), Some(Format::MP3));
+        assert_eq!(detect(vec![0xFF, 0xF1, 0x50, 0x80]), Some(Format { container: Container::Adts, codec: Codec::Aac }));
+        assert_eq!(detect(b"not music at all".to_vec()), None);
+    }
+
+    #[test]
+    fn test_detect_ogg() {
+        let mut page = b"OggS\x00\x02".to_vec();
+        page.extend([0u8; 20]);
+        page.extend([1, 19]);
+        page.extend(b"OpusHead\x01\x02");
+
+        assert_eq!(detect(page), Some(Format { container: Container::Ogg, codec: Codec::Opus }));
+    }
+
+    #[test]
+    fn test_detect_wav_and_flac() {
+        let mut wav = b"RIFF\x00\x00\x00\x00WAVE".to_vec();
+        wav.extend(b"LIST\x03\x00\x00\x00abc\x00");
+        wav.extend(b"fmt \x10\x00\x00\x00\x01\x00\x02\x00");
+
+        assert_eq!(detect(wav), Some(Format { container: Container::Wav, codec: Codec::Pcm }));
+        assert_eq!(detect(b"fLaC\x00\x00\x00\x22".to_vec()), Some(Format { container: Container::Flac, codec: Codec::Flac }));
+    }
+
+    #[test]
+    fn test_detect_mp4() {
+        let stsd = atom(b"stsd", &[&[0u8, 0, 0, 0, 0, 0, 0, 1][..], &atom(b"alac", &[0; 8])].concat());
+        let stbl = atom(b"stbl", &stsd);
+        let trak = atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)));
+
+        let file = [atom(b"ftyp", b"M4A \x00\x00\x00\x00"), atom(b"mdat", &[0; 32]), atom(b"moov", &trak)].concat();
+
+        assert_eq!(detect(file), Some(Format { container: Container::Mp4, codec: Codec::Alac }));
+        assert_eq!(detect(atom(b"ftyp", b"M4A ")), None);
+    }
+
+    #[test]
+    fn test_format_names() {
+        let format = Format::from_extension("OPUS").unwrap();
+
+        assert_eq!(format.mime_type(), "audio/ogg; codecs=opus");
+        assert_eq!(Container::from_name(format.container.name()), Some(Container::Ogg));
+        assert_eq!(Codec::from_name(format.codec.name()), Some(Codec::Opus));
+        assert_eq!(Format::from_extension("jpg"), None);
+    }
+}
@@ -0,0 +1,411 @@
+use std::borrow::Cow;
+use std::io::{Error, Read, Seek, SeekFrom};
+
+use crate::music::format::read_at;
+use crate::music::tags::{Field, ReadSeek, TagReader, Tags};
+
+/// Size of the ID3v2 header, and of the frame headers since ID3v2.3.
+const HEADER_SIZE: usize = 10;
+/// Size of the frame headers of ID3v2.2.
+const V22_FRAME_HEADER_SIZE: usize = 6;
+/// ID3v1 tags take exactly this much at the end of the file.
+const ID3V1_SIZE: u64 = 128;
+
+/// Genres of ID3v1 with Winamp extensions. ID3v2 refers to them by number too.
+const GENRES: [&str; 148] = [
+    "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz", "Metal",
+    "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno", "Industrial",
+    "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno", "Ambient", "Trip-Hop",
+    "Vocal", "Jazz+Funk", "Fusion", "Trance", "Classical", "Instrumental", "Acid", "House", "Game",
+    "Sound Clip", "Gospel", "Noise", "AlternRock", "Bass", "Soul", "Punk", "Space", "Meditative",
+    "Instrumental Pop", "Instrumental Rock", "Ethnic", "Gothic", "Darkwave", "Techno-Industrial",
+    "Electronic", "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta",
+    "Top 40", "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret", "New Wave",
+    "Psychadelic", "Rave", "Showtunes", "Trailer", "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz", "Polka",
+    "Retro", "Musical", "Rock & Roll", "Hard Rock", "Folk", "Folk-Rock", "National Folk", "Swing",
+    "Fast Fusion", "Bebob", "Latin", "Revival", "Celtic", "Bluegrass", "Avantgarde", "Gothic Rock",
+    "Progressive Rock", "Psychedelic Rock", "Symphonic Rock", "Slow Rock", "Big Band", "Chorus",
+    "Easy Listening", "Acoustic", "Humour", "Speech", "Chanson", "Opera", "Chamber Music", "Sonata",
+    "Symphony", "Booty Bass", "Primus", "Porn Groove", "Satire", "Slow Jam", "Club", "Tango", "Samba",
+    "Folklore", "Ballad", "Power Ballad", "Rhythmic Soul", "Freestyle", "Duet", "Punk Rock",
+    "Drum Solo", "A capella", "Euro-House", "Dance Hall", "Goa", "Drum & Bass", "Club-House",
+    "Hardcore", "Terror", "Indie", "BritPop", "Afro-Punk", "Polsk Punk", "Beat", "Christian Gangsta Rap",
+    "Heavy Metal", "Black Metal", "Crossover", "Contemporary Christian", "Christian Rock", "Merengue",
+    "Salsa", "Thrash Metal", "Anime", "JPop", "Synthpop",
+];
+
+/// Reads the ID3v2 tag at the start of the file, and fills in what it lacks
+/// from the ID3v1 tag at the end. Tags that can't be made sense of are
+/// ignored.
+pub struct Id3Reader;
+
+impl TagReader for Id3Reader {
+    fn read_tags(&self, reader: &mut dyn ReadSeek) -> Result<Tags, Error> {
+        Ok(read_id3v2(reader)?.or(read_id3v1(reader)?))
+    }
+}
+
+/// Genre of ID3v1 by its number.
+pub fn id3_genre(number: usize) -> Option<&'static str> {
+    GENRES.get(number).copied()
+}
+
+fn read_id3v2<R: Read + Seek + ?Sized>(reader: &mut R) -> Result<Tags, Error> {
+    let mut tags = Tags::default();
+
+    let header = read_at(reader, 0, HEADER_SIZE as u64)?;
+
+    if header.len() < HEADER_SIZE || &header[..3] != b"ID3" {
+        return Ok(tags);
+    }
+
+    let (version, flags) = (header[3], header[5]);
+    let unsynchronised = flags & 0x80 != 0;
+
+    let Some(size) = syncsafe(&header[6..10]) else {
+        return Ok(tags);
+    };
+
+    if !(2..=4).contains(&version) {
+        return Ok(tags);
+    }
+
+    let mut body = Cow::Owned(read_at(reader, HEADER_SIZE as u64, size as u64)?);
+
+    // Before ID3v2.4, the whole tag is unsynchronised at once.
+    if unsynchronised && version < 4 {
+        body = Cow::Owned(resynchronise(&body));
+    }
+
+    let mut start = 0;
+
+    if flags & 0x40 != 0 {
+        start = match version {
+            // Means the tag is compressed, which no one does.
+            2 => return Ok(tags),
+            // Size doesn't include itself.
+            3 => body.get(..4).map_or(body.len(), |x| 4 + be_number(x)),
+            _ => body.get(..4).and_then(syncsafe).unwrap_or(body.len()),
+        };
+    }
+
+    read_frames(body.get(start..).unwrap_or_default(), version, unsynchronised, &mut tags);
+
+    Ok(tags)
+}
+
+fn read_frames(mut bytes: &[u8], version: u8, unsynchronised: bool, tags: &mut Tags) {
+    let header_size = if version == 2 { V22_FRAME_HEADER_SIZE } else { HEADER_SIZE };
+
+    // Padding follows the last frame.
+    while bytes.len() >= header_size && bytes[0] != 0 {
+        let (id, size, format_flags) = match version {
+            2 => (&bytes[..3], be_number(&bytes[3..6]), 0),
+            3 => (&bytes[..4], be_number(&bytes[4..8]), bytes[9]),
+            _ => match syncsafe(&bytes[4..8]) {
+                Some(size) => (&bytes[..4], size, bytes[9]),
+                None => return,
+            },
+        };
+
+        let Some(data) = bytes.get(header_size..header_size + size) else {
+            return;
+        };
+
+        if let Some(data) = frame_data(data, version, format_flags, unsynchronised) {
+            read_frame(id, &data, tags);
+        }
+
+        bytes = &bytes[header_size + size..];
+    }
+}
+
+/// Contents of the frame without the extra data its flags announce. `None` if
+/// it is compressed or encrypted.
+fn frame_data(data: &[u8], version: u8, flags: u8, unsynchronised: bool) -> Option<Cow<'_, [u8]>> {
+    match version {
+        2 => Some(Cow::Borrowed(data)),
+        3 => {
+            if flags & 0xC0 != 0 {
+                return None;
+            }
+
+            // Group id.
+            let skip = if flags & 0x20 != 0 { 1 } else { 0 };
+
+            Some(Cow::Borrowed(data.get(skip..)?))
+        }
+        _ => {
+            if flags & 0x0C != 0 {
+                return None;
+            }
+
+            // Group id, then data length.
+            let skip = if flags & 0x40 != 0 { 1 } else { 0 } + if flags & 0x01 != 0 { 4 } else { 0 };
+            let data = data.get(skip..)?;
+
+            if flags & 0x02 != 0 || unsynchronised {
+                Some(Cow::Owned(resynchronise(data)))
+            } else {
+                Some(Cow::Borrowed(data))
+            }
+        }
+    }
+}
+
+fn read_frame(id: &[u8], data: &[u8], tags: &mut Tags) {
+    let field = match id {
+        b"TIT2" | b"TT2" => Field::Title,
+        b"TPE1" | b"TP1" => Field::Artist,
+        b"TALB" | b"TAL" => Field::Album,
+        b"TPE2" | b"TP2" => Field::AlbumArtist,
+        b"TRCK" | b"TRK" => Field::TrackNumber,
+        b"TPOS" | b"TPA" => Field::DiscNumber,
+        b"TDRC" | b"TYER" | b"TYE" => Field::Year,
+        b"TCON" | b"TCO" => Field::Genre,
+        b"COMM" | b"COM" => {
+            // Language, then a description, then the text. Comments with a
+            // description are usually for programs, like iTunes' ones.
+            if let Some((&encoding, rest)) = data.split_first() {
+                let values = decode_text(encoding, rest.get(3..).unwrap_or_default());
+
+                if let [description, text, ..] = &values[..] {
+                    if description.is_empty() {
+                        tags.set(Field::Comment, text);
+                    }
+                }
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    let Some((&encoding, rest)) = data.split_first() else {
+        return;
+    };
+
+    let values: Vec<_> = decode_text(encoding, rest).into_iter()
+        .filter(|x| !x.is_empty())
+        .map(|x| if field == Field::Genre { genre_name(&x) } else { x })
+        .collect();
+
+    tags.set(field, &values.join("/"));
+}
+
+/// Strings of a text frame, which are separated by terminators. ID3v2.4
+/// allows several values in one frame.
+fn decode_text(encoding: u8, bytes: &[u8]) -> Vec<String> {
+    match encoding {
+        // ISO-8859-1, whose characters are the first 256 of Unicode.
+        0 => bytes.split(|x| *x == 0).map(|x| x.iter().map(|x| *x as char).collect()).collect(),
+        3 => bytes.split(|x| *x == 0).map(|x| String::from_utf8_lossy(x).into_owned()).collect(),
+        1 | 2 => {
+            let units: Vec<u16> = bytes.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect();
+
+            units.split(|x| *x == 0).map(|units| {
+                // Encoding 1 starts every string with a byte order mark,
+                // encoding 2 is always big endian.
+                let (units, little_endian) = match units.split_first() {
+                    Some((0xFEFF, rest)) if encoding == 1 => (rest, false),
+                    Some((0xFFFE, rest)) if encoding == 1 => (rest, true),
+                    _ => (units, encoding == 1),
+                };
+
+                let units = units.iter().map(|x| if little_endian { x.swap_bytes() } else { *x });
+
+                char::decode_utf16(units).map(|x| x.unwrap_or(char::REPLACEMENT_CHARACTER)).collect()
+            }).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Genres are either text, `(17)` or `17` for a genre of ID3v1, or `(17)Rock`
+/// with text refining it.
+fn genre_name(value: &str) -> String {
+    let number = match value.strip_prefix('(').and_then(|x| x.split_once(')')) {
+        Some((_, refinement)) if !refinement.is_empty() => return refinement.to_owned(),
+        Some(("RX", _)) => return "Remix".to_owned(),
+        Some(("CR", _)) => return "Cover".to_owned(),
+        Some((number, _)) => number,
+        None => value,
+    };
+
+    number.parse::<usize>().ok()
+        .and_then(|x| GENRES.get(x))
+        .map_or_else(|| value.to_owned(), |x| x.to_string())
+}
+
+/// ID3v1 has fixed size fields, padded with zeros or spaces. ID3v1.1 takes
+/// the last two bytes of the comment for the track number.
+fn read_id3v1<R: Read + Seek + ?Sized>(reader: &mut R) -> Result<Tags, Error> {
+    let mut tags = Tags::default();
+
+    let length = reader.seek(SeekFrom::End(0))?;
+
+    if length < ID3V1_SIZE {
+        return Ok(tags);
+    }
+
+    let tag = read_at(reader, length - ID3V1_SIZE, ID3V1_SIZE)?;
+
+    if tag.len() as u64 != ID3V1_SIZE || &tag[..3] != b"TAG" {
+        return Ok(tags);
+    }
+
+    let text = |range: std::ops::Range<usize>| {
+        let field = &tag[range];
+        let end = field.iter().position(|x| *x == 0).unwrap_or(field.len());
+
+        decode_text(0, &field[..end]).concat()
+    };
+
+    let has_track = tag[125] == 0 && tag[126] != 0;
+
+    tags.set(Field::Title, &text(3..33));
+    tags.set(Field::Artist, &text(33..63));
+    tags.set(Field::Album, &text(63..93));
+    tags.set(Field::Year, &text(93..97));
+    tags.set(Field::Comment, &text(97..if has_track { 125 } else { 127 }));
+
+    if has_track {
+        tags.set(Field::TrackNumber, &tag[126].to_string());
+    }
+
+    if let Some(genre) = GENRES.get(tag[127] as usize) {
+        tags.set(Field::Genre, genre);
+    }
+
+    Ok(tags)
+}
+
+/// Undoes unsynchronisation, which puts a zero after every 0xFF that could be
+/// mistaken for the start of an MPEG frame.
+fn resynchronise(bytes: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(bytes.len());
+
+    for (i, byte) in bytes.iter().enumerate() {
+        if *byte == 0 && i > 0 && bytes[i - 1] == 0xFF {
+            continue;
+        }
+        result.push(*byte);
+    }
+
+    result
+}
+
+/// Number whose bytes only use 7 bits, so it never looks like a frame sync.
+fn syncsafe(bytes: &[u8]) -> Option<usize> {
+    if bytes.iter().any(|x| x & 0x80 != 0) {
+        return None;
+    }
+
+    Some(bytes.iter().fold(0, |size, x| size << 7 | *x as usize))
+}
+
+fn be_number(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |size, x| size << 8 | *x as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn syncsafe_bytes(size: usize) -> [u8; 4] {
+        [(size >> 21) as u8 & 0x7F, (size >> 14) as u8 & 0x7F, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]
+    }
+
+    fn frame(id: &[u8; 4], flags: u8, data: &[u8]) -> Vec<u8> {
+        [&id[..], &(data.len() as u32).to_be_bytes(), &[0, flags], data].concat()
+    }
+
+    fn v24_frame(id: &[u8; 4], flags: u8, data: &[u8]) -> Vec<u8> {
+        [&id[..], &syncsafe_bytes(data.len()), &[0, flags], data].concat()
+    }
+
+    fn tag(version: u8, flags: u8, body: &[u8]) -> Vec<u8> {
+        [&b"ID3"[..], &[version, 0, flags], &syncsafe_bytes(body.len()), body].concat()
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        [0xFF, 0xFE].into_iter().chain(text.encode_utf16().flat_map(|x| x.to_le_bytes())).collect()
+    }
+
+    #[test]
+    fn test_id3v23_frames() {
+        let body = [
+            frame(b"TIT2", 0, &[&[1][..], &utf16("Мелодия")].concat()),
+            frame(b"TPE1", 0, b"\x00Caf\xe9"),
+            frame(b"TRCK", 0, b"\x003/12"),
+            frame(b"TCON", 0, b"\x00(17)"),
+            frame(b"COMM", 0, b"\x00engiTunNORM\x00 0000"),
+            frame(b"COMM", 0, b"\x00eng\x00Nice one"),
+            frame(b"TALB", 0x80, b"compressed"),
+            vec![0; 32],
+        ].concat();
+
+        let tags = Id3Reader.read_tags(&mut Cursor::new(tag(3, 0, &body))).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Мелодия"));
+        assert_eq!(tags.artist.as_deref(), Some("Café"));
+        assert_eq!(tags.track_number, Some(3));
+        assert_eq!(tags.genre.as_deref(), Some("Rock"));
+        assert_eq!(tags.comment.as_deref(), Some("Nice one"));
+        assert_eq!(tags.album, None);
+    }
+
+    #[test]
+    fn test_id3v24_unsynchronised() {
+        let body = [
+            // Extended header of 6 bytes, including its size.
+            b"\x00\x00\x00\x06\x01\x00".to_vec(),
+            // UTF-8 with several values.
+            v24_frame(b"TPE1", 0, b"\x03A\x00B"),
+            // UTF-16BE "ÿþ", unsynchronised, after its length.
+            v24_frame(b"TIT2", 0x03, b"\x00\x00\x00\x05\x02\x00\xFF\x00\x00\xFE"),
+            v24_frame(b"TDRC", 0, b"\x032003-05-01"),
+        ].concat();
+
+        let tags = Id3Reader.read_tags(&mut Cursor::new(tag(4, 0x40, &body))).unwrap();
+
+        assert_eq!(tags.artist.as_deref(), Some("A/B"));
+        assert_eq!(tags.title.as_deref(), Some("ÿþ"));
+        assert_eq!(tags.year, Some(2003));
+    }
+
+    #[test]
+    fn test_id3v22_and_v1_fallback() {
+        let body = [&b"TT2\x00\x00\x06\x00Title"[..], b"TYE\x00\x00\x05\x001999"].concat();
+
+        let mut file = tag(2, 0, &body);
+        file.extend([0xFF, 0xFB, 0x90, 0x64]);
+
+        let mut v1 = [0u8; 128];
+        v1[..3].copy_from_slice(b"TAG");
+        v1[3..12].copy_from_slice(b"Old title");
+        v1[33..39].copy_from_slice(b"Artist");
+        v1[126] = 4;
+        v1[127] = 8;
+
+        file.extend(v1);
+
+        let tags = Id3Reader.read_tags(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Title"));
+        assert_eq!(tags.year, Some(1999));
+        assert_eq!(tags.artist.as_deref(), Some("Artist"));
+        assert_eq!(tags.track_number, Some(4));
+        assert_eq!(tags.genre.as_deref(), Some("Jazz"));
+    }
+
+    #[test]
+    fn test_genre_names() {
+        assert_eq!(genre_name("(17)"), "Rock");
+        assert_eq!(genre_name("17"), "Rock");
+        assert_eq!(genre_name("(4)Eurodisco"), "Eurodisco");
+        assert_eq!(genre_name("(RX)"), "Remix");
+        assert_eq!(genre_name("Shoegaze"), "Shoegaze");
+        assert_eq!(genre_name("(999)"), "(999)");
+    }
+}
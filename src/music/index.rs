@@ -1,193 +1,507 @@
-use crate::common::{util::{iter_to_json_string, FileName, FilePath}};
+use crate::common::{json::{quote, JsonValue}, sha1::sha1, util::{FileName, FilePath}};
+use crate::music::format::{detect_format, Codec, Container, Format};
+use crate::music::tags::{read_tags, Tags};
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    fs::{self, File, Metadata},
+    io::{BufWriter, Error, ErrorKind, Write},
     path::Path,
-    sync::Once,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-pub type IndexMap = HashMap<FileName, FilePath>;
+/// Identifies a track for as long as its file stays where it is, even if the
+/// library is indexed again. Derived from the path, see `track_id`.
+pub type TrackId = String;
+
+pub type IndexMap = HashMap<TrackId, Track>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// Shown to users, not necessarily unique.
+    pub name: FileName,
+    /// Relative to the root of the index.
+    pub path: FilePath,
+    pub format: Format,
+    pub tags: Tags,
+    /// Modification time of the file, in seconds since the epoch. Zero if
+    /// unknown.
+    pub modified: u64,
+    pub size: u64,
+}
+
+impl Track {
+    pub fn new(name: FileName, path: FilePath, format: Format, metadata: &Metadata) -> Self {
+        let modified = metadata.modified().ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |x| x.as_secs());
+
+        Track { name, path, format, tags: Tags::default(), modified, size: metadata.len() }
+    }
+
+    pub fn id(&self) -> TrackId {
+        track_id(&self.path)
+    }
+
+    /// Whether both were recorded from the same file, and it didn't change in
+    /// between, as far as its modification time and size tell.
+    fn same_file(&self, other: &Track) -> bool {
+        self.path == other.path && self.modified == other.modified && self.size == other.size
+            && self.modified != 0
+    }
+}
 
 #[derive(Debug)]
 pub struct MusicIndex {
     map: IndexMap,
     path: FilePath,
+    generation: u64,
 }
 
 impl MusicIndex {
-    pub fn key_json_array(&self) -> String {
-        iter_to_json_string(self.map.keys())
+    /// Changes every time the index is loaded. Nanoseconds since the epoch.
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
 
-    pub fn get(&self, item: &str) -> Option<FilePath> {
-        if let Some(value) = self.map.get(item) {
-            Some(self.path.clone() + value)
-        } else {
-            None
-        }
+    /// Tracks as a JSON array of `{"id","name","path","container","codec","mime"}`
+    /// objects, sorted by name.
+    pub fn list_json(&self) -> String {
+        let mut tracks: Vec<_> = self.map.iter().collect();
+        tracks.sort_by(|a, b| (&a.1.name, &a.1.path).cmp(&(&b.1.name, &b.1.path)));
+
+        let array = tracks.into_iter()
+            .map(|(id, track)| JsonValue::Object(track_fields(id, track)))
+            .collect();
+
+        JsonValue::Array(array).to_string()
     }
-}
 
-static mut STATIC_MUSIC_INDEX: Result<MusicIndex, String> = Err(String::new());
-static INIT_MUSIC: Once = Once::new();
+    /// Everything known about the track as a JSON object: what `list_json`
+    /// has, and its tags.
+    pub fn info_json(&self, id: &str) -> Option<String> {
+        let track = self.map.get(id)?;
 
-pub fn init_music_index(path: String) -> Result<(), String> {
-    unsafe {
-        INIT_MUSIC.call_once(move || {
-            if STATIC_MUSIC_INDEX.is_err() {
-                match load_index(path) {
-                    Ok(index) => STATIC_MUSIC_INDEX = Ok(index),
-                    Err(err) => STATIC_MUSIC_INDEX = Err(err.to_string()),
-                }
-            }
+        let mut fields = track_fields(id, track);
+        fields.push(("tags".into(), track.tags.to_json()));
+
+        Some(JsonValue::Object(fields).to_string())
+    }
+
+    /// Full path to the file of the track.
+    pub fn path_of(&self, track: &Track) -> FilePath {
+        self.path.clone() + &track.path
+    }
+
+    pub fn track(&self, id: &str) -> Option<&Track> {
+        self.map.get(id)
+    }
+
+    /// Id of a track called `name`. If there are several, the one whose path
+    /// comes first.
+    pub fn find_by_name(&self, name: &str) -> Option<&TrackId> {
+        self.map.iter()
+            .filter(|(_, track)| track.name == name)
+            .min_by(|a, b| a.1.path.cmp(&b.1.path))
+            .map(|(id, _)| id)
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Directory every track is in.
+    pub fn root(&self) -> &str {
+        &self.path
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = &TrackId> {
+        self.map.keys()
+    }
+
+    /// Copy of the index, without tracks at `removed` paths or anywhere under
+    /// them, and with `added` tracks. Paths are relative to the root.
+    pub fn updated(&self, removed: &[FilePath], added: IndexMap) -> MusicIndex {
+        let is_removed = |track: &Track| removed.iter().any(|x| {
+            track.path.strip_prefix(x.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
         });
 
-        match &STATIC_MUSIC_INDEX {
-            Ok(_) => Ok(()),
-            Err(err) => Err(err.into()),
+        let mut map: IndexMap = self.map.iter()
+            .filter(|(_, track)| !is_removed(track))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+
+        map.extend(added);
+
+        MusicIndex {
+            map,
+            path: self.path.clone(),
+            generation: new_generation(),
         }
     }
+
+    /// Whether both have the same tracks in the same files.
+    pub fn same_tracks(&self, other: &MusicIndex) -> bool {
+        self.map == other.map
+    }
 }
 
-pub fn get_music_index() -> &'static MusicIndex {
-    unsafe { STATIC_MUSIC_INDEX.as_ref().unwrap() }
+fn track_fields(id: &str, track: &Track) -> Vec<(String, JsonValue)> {
+    vec![
+        ("id".into(), id.into()),
+        ("name".into(), track.name.as_str().into()),
+        ("path".into(), track.path.as_str().into()),
+        ("container".into(), track.format.container.name().into()),
+        ("codec".into(), track.format.codec.name().into()),
+        ("mime".into(), track.format.mime_type().into()),
+    ]
 }
 
-// {"path":"...","entries":[{"...":"..."},...]}
-fn load_index(path: String) -> Result<MusicIndex, Error> {
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
+/// Index being served, and the file it was loaded from. The index is replaced
+/// as a whole, so requests that already took a snapshot keep using the old
+/// one until they finish.
+#[derive(Debug)]
+pub struct Library {
+    current: RwLock<Arc<MusicIndex>>,
+    index_path: String,
+}
 
-    let mut index_path = String::new();
-    let mut entries: HashMap<FileName, FilePath> = HashMap::new();
+impl Library {
+    pub fn load(index_path: String) -> Result<Self, Error> {
+        let index = load_index(index_path.clone())?;
 
-    let mut cursor_position = 0;
+        Ok(Library {
+            current: RwLock::new(Arc::new(index)),
+            index_path,
+        })
+    }
 
-    let mut buffer = [0; 1];
+    /// Index as it is right now. Stays the same for as long as it is held,
+    /// even if the library is reloaded meanwhile.
+    pub fn snapshot(&self) -> Arc<MusicIndex> {
+        self.current.read().unwrap_or_else(|x| x.into_inner()).clone()
+    }
 
-    let mut read_key = false;
-    let mut in_array = false;
-    let mut key = String::new();
-    let mut value = String::new();
+    /// Swaps in a new index, returning the old one.
+    pub fn replace(&self, index: MusicIndex) -> Arc<MusicIndex> {
+        let mut current = self.current.write().unwrap_or_else(|x| x.into_inner());
+        std::mem::replace(&mut *current, Arc::new(index))
+    }
 
-    loop {
-        let size = reader.read(&mut buffer)?;
+    /// Swaps in the index made by `update` from the current one, unless it
+    /// returns `None`. Nothing can replace the index in between. Returns the
+    /// old and the new index.
+    pub fn update<F>(&self, update: F) -> Option<(Arc<MusicIndex>, Arc<MusicIndex>)>
+    where
+        F: FnOnce(&MusicIndex) -> Option<MusicIndex>,
+    {
+        let mut current = self.current.write().unwrap_or_else(|x| x.into_inner());
+        let new = Arc::new(update(&current)?);
+
+        Some((std::mem::replace(&mut *current, new.clone()), new))
+    }
 
-        if size == 0 {
-            break;
-        }
+    /// Loads the index file again. On error, the old index is kept. Returns
+    /// the new number of tracks.
+    pub fn reload(&self) -> Result<usize, Error> {
+        let index = load_index(self.index_path.clone())?;
+        let count = index.track_count();
 
-        match buffer[0] as char {
-            '{' => {}
-            '}' => {}
-            ':' => {}
-            ',' => {}
-            ' ' => {}
-            '[' => {
-                in_array = true;
-                read_key = false;
-            }
-            ']' => {
-                in_array = false;
-            }
-            '\r' => {}
-            '\n' => {}
-            '\"' => {
-                let mut buffer = vec![];
-                reader.read_until(b'\"', &mut buffer)?;
-                buffer.pop(); // remove last "
-
-                let quoted = String::from_utf8(buffer).map_err(|err| {
-                    let message =
-                        format!("Invalid UTF-8 sequence at position {} ({})", cursor_position, err);
-                    Error::new(ErrorKind::InvalidInput, message)
-                })?;
-
-                if !read_key {
-                    key = quoted;
-                } else {
-                    value = quoted;
-                    if in_array {
-                        entries.insert(key.clone(), value.clone());
-                    }
-                }
-                read_key = !read_key;
-            }
-            _ => {
-                let message = format!("Invalid character '{}' at position {}", cursor_position, buffer[0] as char);
-                return Err(Error::new(ErrorKind::InvalidInput, message));
-            }
-        }
+        self.replace(index);
 
-        if &key == "path" && !value.is_empty() {
-            index_path = value.clone();
-        }
+        Ok(count)
+    }
 
-        cursor_position += 1;
+    pub fn index_path(&self) -> &str {
+        &self.index_path
     }
+}
 
-    if index_path.is_empty() || entries.is_empty() {
-        return Err(Error::new(
-            ErrorKind::InvalidInput,
-            "File is not a zest index",
-        ));
+// {"path":"...","entries":[{"id":"...","name":"...","path":"...","container":"...","codec":"...","tags":{...},"mtime":...,"size":...},...]}
+//
+// Entries of older index files may lack the id, which is then derived from the
+// path, or only have the path: {"<name>":"<path>"}. Their format is guessed
+// from the extension, and they are examined again on update, as are the ones
+// without tags.
+fn load_index(path: String) -> Result<MusicIndex, Error> {
+    let value = JsonValue::parse(&fs::read_to_string(path)?)?;
+
+    let not_an_index = || Error::new(ErrorKind::InvalidInput, "File is not a zest index");
+
+    let index_path = value.get("path").and_then(|x| x.as_str()).ok_or_else(not_an_index)?;
+    let entries = value.get("entries").and_then(|x| x.as_array()).ok_or_else(not_an_index)?;
+
+    let map: IndexMap = entries.iter()
+        .map(|x| make_entry(x).ok_or_else(not_an_index))
+        .collect::<Result<_, _>>()?;
+
+    if index_path.is_empty() || map.is_empty() {
+        return Err(not_an_index());
     }
 
     Ok(MusicIndex {
-        path: index_path,
-        map: entries,
+        path: index_path.to_owned(),
+        map,
+        generation: new_generation(),
     })
 }
 
+fn make_entry(entry: &JsonValue) -> Option<(TrackId, Track)> {
+    let number = |name: &str| entry.get(name).and_then(|x| x.as_u64()).unwrap_or(0);
+
+    let Some(path) = entry.get("path") else {
+        let (name, path) = entry.as_object()?.first()?;
+        let path = path.as_str()?.to_owned();
+        let track = Track {
+            name: name.clone(),
+            format: guess_format(&path),
+            tags: Tags::default(),
+            path,
+            modified: 0,
+            size: 0,
+        };
+
+        return Some((track.id(), track));
+    };
+
+    let path = path.as_str()?.to_owned();
+    let text = |name: &str| entry.get(name).and_then(|x| x.as_str());
+
+    let format = text("container").and_then(Container::from_name)
+        .zip(text("codec").and_then(Codec::from_name))
+        .map(|(container, codec)| Format { container, codec });
+
+    let tags = entry.get("tags").map(Tags::from_json);
+
+    let track = Track {
+        name: text("name")?.to_owned(),
+        format: format.unwrap_or_else(|| guess_format(&path)),
+        // Makes sure the file gets examined on update.
+        modified: if format.is_some() && tags.is_some() { number("mtime") } else { 0 },
+        tags: tags.unwrap_or_default(),
+        size: number("size"),
+        path,
+    };
+
+    let id = entry.get("id").and_then(|x| x.as_str()).map_or_else(|| track.id(), |x| x.to_owned());
+
+    Some((id, track))
+}
+
+fn new_generation() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_nanos() as u64)
+}
+
+/// Name of the track in a file, if it is a supported one.
+pub fn track_name(filename: &str) -> Option<&str> {
+    let (name, extension) = filename.rsplit_once('.')?;
+    Format::from_extension(extension).map(|_| name)
+}
+
+/// Format of a file by its extension, for files that weren't examined or
+/// couldn't be recognised.
+fn guess_format(path: &str) -> Format {
+    path.rsplit_once('.')
+        .and_then(|(_, extension)| Format::from_extension(extension))
+        .unwrap_or(Format::MP3)
+}
+
+/// Track in the file at `location`, which is at `path` relative to the root.
+/// `None` if its extension isn't one of the supported formats. Files that
+/// can't be recognised by their contents, like MP3s with junk at the start,
+/// are taken to be what their extension says.
+pub fn examine_file(location: &str, path: FilePath) -> Result<Option<Track>, Error> {
+    let Some(name) = track_name(path.rsplit('/').next().unwrap_or_default()) else {
+        return Ok(None);
+    };
+
+    let mut file = File::open(location)?;
+    let metadata = file.metadata()?;
+
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+
+    let format = match detect_format(&mut file)? {
+        Some(format) => format,
+        None => guess_format(&path),
+    };
+
+    let tags = read_tags(&mut file, format.container)?;
+
+    Ok(Some(Track { tags, ..Track::new(name.to_owned(), path, format, &metadata) }))
+}
+
+/// Id of the track at `path`, relative to the root: first 8 bytes of its
+/// SHA-1, in hex.
+pub fn track_id(path: &str) -> TrackId {
+    sha1(path).iter().take(8).map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Names shared by several tracks, with their paths, sorted.
+pub fn name_collisions(index: &IndexMap) -> Vec<(&FileName, Vec<&FilePath>)> {
+    let mut by_name: HashMap<&FileName, Vec<&FilePath>> = HashMap::new();
+
+    for track in index.values() {
+        by_name.entry(&track.name).or_default().push(&track.path);
+    }
+
+    let mut collisions: Vec<_> = by_name.into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .collect();
+
+    for (_, paths) in collisions.iter_mut() {
+        paths.sort();
+    }
+    collisions.sort();
+
+    collisions
+}
+
+/// Tells about tracks that look the same in the list, so they can be told
+/// apart by their paths.
+fn report_collisions(index: &IndexMap) {
+    for (name, paths) in name_collisions(index) {
+        eprintln!("Warning: {} tracks are called '{}':", paths.len(), name);
+
+        for path in paths {
+            eprintln!("    {}", path);
+        }
+    }
+}
+
 pub fn make_index(path: &FilePath, be_verbose: bool) -> Result<String, Error> {
-    make_index_file(recurse_music(&path, be_verbose)?, path)
+    let index = recurse_music(&path, &IndexMap::new(), be_verbose)?;
+    report_collisions(&index);
+
+    make_index_file(index, path)
+}
+
+/// How the tracks of an index changed, after the library was scanned again.
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexUpdate {
+    pub added: usize,
+    /// Files whose modification time or size is different.
+    pub changed: usize,
+    pub removed: usize,
+    pub unchanged: usize,
 }
 
-fn recurse_music(path: &String, be_verbose: bool) -> Result<HashMap<FileName, FilePath>, Error> {
-    fn internal(path: &String, initial_path_len: usize, be_verbose: bool)
-        -> Result<HashMap<FileName, FilePath>, Error> {
-        let mut dir = fs::read_dir(path.to_string())?;
-        let mut index: HashMap<FileName, FilePath> = HashMap::new();
+/// Scans the library of the index file again, and rewrites the file. Tracks
+/// whose files look the same are kept as they are.
+pub fn update_index(filename: &str, be_verbose: bool) -> Result<IndexUpdate, Error> {
+    let index = load_index(filename.to_owned())?;
+    let (map, update) = merge_scan(&index.map, recurse_music(&index.path, &index.map, be_verbose)?);
+
+    report_collisions(&map);
+
+    if map.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Directory does not contain any of the supported music files anymore"
+        ));
+    }
+
+    save_index(&MusicIndex { map, ..index }, filename)?;
 
-        while let Some(Ok(file)) = dir.next() {
-            let mut filepath: String = file.path().to_string_lossy().into();
-            let mut filename: String = file.file_name().to_string_lossy().into();
+    Ok(update)
+}
 
-            if cfg!(target_os = "windows") {
-                filepath = filepath.replace("\\", "/");
-                filename = filename.replace("\\", "/");
+/// Tracks that were `scanned`, keeping the ones from `old` whose files are
+/// the same.
+fn merge_scan(old: &IndexMap, scanned: IndexMap) -> (IndexMap, IndexUpdate) {
+    let mut update = IndexUpdate {
+        removed: old.keys().filter(|x| !scanned.contains_key(*x)).count(),
+        ..IndexUpdate::default()
+    };
+
+    let map = scanned.into_iter()
+        .map(|(id, track)| match old.get(&id) {
+            Some(old_track) if old_track.same_file(&track) => {
+                update.unchanged += 1;
+                (id, old_track.clone())
+            }
+            Some(_) => {
+                update.changed += 1;
+                (id, track)
             }
+            None => {
+                update.added += 1;
+                (id, track)
+            }
+        })
+        .collect();
 
-            if let Ok(true) = file.metadata().map(|x| x.is_file()) {
-                if filepath.ends_with(".mp3") {
-                    if be_verbose {
-                        println!("Adding {}...", filename);
-                    }
+    (map, update)
+}
 
-                    filepath.drain(..initial_path_len);
+/// Tracks under `path`. Files that look the same as in `known` are taken from
+/// there instead of being read again.
+fn recurse_music(path: &String, known: &IndexMap, be_verbose: bool) -> Result<IndexMap, Error> {
+    recurse_directory(path, path.len(), known, be_verbose)
+}
 
-                    index.insert(
-                        filename.trim_end_matches(".mp3").to_owned(),
-                        filepath.trim_start_matches(path.as_str()).to_owned(),
-                    );
-                }
-            } else {
-                if be_verbose {
-                    println!("Entering {:?}...", file.file_name());
+/// Tracks in `dir`, which is somewhere under `root`, with paths relative to
+/// `root`. Files that look the same as in `known` are taken from there.
+pub fn scan_directory(root: &str, dir: &str, known: &MusicIndex) -> Result<IndexMap, Error> {
+    recurse_directory(&dir.to_owned(), root.len(), &known.map, false)
+}
+
+fn recurse_directory(path: &String, initial_path_len: usize, known: &IndexMap, be_verbose: bool)
+    -> Result<IndexMap, Error> {
+    let mut dir = fs::read_dir(path.to_string())?;
+    let mut index: IndexMap = HashMap::new();
+
+    while let Some(Ok(file)) = dir.next() {
+        let mut filepath: String = file.path().to_string_lossy().into();
+        let mut filename: String = file.file_name().to_string_lossy().into();
+
+        if cfg!(target_os = "windows") {
+            filepath = filepath.replace("\\", "/");
+            filename = filename.replace("\\", "/");
+        }
+
+        if let Some(metadata) = file.metadata().ok().filter(|x| x.is_file()) {
+            if let Some(name) = track_name(&filename) {
+                let location = filepath.clone();
+                filepath.drain(..initial_path_len);
+
+                let relative = filepath.trim_start_matches(path.as_str()).to_owned();
+                let seen = Track::new(name.to_owned(), relative.clone(), guess_format(&relative), &metadata);
+
+                if let Some(track) = known.get(&seen.id()).filter(|x| x.same_file(&seen)) {
+                    index.insert(seen.id(), track.clone());
+                    continue;
                 }
 
-                index.extend(internal(&filepath, initial_path_len, be_verbose)?);
+                match examine_file(&location, relative) {
+                    Ok(Some(track)) => {
+                        if be_verbose {
+                            println!("Adding {}, {}...", filename, track.format.codec.name());
+                        }
+
+                        index.insert(track.id(), track);
+                    }
+                    Ok(None) if be_verbose => println!("Skipping {}, it is not in a supported format", filename),
+                    Ok(None) => {}
+                    Err(err) => eprintln!("Warning: skipping {}: {}", filename, err),
+                }
+            }
+        } else {
+            if be_verbose {
+                println!("Entering {:?}...", file.file_name());
             }
-        }
 
-        Ok(index)
+            index.extend(recurse_directory(&filepath, initial_path_len, known, be_verbose)?);
+        }
     }
 
-    internal(path, path.len(), be_verbose)
+    Ok(index)
 }
 
-fn make_index_file(index: HashMap<FileName, FilePath>, path: &FilePath) -> Result<String, Error> {
+fn make_index_file(index: IndexMap, path: &FilePath) -> Result<String, Error> {
     if index.is_empty() {
         return Err(Error::new(
             ErrorKind::InvalidInput,
@@ -206,18 +520,39 @@ fn make_index_file(index: HashMap<FileName, FilePath>, path: &FilePath) -> Resul
 
     let filename = format!("./zest-index-{}.json", i);
 
-    let file = File::create(&filename)?;
+    write_index(File::create(&filename)?, &index, path)?;
+
+    Ok(filename)
+}
+
+/// Writes the index back to `filename`. Readers of the file never see it
+/// half-written.
+pub fn save_index(index: &MusicIndex, filename: &str) -> Result<(), Error> {
+    let temporary = format!("{}.tmp", filename);
+
+    write_index(File::create(&temporary)?, &index.map, &index.path)?;
+    fs::rename(temporary, filename)
+}
+
+fn write_index(file: File, index: &IndexMap, path: &FilePath) -> Result<(), Error> {
     let mut writer = BufWriter::new(file);
 
-    write!(writer, "{{\"path\":\"{path}\",\"entries\":[")?;
+    write!(writer, "{{\"path\":{},\"entries\":[", quote(path))?;
 
     let mut files = index.iter().peekable();
-    while let Some((filename, filepath)) = files.next() {
-        writer.write_all(b"{\"")?;
-        writer.write_all(filename.to_owned().as_bytes())?;
-        writer.write_all(b"\":\"")?;
-        writer.write_all(filepath.as_bytes())?;
-        writer.write_all(b"\"}")?;
+    while let Some((id, track)) = files.next() {
+        let entry = JsonValue::Object(vec![
+            ("id".into(), id.as_str().into()),
+            ("name".into(), track.name.as_str().into()),
+            ("path".into(), track.path.as_str().into()),
+            ("container".into(), track.format.container.name().into()),
+            ("codec".into(), track.format.codec.name().into()),
+            ("tags".into(), track.tags.to_json()),
+            ("mtime".into(), track.modified.into()),
+            ("size".into(), track.size.into()),
+        ]);
+
+        write!(writer, "{}", entry)?;
         if &files.peek() != &None {
             writer.write_all(b",")?;
         }
@@ -225,7 +560,7 @@ fn make_index_file(index: HashMap<FileName, FilePath>, path: &FilePath) -> Resul
 
     write!(writer, "]}}")?;
 
-    Ok(filename)
+    writer.flush()
 }
 
 #[cfg(test)]
@@ -233,22 +568,38 @@ mod tests {
     use super::*;
     use std::fs;
 
+    fn track(path: &str, modified: u64, size: u64) -> Track {
+        let name = track_name(path.rsplit('/').next().unwrap()).unwrap();
+        Track {
+            name: name.to_string(),
+            path: path.to_string(),
+            format: guess_format(path),
+            tags: Tags::default(),
+            modified,
+            size,
+        }
+    }
+
+    fn index_of(tracks: &[Track]) -> IndexMap {
+        tracks.iter().map(|x| (x.id(), x.clone())).collect()
+    }
+
     #[test]
     fn test_make_and_load_index_file() {
-        let mut index = HashMap::new();
+        let index = index_of(&[track("/file1.mp3", 1700000000, 3), track("/file2.mp3", 0, 0)]);
         let path = "music";
 
-        index.insert("file1".to_string(), "/file1.mp3".to_string());
-        index.insert("file2".to_string(), "/file2.mp3".to_string());
-
         match make_index_file(index, &path.to_string()) {
             Ok(filename) => {
                 match load_index(filename.clone()) {
                     Ok(music_index) => {
                         let _ = fs::remove_file(filename);
 
+                        let id = track_id("/file1.mp3");
+
                         assert_eq!(music_index.map.len(), 2);
-                        assert_eq!(music_index.get("file1").unwrap(), "music/file1.mp3")
+                        assert_eq!(music_index.path_of(&music_index.map[&id]), "music/file1.mp3");
+                        assert_eq!(music_index.map[&id], track("/file1.mp3", 1700000000, 3));
                     }
                     Err(e) => panic!("Test failed: {:?}", e),
                 };
@@ -256,4 +607,195 @@ mod tests {
             Err(e) => panic!("Test failed: {:?}", e),
         }
     }
+
+    #[test]
+    fn test_index_file_awkward_names() {
+        let names = ["He said \"hi\"", "back\\slash", "Мелодия 🎵", "tab\there", "{\"a\":1}"];
+        let tracks: Vec<_> = names.iter().map(|x| track(&format!("/{}.mp3", x), 1, 2)).collect();
+
+        let index = MusicIndex {
+            map: index_of(&tracks),
+            path: "C:\\Music \"quoted\"".to_string(),
+            generation: 0,
+        };
+
+        let path = std::env::temp_dir().join(format!("zest-awkward-{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+
+        save_index(&index, &path).unwrap();
+        let loaded = load_index(path.clone()).unwrap();
+        let _ = fs::remove_file(path);
+
+        assert_eq!(loaded.root(), index.root());
+        assert!(loaded.same_tracks(&index));
+        assert_eq!(JsonValue::parse(&loaded.list_json()).unwrap().as_array().unwrap().len(), names.len());
+    }
+
+    #[test]
+    fn test_examine_unrecognised_file() {
+        let dir = std::env::temp_dir().join(format!("zest-examine-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let song = dir.join("junk.mp3");
+        let notes = dir.join("notes.txt");
+        fs::write(&song, [0xAA; 8192]).unwrap();
+        fs::write(&notes, b"not music").unwrap();
+
+        let track = examine_file(&song.to_string_lossy(), "/junk.mp3".to_string()).unwrap();
+        let other = examine_file(&notes.to_string_lossy(), "/notes.txt".to_string()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(track.map(|x| (x.name, x.format)), Some(("junk".to_string(), Format::MP3)));
+        assert!(other.is_none());
+    }
+
+    #[test]
+    fn test_scan_directory_reuses_known() {
+        let root = std::env::temp_dir().join(format!("zest-scan-{}", std::process::id()));
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::write(root.join("album/song.mp3"), [0xAA; 64]).unwrap();
+
+        let root = root.to_string_lossy().to_string();
+        let dir = format!("{}/album", root);
+        let empty = MusicIndex { map: IndexMap::new(), path: root.clone(), generation: 0 };
+
+        let mut map = scan_directory(&root, &dir, &empty).unwrap();
+        let id = track_id("/album/song.mp3");
+
+        // Tags that aren't in the file show that it wasn't read again.
+        map.get_mut(&id).unwrap().tags.title = Some("Known".into());
+
+        let known = MusicIndex { map, path: root.clone(), generation: 0 };
+        let rescanned = scan_directory(&root, &dir, &known).unwrap();
+        let _ = fs::remove_dir_all(&root);
+
+        assert_eq!(rescanned[&id].tags.title.as_deref(), Some("Known"));
+    }
+
+    #[test]
+    fn test_track_ids() {
+        let id = track_id("/album/01 - Intro.mp3");
+
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|x| x.is_ascii_hexdigit()));
+        assert_eq!(id, track_id("/album/01 - Intro.mp3"));
+        assert_ne!(id, track_id("/other album/01 - Intro.mp3"));
+    }
+
+    #[test]
+    fn test_same_names() {
+        let index = MusicIndex {
+            map: index_of(&[
+                track("/b/01 - Intro.mp3", 0, 0),
+                track("/a/01 - Intro.mp3", 0, 0),
+                track("/a/02 - Song.mp3", 0, 0),
+            ]),
+            path: "music".to_string(),
+            generation: 0,
+        };
+
+        assert_eq!(index.track_count(), 3);
+        let collisions = name_collisions(&index.map);
+
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(collisions[0].0, "01 - Intro");
+        assert_eq!(collisions[0].1, ["/a/01 - Intro.mp3", "/b/01 - Intro.mp3"]);
+        assert_eq!(index.find_by_name("01 - Intro"), Some(&track_id("/a/01 - Intro.mp3")));
+        assert_eq!(index.find_by_name("03 - Outro"), None);
+
+        let list = JsonValue::parse(&index.list_json()).unwrap();
+        let paths: Vec<_> = list.as_array().unwrap().iter()
+            .map(|x| x.get("path").and_then(|x| x.as_str()).unwrap())
+            .collect();
+
+        assert_eq!(paths, ["/a/01 - Intro.mp3", "/b/01 - Intro.mp3", "/a/02 - Song.mp3"]);
+        assert_eq!(list.as_array().unwrap()[0].get("id").and_then(|x| x.as_str()), Some(track_id(paths[0]).as_str()));
+    }
+
+    #[test]
+    fn test_index_updated() {
+        let index = MusicIndex {
+            map: index_of(&[
+                track("/album/intro.mp3", 0, 0),
+                track("/album/cd2/outro.mp3", 0, 0),
+                track("/albums/single.mp3", 0, 0),
+            ]),
+            path: "music".to_string(),
+            generation: 0,
+        };
+
+        let added = index_of(&[track("/new.mp3", 0, 0)]);
+        let updated = index.updated(&["/album".to_string()], added);
+
+        let mut names: Vec<_> = updated.ids().map(|x| updated.track(x).unwrap().name.as_str()).collect();
+        names.sort();
+
+        assert_eq!(names, ["new", "single"]);
+        assert!(!updated.same_tracks(&index));
+        assert!(index.updated(&[], HashMap::new()).same_tracks(&index));
+    }
+
+    #[test]
+    fn test_merge_scan() {
+        let old = index_of(&[
+            track("/same.mp3", 10, 1),
+            track("/touched.mp3", 10, 1),
+            track("/gone.mp3", 10, 1),
+        ]);
+
+        let scanned = index_of(&[
+            track("/same.mp3", 10, 1),
+            track("/touched.mp3", 20, 1),
+            track("/new.mp3", 20, 1),
+            // Same name as an existing track, in another folder.
+            track("/other/same.mp3", 20, 1),
+        ]);
+
+        let (map, update) = merge_scan(&old, scanned);
+
+        assert_eq!(update, IndexUpdate { added: 2, changed: 1, removed: 1, unchanged: 1 });
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[&track_id("/touched.mp3")].modified, 20);
+        assert!(map.contains_key(&track_id("/other/same.mp3")));
+        assert!(!map.contains_key(&track_id("/gone.mp3")));
+    }
+
+    #[test]
+    fn test_load_old_index_file() {
+        let path = std::env::temp_dir().join(format!("zest-old-index-{}.json", std::process::id()));
+
+        fs::write(&path, "{\"path\":\"music\",\"entries\":[{\"a\":\"/a.mp3\"},{\"b\":\"/b.mp3\"}]}").unwrap();
+        let index = load_index(path.to_string_lossy().to_string()).unwrap();
+        let _ = fs::remove_file(path);
+
+        assert_eq!(index.root(), "music");
+        assert_eq!(index.map[&track_id("/b.mp3")], track("/b.mp3", 0, 0));
+    }
+
+    #[test]
+    fn test_library_reload_keeps_snapshots() {
+        let path = std::env::temp_dir().join(format!("zest-library-{}.json", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+
+        let (old, new) = (track_id("/old.mp3"), track_id("/new.mp3"));
+
+        fs::write(&path, "{\"path\":\"music\",\"entries\":[{\"old\":\"/old.mp3\"}]}").unwrap();
+
+        let library = Library::load(path.clone()).unwrap();
+        let before = library.snapshot();
+
+        fs::write(&path, "{\"path\":\"music\",\"entries\":[{\"new\":\"/new.mp3\"}]}").unwrap();
+        assert_eq!(library.reload().unwrap(), 1);
+
+        assert!(before.track(&old).is_some());
+        assert!(library.snapshot().track(&old).is_none());
+        assert!(library.snapshot().track(&new).is_some());
+
+        // Broken file leaves the library as it was.
+        fs::write(&path, "not an index").unwrap();
+        assert!(library.reload().is_err());
+        assert!(library.snapshot().track(&new).is_some());
+
+        let _ = fs::remove_file(path);
+    }
 }
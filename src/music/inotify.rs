@@ -0,0 +1,169 @@
+use std::ffi::CString;
+use std::io::{Error, ErrorKind};
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use crate::common::util::check_os_result;
+
+/// What directories are watched for: entries appearing, disappearing, or
+/// being written to.
+const WATCH_MASK: u32 = libc::IN_CREATE | libc::IN_CLOSE_WRITE | libc::IN_DELETE
+    | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ONLYDIR;
+
+/// Size of `inotify_event`, without the name following it.
+const EVENT_HEADER_SIZE: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InotifyEvent {
+    /// Watch descriptor of the directory, or -1 on queue overflow.
+    pub wd: i32,
+    pub mask: u32,
+    /// Entry of the directory the event is about, if any.
+    pub name: Option<String>,
+}
+
+impl InotifyEvent {
+    pub fn is_dir(&self) -> bool {
+        self.mask & libc::IN_ISDIR != 0
+    }
+
+    /// Some events were dropped, since they weren't read fast enough.
+    pub fn is_overflow(&self) -> bool {
+        self.mask & libc::IN_Q_OVERFLOW != 0
+    }
+
+    /// Watch was removed, by `remove` or because the directory is gone.
+    pub fn is_ignored(&self) -> bool {
+        self.mask & libc::IN_IGNORED != 0
+    }
+
+    /// Entry has left the directory, and the path no longer points to it.
+    pub fn is_gone(&self) -> bool {
+        self.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0
+    }
+}
+
+/// Thin wrapper around an inotify instance, watching directories. Watching
+/// is not recursive, every subdirectory has to be added by itself.
+#[derive(Debug)]
+pub struct Inotify {
+    fd: RawFd,
+}
+
+impl Inotify {
+    pub fn new() -> Result<Self, Error> {
+        let fd = check_os_result(unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) })?;
+
+        Ok(Inotify { fd })
+    }
+
+    /// Returns the watch descriptor events for `path` will come with.
+    pub fn add(&self, path: &str) -> Result<i32, Error> {
+        let path = CString::new(path).map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+
+        check_os_result(unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), WATCH_MASK) })
+    }
+
+    pub fn remove(&self, wd: i32) -> Result<(), Error> {
+        check_os_result(unsafe { libc::inotify_rm_watch(self.fd, wd) })?;
+
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for events, and appends them to `events`.
+    /// Interruptions by signals are not errors.
+    pub fn wait(&self, events: &mut Vec<InotifyEvent>, timeout: Duration) -> Result<(), Error> {
+        let mut pollfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+        if let Err(err) = check_os_result(unsafe { libc::poll(&mut pollfd, 1, timeout) }) {
+            return match err.raw_os_error() {
+                Some(libc::EINTR) => Ok(()),
+                _ => Err(err),
+            };
+        }
+
+        let mut buffer = [0u8; 64 * 1024];
+
+        loop {
+            let count = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+
+            if count < 0 {
+                let err = Error::last_os_error();
+
+                return match err.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::Interrupted => Ok(()),
+                    _ => Err(err),
+                };
+            }
+
+            events.extend(parse_events(&buffer[..count as usize]));
+        }
+    }
+}
+
+impl Drop for Inotify {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Reads `inotify_event` structs, each followed by a NUL-padded name.
+fn parse_events(mut bytes: &[u8]) -> Vec<InotifyEvent> {
+    let mut events = Vec::new();
+
+    while bytes.len() >= EVENT_HEADER_SIZE {
+        let field = |i: usize| u32::from_ne_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+
+        let (wd, mask, length) = (field(0) as i32, field(1), field(3) as usize);
+        let end = (EVENT_HEADER_SIZE + length).min(bytes.len());
+
+        let name = bytes[EVENT_HEADER_SIZE..end].split(|x| *x == 0).next()
+            .filter(|x| !x.is_empty())
+            .map(|x| String::from_utf8_lossy(x).into_owned());
+
+        events.push(InotifyEvent { wd, mask, name });
+        bytes = &bytes[end..];
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_inotify_events() {
+        let dir = std::env::temp_dir().join(format!("zest-inotify-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+
+        let inotify = Inotify::new().unwrap();
+        let wd = inotify.add(&dir.to_string_lossy()).unwrap();
+
+        let mut events = Vec::new();
+        inotify.wait(&mut events, Duration::ZERO).unwrap();
+        assert!(events.is_empty());
+
+        fs::write(dir.join("a.mp3"), b"").unwrap();
+        fs::create_dir(dir.join("album")).unwrap();
+        fs::remove_file(dir.join("a.mp3")).unwrap();
+
+        while events.len() < 4 {
+            inotify.wait(&mut events, Duration::from_secs(1)).unwrap();
+        }
+
+        let names: Vec<_> = events.iter().map(|x| (x.wd, x.name.as_deref(), x.is_dir(), x.is_gone())).collect();
+
+        assert_eq!(names, [
+            (wd, Some("a.mp3"), false, false), // Created
+            (wd, Some("a.mp3"), false, false), // Closed after writing
+            (wd, Some("album"), true, false),
+            (wd, Some("a.mp3"), false, true),
+        ]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
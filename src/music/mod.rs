@@ -1,2 +1,11 @@
 pub mod endpoint;
+pub mod format;
+pub mod id3;
 pub mod index;
+#[cfg(target_os = "linux")]
+pub mod inotify;
+pub mod mp4;
+pub mod tags;
+pub mod vorbis;
+#[cfg(target_os = "linux")]
+pub mod watcher;
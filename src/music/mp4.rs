@@ -0,0 +1,157 @@
+use std::io::{Error, SeekFrom};
+
+use crate::music::format::{atoms, find_atom, read_at};
+use crate::music::id3::id3_genre;
+use crate::music::tags::{Field, ReadSeek, TagReader, Tags};
+
+/// Items larger than this are not text, like cover art, and are skipped.
+const MAX_ITEM_SIZE: u64 = 64 * 1024;
+
+/// Type of `data` atoms with UTF-8 text.
+const UTF8: u32 = 1;
+/// Type of `data` atoms with UTF-16 text.
+const UTF16: u32 = 2;
+
+/// Reads the iTunes-style `ilst` metadata of MP4 and M4A files.
+pub struct Mp4Reader;
+
+impl TagReader for Mp4Reader {
+    fn read_tags(&self, reader: &mut dyn ReadSeek) -> Result<Tags, Error> {
+        let mut tags = Tags::default();
+
+        let length = reader.seek(SeekFrom::End(0))?;
+
+        let mut range = Some(0..length);
+
+        for path in [b"moov", b"udta", b"meta"] {
+            range = match range {
+                Some(x) => find_atom(reader, x, path)?,
+                None => return Ok(tags),
+            };
+        }
+
+        let Some(mut meta) = range else {
+            return Ok(tags);
+        };
+
+        // Version and flags come first, except in files written the QuickTime
+        // way, where atoms start right away.
+        if read_at(reader, meta.start + 4, 4)? != b"hdlr" {
+            meta.start += 4;
+        }
+
+        let Some(ilst) = find_atom(reader, meta, b"ilst")? else {
+            return Ok(tags);
+        };
+
+        for (name, item) in atoms(reader, ilst)? {
+            let Some(data) = find_atom(reader, item, b"data")? else {
+                continue;
+            };
+
+            if data.end - data.start > MAX_ITEM_SIZE {
+                continue;
+            }
+
+            let data = read_at(reader, data.start, data.end - data.start)?;
+
+            // Type, then locale, then the value.
+            let (Some(kind), Some(value)) = (data.get(..4), data.get(8..)) else {
+                continue;
+            };
+
+            let kind = u32::from_be_bytes(kind.try_into().unwrap()) & 0xFFFFFF;
+
+            read_item(&name, kind, value, &mut tags);
+        }
+
+        Ok(tags)
+    }
+}
+
+/// Sets the field that the item called `name` is about.
+fn read_item(name: &[u8; 4], kind: u32, value: &[u8], tags: &mut Tags) {
+    let field = match name {
+        b"\xa9nam" => Field::Title,
+        b"\xa9ART" => Field::Artist,
+        b"\xa9alb" => Field::Album,
+        b"aART" => Field::AlbumArtist,
+        b"\xa9day" => Field::Year,
+        b"\xa9gen" => Field::Genre,
+        b"\xa9cmt" => Field::Comment,
+        // Numbers out of the total, as 16 bits after two bytes of padding.
+        b"trkn" | b"disk" => {
+            let field = if name == b"trkn" { Field::TrackNumber } else { Field::DiscNumber };
+
+            if let Some(number) = value.get(2..4).map(|x| u16::from_be_bytes([x[0], x[1]])) {
+                if number > 0 {
+                    tags.set(field, &number.to_string());
+                }
+            }
+
+            return;
+        }
+        // Genres of ID3v1, counted from one.
+        b"gnre" => {
+            let number = value.get(..2).map(|x| u16::from_be_bytes([x[0], x[1]]) as usize);
+
+            if let Some(genre) = number.and_then(|x| id3_genre(x.checked_sub(1)?)) {
+                tags.set(Field::Genre, genre);
+            }
+
+            return;
+        }
+        _ => return,
+    };
+
+    let text = match kind {
+        UTF8 => String::from_utf8_lossy(value).into_owned(),
+        UTF16 => {
+            let units: Vec<u16> = value.chunks_exact(2).map(|x| u16::from_be_bytes([x[0], x[1]])).collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => return,
+    };
+
+    tags.set(field, &text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn atom(name: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        [&(contents.len() as u32 + 8).to_be_bytes()[..], name, contents].concat()
+    }
+
+    fn item(name: &[u8; 4], kind: u32, value: &[u8]) -> Vec<u8> {
+        atom(name, &atom(b"data", &[&kind.to_be_bytes()[..], &[0; 4], value].concat()))
+    }
+
+    #[test]
+    fn test_mp4_items() {
+        let ilst = [
+            item(b"\xa9nam", UTF8, "Песня".as_bytes()),
+            item(b"\xa9ART", UTF16, &[0, b'A', 0, b'B']),
+            item(b"trkn", 0, &[0, 0, 0, 4, 0, 12, 0, 0]),
+            item(b"disk", 0, &[0, 0, 0, 0, 0, 2]),
+            item(b"gnre", 0, &[0, 18]),
+            item(b"\xa9day", UTF8, b"2011-03-04T00:00:00Z"),
+            item(b"covr", 13, &[0xFF; 16]),
+        ].concat();
+
+        let meta = [&[0; 4][..], &atom(b"hdlr", &[0; 25]), &atom(b"ilst", &ilst)].concat();
+        let moov = atom(b"moov", &atom(b"udta", &atom(b"meta", &meta)));
+        let file = [atom(b"ftyp", b"M4A \0\0\0\0"), moov].concat();
+
+        let tags = Mp4Reader.read_tags(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Песня"));
+        assert_eq!(tags.artist.as_deref(), Some("AB"));
+        assert_eq!(tags.track_number, Some(4));
+        assert_eq!(tags.disc_number, None);
+        assert_eq!(tags.genre.as_deref(), Some("Rock"));
+        assert_eq!(tags.year, Some(2011));
+    }
+}
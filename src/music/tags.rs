@@ -0,0 +1,194 @@
+use std::io::{Error, Read, Seek};
+
+use crate::common::json::JsonValue;
+use crate::music::format::Container;
+use crate::music::id3::Id3Reader;
+use crate::music::mp4::Mp4Reader;
+use crate::music::vorbis::{FlacReader, OggReader};
+
+/// Anything tags can be read from, like files.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Reads tags of one kind of files, and maps them onto `Tags`.
+pub trait TagReader {
+    /// Tags of the file. Empty if it has none this reader understands.
+    fn read_tags(&self, reader: &mut dyn ReadSeek) -> Result<Tags, Error>;
+}
+
+/// Reader for the tags files in `container` have, if they have any.
+pub fn tag_reader(container: Container) -> Option<&'static dyn TagReader> {
+    match container {
+        Container::Mpeg | Container::Adts => Some(&Id3Reader),
+        Container::Flac => Some(&FlacReader),
+        Container::Ogg => Some(&OggReader),
+        Container::Mp4 => Some(&Mp4Reader),
+        Container::Wav => None,
+    }
+}
+
+/// Tags of a file in `container`, empty if there are none to read.
+pub fn read_tags<R: Read + Seek>(reader: &mut R, container: Container) -> Result<Tags, Error> {
+    match tag_reader(container) {
+        Some(tag_reader) => tag_reader.read_tags(reader),
+        None => Ok(Tags::default()),
+    }
+}
+
+/// Fields of `Tags`, for readers that map their own names onto them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    TrackNumber,
+    DiscNumber,
+    Year,
+    Genre,
+    Comment,
+}
+
+/// What the tags of a file tell about the track. Fields that none of the
+/// tags had are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Tags {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<u32>,
+    pub disc_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl Tags {
+    /// Sets the field from text as it is in the tag, unless it is already set
+    /// or the text is blank. Numbers like `3/12` and dates like `2003-05-01`
+    /// are taken up to where the number ends.
+    pub fn set(&mut self, field: Field, value: &str) {
+        let value = value.trim_matches(|x: char| x.is_whitespace() || x == '\0');
+
+        if value.is_empty() {
+            return;
+        }
+
+        let number = || {
+            let digits = value.find(|x: char| !x.is_ascii_digit()).unwrap_or(value.len());
+            value[..digits].parse::<u32>().ok()
+        };
+
+        let text = || Some(value.to_owned());
+
+        match field {
+            Field::Title => self.title = self.title.take().or_else(text),
+            Field::Artist => self.artist = self.artist.take().or_else(text),
+            Field::Album => self.album = self.album.take().or_else(text),
+            Field::AlbumArtist => self.album_artist = self.album_artist.take().or_else(text),
+            Field::TrackNumber => self.track_number = self.track_number.or_else(number),
+            Field::DiscNumber => self.disc_number = self.disc_number.or_else(number),
+            Field::Year => self.year = self.year.or_else(number),
+            Field::Genre => self.genre = self.genre.take().or_else(text),
+            Field::Comment => self.comment = self.comment.take().or_else(text),
+        }
+    }
+
+    /// Fills the fields that are `None` with ones from `other`.
+    pub fn or(self, other: Tags) -> Tags {
+        Tags {
+            title: self.title.or(other.title),
+            artist: self.artist.or(other.artist),
+            album: self.album.or(other.album),
+            album_artist: self.album_artist.or(other.album_artist),
+            track_number: self.track_number.or(other.track_number),
+            disc_number: self.disc_number.or(other.disc_number),
+            year: self.year.or(other.year),
+            genre: self.genre.or(other.genre),
+            comment: self.comment.or(other.comment),
+        }
+    }
+
+    /// Object with the fields that are set.
+    pub fn to_json(&self) -> JsonValue {
+        let texts = [
+            ("title", &self.title),
+            ("artist", &self.artist),
+            ("album", &self.album),
+            ("album_artist", &self.album_artist),
+            ("genre", &self.genre),
+            ("comment", &self.comment),
+        ];
+
+        let numbers = [
+            ("track_number", self.track_number),
+            ("disc_number", self.disc_number),
+            ("year", self.year),
+        ];
+
+        let texts = texts.into_iter()
+            .filter_map(|(key, value)| Some((key.to_owned(), value.as_deref()?.into())));
+
+        let numbers = numbers.into_iter()
+            .filter_map(|(key, value)| Some((key.to_owned(), (value? as u64).into())));
+
+        JsonValue::Object(texts.chain(numbers).collect())
+    }
+
+    pub fn from_json(value: &JsonValue) -> Tags {
+        let text = |key: &str| value.get(key).and_then(|x| x.as_str()).map(|x| x.to_owned());
+        let number = |key: &str| value.get(key).and_then(|x| x.as_u64()).and_then(|x| u32::try_from(x).ok());
+
+        Tags {
+            title: text("title"),
+            artist: text("artist"),
+            album: text("album"),
+            album_artist: text("album_artist"),
+            track_number: number("track_number"),
+            disc_number: number("disc_number"),
+            year: number("year"),
+            genre: text("genre"),
+            comment: text("comment"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tags_set() {
+        let mut tags = Tags::default();
+
+        tags.set(Field::Title, "  First\0");
+        tags.set(Field::Title, "Second");
+        tags.set(Field::Artist, " ");
+        tags.set(Field::TrackNumber, "3/12");
+        tags.set(Field::Year, "2003-05-01T10:00");
+        tags.set(Field::DiscNumber, "side A");
+
+        assert_eq!(tags.title.as_deref(), Some("First"));
+        assert_eq!(tags.artist, None);
+        assert_eq!(tags.track_number, Some(3));
+        assert_eq!(tags.year, Some(2003));
+        assert_eq!(tags.disc_number, None);
+    }
+
+    #[test]
+    fn test_tags_json() {
+        let tags = Tags {
+            title: Some("Мелодия \"1\"".into()),
+            track_number: Some(7),
+            ..Tags::default()
+        };
+
+        let json = tags.to_json().to_string();
+
+        assert_eq!(json, "{\"title\":\"Мелодия \\\"1\\\"\",\"track_number\":7}");
+        assert_eq!(Tags::from_json(&JsonValue::parse(&json).unwrap()), tags);
+        assert_eq!(Tags::default().to_json().to_string(), "{}");
+    }
+}
@@ -0,0 +1,254 @@
+use std::io::Error;
+
+use crate::music::format::{id3_tag_size, read_at};
+use crate::music::tags::{Field, ReadSeek, TagReader, Tags};
+
+/// Type of the FLAC metadata block with Vorbis comments.
+const VORBIS_COMMENT_BLOCK: u8 = 4;
+/// Metadata blocks are skipped this many times at most.
+const MAX_BLOCKS: usize = 128;
+/// Comment headers of Ogg streams are read up to this size. Embedded pictures
+/// can make them large, and text usually comes before those.
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+
+/// Reads the `VORBIS_COMMENT` metadata block of FLAC files.
+pub struct FlacReader;
+
+/// Reads the comment header of Ogg Vorbis, Opus and FLAC streams.
+pub struct OggReader;
+
+impl TagReader for FlacReader {
+    fn read_tags(&self, reader: &mut dyn ReadSeek) -> Result<Tags, Error> {
+        let mut tags = Tags::default();
+
+        // Some taggers put ID3 tags before the stream anyway.
+        let header = read_at(reader, 0, 10)?;
+        let mut offset = if header.starts_with(b"ID3") { id3_tag_size(&header).unwrap_or(0) } else { 0 };
+
+        if read_at(reader, offset, 4)? != b"fLaC" {
+            return Ok(tags);
+        }
+
+        offset += 4;
+
+        for _ in 0..MAX_BLOCKS {
+            let header = read_at(reader, offset, 4)?;
+
+            if header.len() < 4 {
+                break;
+            }
+
+            let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+
+            if header[0] & 0x7F == VORBIS_COMMENT_BLOCK {
+                read_comments(&read_at(reader, offset + 4, length)?, &mut tags);
+                break;
+            }
+
+            // Last block before the audio.
+            if header[0] & 0x80 != 0 {
+                break;
+            }
+
+            offset += 4 + length;
+        }
+
+        Ok(tags)
+    }
+}
+
+impl TagReader for OggReader {
+    fn read_tags(&self, reader: &mut dyn ReadSeek) -> Result<Tags, Error> {
+        let mut tags = Tags::default();
+
+        let packets = first_packets(reader)?;
+
+        let [first, second, ..] = &packets[..] else {
+            return Ok(tags);
+        };
+
+        let comments = if first.starts_with(b"\x01vorbis") {
+            second.strip_prefix(b"\x03vorbis")
+        } else if first.starts_with(b"OpusHead") {
+            second.strip_prefix(b"OpusTags")
+        } else if first.starts_with(b"\x7fFLAC") {
+            // Metadata blocks follow in packets of their own, comments first.
+            second.get(4..).filter(|_| second[0] & 0x7F == VORBIS_COMMENT_BLOCK)
+        } else {
+            None
+        };
+
+        if let Some(comments) = comments {
+            read_comments(comments, &mut tags);
+        }
+
+        Ok(tags)
+    }
+}
+
+/// Identification and comment headers, the first two packets of the first
+/// stream. The second may be cut short if it is too large.
+fn first_packets(reader: &mut dyn ReadSeek) -> Result<Vec<Vec<u8>>, Error> {
+    let mut packets = vec![Vec::new()];
+    let mut offset = 0;
+    let mut serial = None;
+
+    // Packets end with a segment shorter than 255 bytes, and can go on over
+    // several pages.
+    while packets.len() < 3 && packets.last().is_some_and(|x| x.len() < MAX_PACKET_SIZE) {
+        let header = read_at(reader, offset, 27)?;
+
+        if header.len() < 27 || &header[..4] != b"OggS" {
+            break;
+        }
+
+        let segments = read_at(reader, offset + 27, header[26] as u64)?;
+        let length: u64 = segments.iter().map(|x| *x as u64).sum();
+        let data = read_at(reader, offset + 27 + segments.len() as u64, length)?;
+
+        offset += 27 + segments.len() as u64 + length;
+
+        // Pages of other streams of the file are in between.
+        if *serial.get_or_insert_with(|| header[14..18].to_vec()) != header[14..18] {
+            continue;
+        }
+
+        let mut data = &data[..];
+
+        for size in segments {
+            let (segment, rest) = data.split_at((size as usize).min(data.len()));
+
+            packets.last_mut().unwrap().extend_from_slice(segment);
+            data = rest;
+
+            if size < 255 {
+                packets.push(Vec::new());
+            }
+        }
+    }
+
+    Ok(packets)
+}
+
+/// Reads a Vorbis comment block: vendor string, then `KEY=value` fields, each
+/// after its length. Stops where the block is cut short.
+pub fn read_comments(bytes: &[u8], tags: &mut Tags) {
+    let length = |at: usize| bytes.get(at..at + 4).map(|x| u32::from_le_bytes(x.try_into().unwrap()) as usize);
+
+    let Some(vendor) = length(0) else {
+        return;
+    };
+
+    let mut at = 4usize.saturating_add(vendor);
+
+    let Some(count) = length(at) else {
+        return;
+    };
+
+    at += 4;
+
+    for _ in 0..count {
+        let Some(size) = length(at) else {
+            return;
+        };
+
+        let Some(comment) = bytes.get(at + 4..).and_then(|x| x.get(..size)) else {
+            return;
+        };
+
+        at += 4 + size;
+
+        let comment = String::from_utf8_lossy(comment);
+
+        if let Some((name, value)) = comment.split_once('=') {
+            if let Some(field) = comment_field(name) {
+                tags.set(field, value);
+            }
+        }
+    }
+}
+
+/// Field that a comment is about. Names are case-insensitive, and there
+/// are a few common ones besides the ones recommended.
+fn comment_field(name: &str) -> Option<Field> {
+    let field = match name.to_ascii_uppercase().as_str() {
+        "TITLE" => Field::Title,
+        "ARTIST" => Field::Artist,
+        "ALBUM" => Field::Album,
+        "ALBUMARTIST" | "ALBUM ARTIST" | "ALBUM_ARTIST" => Field::AlbumArtist,
+        "TRACKNUMBER" => Field::TrackNumber,
+        "DISCNUMBER" => Field::DiscNumber,
+        "DATE" | "YEAR" => Field::Year,
+        "GENRE" => Field::Genre,
+        "COMMENT" | "DESCRIPTION" => Field::Comment,
+        _ => return None,
+    };
+
+    Some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn comments(fields: &[&str]) -> Vec<u8> {
+        let mut bytes = [&4u32.to_le_bytes()[..], b"zest", &(fields.len() as u32).to_le_bytes()].concat();
+
+        for field in fields {
+            bytes.extend((field.len() as u32).to_le_bytes());
+            bytes.extend(field.as_bytes());
+        }
+
+        bytes
+    }
+
+    /// Page of stream `serial`, with `data` cut into `segments`.
+    fn page(serial: u8, segments: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend([0; 8]); // Granule position.
+        page.extend([serial, 0, 0, 0]);
+        page.extend([0; 8]); // Sequence number and checksum.
+        page.push(segments.len() as u8);
+        page.extend(segments);
+        page.extend(data);
+        page
+    }
+
+    #[test]
+    fn test_flac_comments() {
+        let block = comments(&["title=Song", "ARTIST=Someone", "TrackNumber=02/10", "DATE=1999-01-01", "broken"]);
+
+        let mut file = b"fLaC".to_vec();
+        file.extend([0x00, 0, 0, 34]); // Stream info.
+        file.extend([0; 34]);
+        file.extend([0x84, 0, 0, block.len() as u8]);
+        file.extend(&block);
+
+        let tags = FlacReader.read_tags(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.title.as_deref(), Some("Song"));
+        assert_eq!(tags.artist.as_deref(), Some("Someone"));
+        assert_eq!(tags.track_number, Some(2));
+        assert_eq!(tags.year, Some(1999));
+    }
+
+    #[test]
+    fn test_ogg_comments_over_pages() {
+        let packet = [&b"OpusTags"[..], &comments(&["ALBUM=Record", &format!("COMMENT={}", "x".repeat(300))])].concat();
+        let (start, rest) = packet.split_at(255);
+
+        let file = [
+            page(1, &[19], b"OpusHead\x01\x02\x00\x00\x80\xbb\x00\x00\x00\x00\x00"),
+            // Another stream in between.
+            page(2, &[8], b"OpusHead"),
+            page(1, &[255], start),
+            page(1, &[rest.len() as u8], rest),
+        ].concat();
+
+        let tags = OggReader.read_tags(&mut Cursor::new(file)).unwrap();
+
+        assert_eq!(tags.album.as_deref(), Some("Record"));
+        assert_eq!(tags.comment.map(|x| x.len()), Some(300));
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Error;
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::{Duration, Instant};
+
+use crate::common::logger::{Log, Logger, Verbosity};
+use crate::common::util::{Am, FilePath};
+use crate::music::index::{examine_file, save_index, scan_directory, IndexMap, MusicIndex};
+use crate::music::inotify::{Inotify, InotifyEvent};
+use crate::server::events::{event_bus, ServerEvent};
+use crate::server::signals::shutdown_requested;
+use crate::server::state::ServerState;
+use crate::{log, log_geq};
+
+/// Changes are applied once the library has been quiet for this long, so
+/// copying an album results in one update.
+const DEBOUNCE_DELAY: Duration = Duration::from_secs(1);
+/// Changes that keep coming are still applied this often.
+const MAX_DELAY: Duration = Duration::from_secs(10);
+/// How often the watcher checks whether shutdown was requested.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keeps the index in sync with the library directory, and saves it to the
+/// index file after every change.
+struct Watcher {
+    inotify: Inotify,
+    /// Watched directories, by watch descriptor.
+    directories: HashMap<i32, FilePath>,
+    root: FilePath,
+    /// Paths that changed since the last update.
+    pending: HashSet<FilePath>,
+    /// Events were lost, so the whole library has to be scanned again.
+    rescan: bool,
+    state: Arc<ServerState>,
+    logger: Am<Logger>,
+}
+
+/// Starts watching the library in the background, until shutdown.
+pub fn spawn_watcher(state: Arc<ServerState>, logger: Am<Logger>) -> Result<(), Error> {
+    let root = state.library.snapshot().root().to_owned();
+
+    let mut watcher = Watcher {
+        inotify: Inotify::new()?,
+        directories: HashMap::new(),
+        root: root.clone(),
+        pending: HashSet::new(),
+        rescan: false,
+        state,
+        logger,
+    };
+
+    watcher.watch_tree(&root);
+
+    log!(watcher.logger, "Watching {} directories under '{}'", watcher.directories.len(), root);
+
+    Builder::new()
+        .name("watcher".into())
+        .spawn(move || watcher.run())?;
+
+    Ok(())
+}
+
+impl Watcher {
+    fn run(mut self) {
+        let mut events = Vec::new();
+        let mut first_change: Option<Instant> = None;
+        let mut last_change = Instant::now();
+
+        while !shutdown_requested() {
+            events.clear();
+
+            if let Err(err) = self.inotify.wait(&mut events, POLL_INTERVAL) {
+                log!(self.logger, "*** Stopped watching the library: {}", err);
+                return;
+            }
+
+            let now = Instant::now();
+
+            for event in events.drain(..) {
+                if self.record(event) {
+                    first_change.get_or_insert(now);
+                    last_change = now;
+                }
+            }
+
+            let Some(first) = first_change else {
+                continue;
+            };
+
+            if now - last_change >= DEBOUNCE_DELAY || now - first >= MAX_DELAY {
+                self.apply();
+                first_change = None;
+            }
+        }
+    }
+
+    /// Adds watches for `dir` and every directory under it.
+    fn watch_tree(&mut self, dir: &str) {
+        match self.inotify.add(dir) {
+            Ok(wd) => {
+                self.directories.insert(wd, dir.to_owned());
+            }
+            Err(err) => {
+                log!(self.logger, "*** Could not watch '{}': {}", dir, err);
+                return;
+            }
+        }
+
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|x| x.is_dir()) {
+                self.watch_tree(&format!("{}/{}", dir, entry.file_name().to_string_lossy()));
+            }
+        }
+    }
+
+    /// Removes watches for `dir` and every directory under it.
+    fn forget_tree(&mut self, dir: &str) {
+        let prefix = format!("{}/", dir);
+
+        self.directories.retain(|wd, path| {
+            if path != dir && !path.starts_with(&prefix) {
+                return true;
+            }
+
+            // Deleted directories lose their watches by themselves.
+            let _ = self.inotify.remove(*wd);
+            false
+        });
+    }
+
+    /// Remembers the path the event is about. Returns whether the library
+    /// may have changed.
+    fn record(&mut self, event: InotifyEvent) -> bool {
+        if event.is_overflow() {
+            self.rescan = true;
+            return true;
+        }
+
+        if event.is_ignored() {
+            self.directories.remove(&event.wd);
+            return false;
+        }
+
+        let (Some(dir), Some(name)) = (self.directories.get(&event.wd), &event.name) else {
+            return false;
+        };
+
+        let path = format!("{}/{}", dir, name);
+
+        log_geq!(self.logger, Verbosity::Debug, "Library event {:#x} on '{}'", event.mask, path);
+
+        if event.is_dir() && event.is_gone() {
+            self.forget_tree(&path);
+        }
+
+        self.pending.insert(path);
+
+        true
+    }
+
+    /// Updates the index with paths that changed, as they are now.
+    fn apply(&mut self) {
+        let mut removed = Vec::new();
+        let mut added = IndexMap::new();
+
+        // Tracks that didn't change are not read again, which matters when
+        // the whole library is scanned.
+        let known = self.state.library.snapshot();
+
+        if std::mem::take(&mut self.rescan) {
+            log!(self.logger, "*** Missed some changes to the library, scanning it again");
+
+            self.pending.clear();
+            self.forget_tree(&self.root.clone());
+            self.watch_tree(&self.root.clone());
+
+            // Everything is relative to the root.
+            removed.push(String::new());
+            self.pending.insert(self.root.clone());
+        }
+
+        for path in std::mem::take(&mut self.pending) {
+            let relative = path[self.root.len()..].to_owned();
+
+            match fs::metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => {
+                    if !self.directories.values().any(|x| *x == path) {
+                        self.watch_tree(&path);
+                    }
+
+                    match scan_directory(&self.root, &path, &known) {
+                        Ok(tracks) => added.extend(tracks),
+                        Err(err) => log!(self.logger, "*** Could not scan '{}': {}", path, err),
+                    }
+                }
+                Ok(_) => {
+                    // Replaced, unless it is still a track.
+                    removed.push(relative.clone());
+
+                    match examine_file(&path, relative) {
+                        Ok(Some(track)) => {
+                            added.insert(track.id(), track);
+                        }
+                        Ok(None) => {}
+                        Err(err) => log!(self.logger, "*** Could not read '{}': {}", path, err),
+                    }
+                }
+                Err(_) => removed.push(relative),
+            }
+        }
+
+        let updated = self.state.library.update(|index| {
+            let new = index.updated(&removed, added);
+            (!new.same_tracks(index)).then_some(new)
+        });
+
+        let Some((old, new)) = updated else {
+            return;
+        };
+
+        let changes = publish_changes(&old, &new);
+
+        log!(self.logger, "Library has changed: {} tracks added, {} removed, {} in total",
+            changes.0, changes.1, new.track_count());
+
+        if let Err(err) = save_index(&new, self.state.library.index_path()) {
+            log!(self.logger, "*** Could not save the index to '{}': {}", self.state.library.index_path(), err);
+        }
+    }
+}
+
+/// Tells clients which tracks came and went. Returns how many of each.
+fn publish_changes(old: &MusicIndex, new: &MusicIndex) -> (usize, usize) {
+    let old_ids: HashSet<_> = old.ids().collect();
+    let new_ids: HashSet<_> = new.ids().collect();
+
+    let bus = event_bus();
+
+    for id in new_ids.difference(&old_ids) {
+        let name = new.track(id).map(|x| x.name.clone()).unwrap_or_default();
+        bus.publish(ServerEvent::TrackAdded { id: (*id).clone(), name });
+    }
+
+    for id in old_ids.difference(&new_ids) {
+        let name = old.track(id).map(|x| x.name.clone()).unwrap_or_default();
+        bus.publish(ServerEvent::TrackRemoved { id: (*id).clone(), name });
+    }
+
+    (new_ids.difference(&old_ids).count(), old_ids.difference(&new_ids).count())
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::common::logger::Verbosity;
+use crate::http::connection::{
+    ConnectionConfig, DEFAULT_BODY_TIMEOUT, DEFAULT_HEADER_TIMEOUT, DEFAULT_KEEP_ALIVE_TIMEOUT,
+    DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_REQUESTS, DEFAULT_REQUEST_TIMEOUT, DEFAULT_WRITE_TIMEOUT
+};
+use crate::http::cors::CorsPolicy;
+
+/// Settings a config file can have, named after the long flags of
+/// `zest serve`. These are the ones reloaded on SIGHUP.
+pub const RELOADABLE_SETTINGS: [&str; 10] = [
+    "origins",
+    "credentials",
+    "keep-alive",
+    "max-requests",
+    "max-body",
+    "header-timeout",
+    "body-timeout",
+    "request-timeout",
+    "write-timeout",
+    "verbose",
+];
+
+/// Settings by name, as text. Empty values mean defaults.
+pub type SettingValues = HashMap<String, String>;
+
+/// Settings that can be changed while the server runs.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub connection: ConnectionConfig,
+    pub verbosity: Verbosity,
+}
+
+/// Parses `name = value` lines. Empty lines and ones starting with `#` are
+/// skipped.
+pub fn parse_config(text: &str) -> Result<SettingValues, Error> {
+    let mut values = SettingValues::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |message: &str| {
+            Error::new(ErrorKind::InvalidData, format!("Line {}: {}", number + 1, message))
+        };
+
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(invalid("Expected 'name = value'"));
+        };
+
+        let name = name.trim();
+
+        if !RELOADABLE_SETTINGS.contains(&name) {
+            return Err(invalid(&format!("Unknown setting '{}'", name)));
+        }
+
+        values.insert(name.to_owned(), value.trim().to_owned());
+    }
+
+    Ok(values)
+}
+
+pub fn load_config(path: &str) -> Result<SettingValues, Error> {
+    parse_config(&fs::read_to_string(path)?)
+}
+
+/// Makes settings from their values. Missing and empty ones are defaults.
+pub fn make_settings(values: &SettingValues) -> Result<Settings, Error> {
+    let value = |name: &str| values.get(name).map_or("", |x| x.as_str());
+
+    let number = |name: &str, default: u64| -> Result<u64, Error> {
+        match value(name) {
+            "" => Ok(default),
+            x => x.parse::<u64>().map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, format!("Invalid value of '{}': '{}'", name, x))
+            }),
+        }
+    };
+
+    let seconds = |name: &str, default: u64| number(name, default).map(|x| Duration::from_secs(x.max(1)));
+
+    let allow_credentials = match value("credentials") {
+        "" | "false" | "no" | "0" => false,
+        "true" | "yes" | "1" => true,
+        x => {
+            let message = format!("Invalid value of 'credentials': '{}'", x);
+            return Err(Error::new(ErrorKind::InvalidInput, message));
+        }
+    };
+
+    let connection = ConnectionConfig {
+        keep_alive_timeout: seconds("keep-alive", DEFAULT_KEEP_ALIVE_TIMEOUT)?,
+        max_requests: number("max-requests", DEFAULT_MAX_REQUESTS as u64)? as usize,
+        max_body_size: number("max-body", DEFAULT_MAX_BODY_SIZE as u64)? as usize,
+        header_timeout: seconds("header-timeout", DEFAULT_HEADER_TIMEOUT)?,
+        body_timeout: seconds("body-timeout", DEFAULT_BODY_TIMEOUT)?,
+        request_timeout: seconds("request-timeout", DEFAULT_REQUEST_TIMEOUT)?,
+        write_timeout: seconds("write-timeout", DEFAULT_WRITE_TIMEOUT)?,
+        cors: Arc::new(CorsPolicy::from_list(value("origins"), allow_credentials)?),
+    };
+
+    let verbosity = Verbosity::from(number("verbose", 0)?.min(u8::MAX as u64) as u8);
+
+    Ok(Settings { connection, verbosity })
+}
+
+/// Connection settings that can be replaced while the server runs.
+/// Connections keep the ones they were accepted with.
+#[derive(Debug)]
+pub struct SharedConfig {
+    current: RwLock<ConnectionConfig>,
+}
+
+impl SharedConfig {
+    pub fn new(config: ConnectionConfig) -> Self {
+        SharedConfig { current: RwLock::new(config) }
+    }
+
+    pub fn get(&self) -> ConnectionConfig {
+        self.current.read().unwrap_or_else(|x| x.into_inner()).clone()
+    }
+
+    pub fn replace(&self, config: ConnectionConfig) {
+        *self.current.write().unwrap_or_else(|x| x.into_inner()) = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let values = parse_config("# Comment\n\norigins = https://a.com, https://b.com\nkeep-alive=10\n").unwrap();
+
+        assert_eq!(values["origins"], "https://a.com, https://b.com");
+        assert_eq!(values["keep-alive"], "10");
+
+        assert!(parse_config("threads = 4").is_err());
+        assert!(parse_config("keep-alive 10").is_err());
+    }
+
+    #[test]
+    fn test_make_settings() {
+        let values = parse_config("origins = https://a.com\ncredentials = yes\nwrite-timeout = 0\nverbose = 2").unwrap();
+        let settings = make_settings(&values).unwrap();
+
+        assert!(settings.connection.cors.allow_credentials);
+        assert!(!settings.connection.cors.is_allowed("https://c.com"));
+        assert_eq!(settings.connection.write_timeout, Duration::from_secs(1));
+        assert_eq!(settings.connection.keep_alive_timeout, Duration::from_secs(DEFAULT_KEEP_ALIVE_TIMEOUT));
+        assert_eq!(settings.verbosity, Verbosity::Debug);
+
+        let invalid = |text: &str| make_settings(&parse_config(text).unwrap()).is_err();
+
+        assert!(invalid("max-body = lots"));
+        assert!(invalid("credentials = maybe"));
+        assert!(invalid("credentials = true"));
+    }
+}
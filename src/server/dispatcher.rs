@@ -1,43 +1,251 @@
 use std::error::Error;
-use std::net::{TcpListener, TcpStream};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread::Builder;
+use std::time::Duration;
 
 use crate::common::logger::{Log, Logger, Verbosity};
-use crate::common::threads::ThreadPool;
+use crate::common::threads::{panic_message, PoolStats, ThreadPool};
 use crate::common::util::Am;
 use crate::http::connection::HttpConnection;
 use crate::http::response::HttpResponse;
+use crate::server::config::SharedConfig;
+use crate::http::stream::ConnectionStream;
+use crate::server::middleware::Handler;
+use crate::server::signals::{shutdown_requested, wait_for_shutdown};
+#[cfg(feature = "tls")]
+use crate::server::tls::TlsAcceptor;
 use crate::{log, log_geq, log_eq, log_leq};
 
-type DispatcherJob = fn(&mut HttpConnection, &Am<Logger>) -> Result<(), Box<dyn Error>>;
+type DispatcherJob = Arc<dyn Handler>;
 
-/// Starts the dispatcher, creating `ThreadPool` with N threads to handle incoming connections.
-/// Job is the function to execute on incoming connections.
+/// Clients turned away with 503 are asked to retry after this many seconds.
+const RETRY_AFTER_SECONDS: u32 = 2;
+
+/// What accepted sockets are wrapped into.
+pub enum Transport {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(Arc<TlsAcceptor>),
+}
+
+impl Transport {
+    fn scheme(&self) -> &'static str {
+        match self {
+            Transport::Plain => "http",
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => "https",
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn wrap(&self, stream: TcpStream, logger: &Am<Logger>) -> Result<ConnectionStream, std::io::Error> {
+        match self {
+            Transport::Plain => Ok(stream.into()),
+            #[cfg(feature = "tls")]
+            Transport::Tls(acceptor) => {
+                acceptor.reload_if_requested(logger);
+                acceptor.accept(stream)
+            }
+        }
+    }
+}
+
+/// How connections wait for their next request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DispatchMode {
+    /// Every connection has a worker to itself until it is closed, so there
+    /// can't be more open connections than workers.
+    Threaded,
+    /// Connections waiting for a request are parked in epoll, and only take a
+    /// worker once the request arrives.
+    #[cfg(target_os = "linux")]
+    Epoll,
+}
+
+/// What happens to connections when every worker is busy, and the queue is
+/// full as well.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverloadPolicy {
+    /// Answer with 503 right away.
+    Reject,
+    /// Stop accepting until a worker is free, leaving clients to the backlog
+    /// of the OS.
+    Block,
+}
+
+/// How the dispatcher spreads connections over workers.
+#[derive(Debug, Clone)]
+pub struct DispatcherConfig {
+    /// Workers kept even when there is nothing to do.
+    pub min_threads: usize,
+    /// Workers that may exist under load.
+    pub max_threads: usize,
+    /// Connections that may wait for a free worker.
+    pub queue_size: usize,
+    pub mode: DispatchMode,
+    pub overload: OverloadPolicy,
+    /// Where the pool reports its load.
+    pub stats: Arc<PoolStats>,
+}
+
+/// Starts the dispatcher, creating `ThreadPool` with min to max threads to handle incoming connections.
+/// Job is the handler called for every request on incoming connections.
 ///
 /// Before returning `Ok`, jobs should send their own response with `HttpConnection`.
-/// On `Err`, HTTP Code 500 is sent.
-pub fn start_dispatcher<'a>(
+/// On `Err`, HTTP Code 500 is sent, unless a response was already started.
+///
+/// Connections get the settings `config` has when they are accepted.
+///
+/// Returns once shutdown is requested and every worker has finished.
+pub fn start_dispatcher(
     address: String,
-    thread_count: usize,
+    dispatcher_config: DispatcherConfig,
+    transport: Transport,
+    config: Arc<SharedConfig>,
     logger: &Am<Logger>,
     job: DispatcherJob,
 ) -> Result<(), std::io::Error> {
-    log!(logger, "Binding to <http://{address}>...");
+    log!(logger, "Binding to <{}://{address}>...", transport.scheme());
 
     let listener = TcpListener::bind(address)?;
 
-    let thread_pool = ThreadPool::new(thread_count, logger.clone());
+    let thread_pool = ThreadPool::new(
+        dispatcher_config.min_threads,
+        dispatcher_config.max_threads,
+        dispatcher_config.queue_size,
+        dispatcher_config.stats,
+        logger.clone(),
+    );
 
-    log!(logger, "Started. Available threads: {}.", thread_pool.size());
+    log!(logger, "Started. Available threads: {} to {}. Mode: {:?}. Queue: {}, {:?} when full.",
+        thread_pool.min_size(), thread_pool.max_size(), dispatcher_config.mode, dispatcher_config.queue_size,
+        dispatcher_config.overload);
+
+    let dispatcher = Dispatcher { thread_pool, overload: dispatcher_config.overload };
+
+    match dispatcher_config.mode {
+        DispatchMode::Threaded => accept_threaded(listener, &dispatcher, transport, config, logger, job)?,
+        #[cfg(target_os = "linux")]
+        DispatchMode::Epoll => event_loop::run(listener, &dispatcher, transport, config, logger, job)?,
+    }
+
+    log!(logger, "Stopped accepting connections, waiting for {} busy workers...",
+        dispatcher.thread_pool.stats().busy());
+
+    // Joins the workers.
+    drop(dispatcher);
+
+    Ok(())
+}
+
+/// Hands connections to workers, unless they are overloaded.
+struct Dispatcher {
+    thread_pool: ThreadPool,
+    overload: OverloadPolicy,
+}
+
+impl Dispatcher {
+    /// Runs `serve` for the connection, or answers with 503 if the queue is
+    /// full and the policy says so.
+    fn dispatch<F>(&self, connection: HttpConnection, logger: &Am<Logger>, serve: F)
+    where
+        F: FnOnce(HttpConnection) + Send + 'static,
+    {
+        if self.overload == OverloadPolicy::Reject && self.thread_pool.is_full() {
+            self.thread_pool.stats().count_rejected();
+
+            log!(logger, "{} <= 503 Queue is full ({} waiting)",
+                connection.peer_string(), self.thread_pool.queue_depth());
+
+            return reject(connection);
+        }
+
+        log_geq!(logger, Verbosity::Debug, "Dispatching {:?}, {} waiting",
+            connection.stream(), self.thread_pool.queue_depth());
+
+        if let Err(err) = self.thread_pool.enqueue(move || serve(connection)) {
+            log!(logger, "*** An error has occured while dispatching stream: {}", err);
+        }
+    }
+}
+
+/// Asks the client to retry later, and closes the connection. TLS clients are
+/// disconnected without an answer, since it would take a handshake.
+fn reject(mut connection: HttpConnection) {
+    if connection.stream().is_tls() {
+        return;
+    }
+
+    // Unread request would make closing the socket reset the connection,
+    // and the client could miss the response.
+    let _ = connection.read_available();
+
+    // Workers are busy, the dispatcher itself can't wait for slow clients.
+    if connection.stream().tcp().set_nonblocking(true).is_err() {
+        return;
+    }
+
+    connection.close_after_response();
+
+    let _ = HttpResponse::new(503, "Service Unavailable")
+        .set_header("Retry-After", RETRY_AFTER_SECONDS.to_string())
+        .set_json_body(&"{ \"message\": \"Server is busy, try again later\" }")
+        .send(&mut connection);
+}
+
+/// Connects to the listener once shutdown is requested, so the blocked
+/// `accept` returns and notices it.
+fn spawn_waker(listener: &TcpListener) -> Result<(), std::io::Error> {
+    let mut address = listener.local_addr()?;
+
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            std::net::SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            std::net::SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+
+    Builder::new().name("waker".to_string()).spawn(move || {
+        wait_for_shutdown();
+        let _ = TcpStream::connect_timeout(&address, Duration::from_secs(1));
+    })?;
+
+    Ok(())
+}
+
+fn accept_threaded(
+    listener: TcpListener,
+    dispatcher: &Dispatcher,
+    transport: Transport,
+    config: Arc<SharedConfig>,
+    logger: &Am<Logger>,
+    job: DispatcherJob,
+) -> Result<(), std::io::Error> {
+    spawn_waker(&listener)?;
 
     for connection in listener.incoming() {
+        if shutdown_requested() {
+            break;
+        }
+
         match connection {
             Ok(stream) => {
-                log_geq!(logger, Verbosity::Debug, "Dispatching a thread for {:?}", stream);
+                let stream = match transport.wrap(stream, logger) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log!(logger, "*** An error has occured while accepting stream: {}", err);
+                        continue;
+                    }
+                };
 
                 let logger_clone = logger.clone();
+                let job_clone = job.clone();
 
-                thread_pool.enqueue(move || {
-                    let _ = handle_stream(stream, logger_clone, job);
+                dispatcher.dispatch(HttpConnection::new(stream, config.get()), logger, move |connection| {
+                    let _ = handle_connection(connection, logger_clone, job_clone);
                 });
             }
             Err(err) => {
@@ -49,40 +257,337 @@ pub fn start_dispatcher<'a>(
     Ok(())
 }
 
-fn handle_stream<'a>(
-    stream: TcpStream,
+/// Response for a request that could not be parsed, if the client should get one.
+fn parse_error_response<'a>(err: &std::io::Error) -> Option<HttpResponse<'a>> {
+    let response = match err.kind() {
+        ErrorKind::InvalidInput | ErrorKind::InvalidData => HttpResponse::new(400, "Bad Request"),
+        ErrorKind::OutOfMemory => HttpResponse::new(431, "Request Header Fields Too Large"),
+        ErrorKind::FileTooLarge => HttpResponse::new(413, "Content Too Large"),
+        ErrorKind::Unsupported => HttpResponse::new(417, "Expectation Failed"),
+        ErrorKind::TimedOut => HttpResponse::new(408, "Request Timeout"),
+        _ => return None,
+    };
+
+    Some(response)
+}
+
+/// Serves requests until the connection is no longer kept alive.
+fn handle_connection(
+    mut connection: HttpConnection,
     logger: Am<Logger>,
     job: DispatcherJob,
 ) -> Result<(), Box<dyn Error>> {
-    let connection = HttpConnection::new(stream);
+    while serve_request(&mut connection, &logger, &job)? {}
+
+    log_closing(&connection, &logger);
+
+    Ok(())
+}
+
+/// Reads one request and calls the job for it. Returns whether the
+/// connection is kept alive for the next one.
+fn serve_request(
+    connection: &mut HttpConnection,
+    logger: &Am<Logger>,
+    job: &DispatcherJob,
+) -> Result<bool, Box<dyn Error>> {
+    match connection.next_request() {
+        // Clients are told not to send more, as the server is going away.
+        Ok(true) if shutdown_requested() => connection.close_after_response(),
+        Ok(true) => {}
+        Ok(false) => return Ok(false),
+        Err(err) => {
+            log!(logger, "*** An error has occured while parsing connection: {}", err);
+
+            if let Some(response) = parse_error_response(&err) {
+                response
+                    .allow_origin(connection)
+                    .send(connection)?;
+            }
+
+            return Err(Box::new(err));
+        }
+    }
+
+    log_leq!(logger, Verbosity::Default, "{} => {:?} {:?}",
+        connection.peer_string(), connection.method(), connection.raw_path());
+
+    log_eq!(logger, Verbosity::Details, "Connection: {:?}", connection.stream());
 
-    if let Ok(mut connection) = connection {
-        log_leq!(logger, Verbosity::Default, "{} => {:?} {:?}",
-            connection.peer_string(), connection.method(), connection.raw_path());
+    log_geq!(logger, Verbosity::Debug, "Connection: {:?}", connection);
 
-        log_eq!(logger, Verbosity::Details, "Connection: {:?}", connection.stream());
+    let result = catch_unwind(AssertUnwindSafe(|| job.handle(connection, logger)))
+        .unwrap_or_else(|payload| {
+            log!(logger, "*** Handler has panicked on {:?} {:?} from {}: {}",
+                connection.method(), connection.raw_path(), connection.peer_string(),
+                panic_message(&*payload));
 
-        log_geq!(logger, Verbosity::Debug, "Connection: {:?}", connection);
+            Err(std::io::Error::other("Handler has panicked"))
+        });
 
-        if let Err(err) = job(&mut connection, &logger) {
+    if let Err(err) = result {
+        // The response may have been partially written, so the stream
+        // cannot be trusted to carry another one.
+        connection.close_after_response();
+
+        // Once a response has started, or the connection was upgraded,
+        // another one would be garbage in the middle of it.
+        if connection.response_status() == 0 {
             HttpResponse::new(500, "Internal Server Error")
-                .allow_all_origins(&connection)
-                .send(&mut connection)?;
+                .allow_origin(connection)
+                .send(connection)?;
+        }
 
-            log!(logger, "*** An internal error has occured: {}", err);
+        log!(logger, "*** An internal error has occured: {}", err);
 
-            Err(err)
-        } else {
-            log_geq!(logger, Verbosity::Debug, "Closing {:?}", connection.stream_mut());
+        return Err(Box::new(err));
+    }
+
+    Ok(connection.keep_alive())
+}
+
+fn log_closing(connection: &HttpConnection, logger: &Am<Logger>) {
+    log_geq!(logger, Verbosity::Debug, "Closing {:?} after {} requests",
+        connection.stream(), connection.requests_served());
+}
+
+/// Dispatching with epoll. The dispatcher thread waits for new connections
+/// and for parked ones to become readable, and hands those to workers. After
+/// a response, workers park the connection again instead of waiting for the
+/// next request themselves.
+///
+/// Workers still block while reading a request and writing the response,
+/// since handlers expect that, but idle clients cost no threads.
+#[cfg(target_os = "linux")]
+mod event_loop {
+    use std::collections::HashMap;
+    use std::io::{Error, ErrorKind};
+    use std::net::TcpListener;
+    use std::os::fd::AsRawFd;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::{log_closing, serve_request, Dispatcher, DispatcherJob, Transport};
+    use crate::common::logger::{Log, Logger, Verbosity};
+    use crate::common::util::Am;
+    use crate::http::connection::HttpConnection;
+    use crate::server::config::SharedConfig;
+    use crate::server::poller::Poller;
+    use crate::server::signals::shutdown_requested;
+    use crate::{log, log_geq};
+
+    const LISTENER_TOKEN: u64 = 0;
+    /// How often parked connections are checked for being idle for too long,
+    /// and the loop checks whether shutdown was requested.
+    const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+    struct Parked {
+        connection: HttpConnection,
+        /// Connection is closed if no request arrives by then.
+        deadline: Instant,
+    }
+
+    /// Connections waiting for their next request, by poller token.
+    struct ParkingLot {
+        poller: Poller,
+        parked: Mutex<HashMap<u64, Parked>>,
+        next_token: AtomicU64,
+    }
+
+    impl ParkingLot {
+        fn new() -> Result<Self, Error> {
+            Ok(ParkingLot {
+                poller: Poller::new()?,
+                parked: Mutex::new(HashMap::new()),
+                next_token: AtomicU64::new(LISTENER_TOKEN + 1),
+            })
+        }
+
+        fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Parked>> {
+            self.parked.lock().unwrap_or_else(|x| x.into_inner())
+        }
+
+        fn park(&self, connection: HttpConnection, timeout: Duration) -> Result<(), Error> {
+            let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+            let fd = connection.stream().tcp().as_raw_fd();
+
+            // Inserted before the socket is watched, so the event can't come
+            // first.
+            self.lock().insert(token, Parked { connection, deadline: Instant::now() + timeout });
+
+            if let Err(err) = self.poller.add_oneshot(fd, token) {
+                self.lock().remove(&token);
+                return Err(err);
+            }
 
-            drop(connection);
             Ok(())
         }
-    } else {
-        let err = connection.unwrap_err();
 
-        log!(logger, "*** An error has occured while parsing connection: {}", err);
+        fn unpark(&self, token: u64) -> Option<HttpConnection> {
+            let parked = self.lock().remove(&token)?;
+            let _ = self.poller.delete(parked.connection.stream().tcp().as_raw_fd());
+
+            Some(parked.connection)
+        }
+
+        /// Closes connections that have been idle past their deadline.
+        fn expire(&self, now: Instant, logger: &Am<Logger>) {
+            let expired: Vec<u64> = self.lock().iter()
+                .filter(|(_, x)| x.deadline <= now)
+                .map(|(token, _)| *token)
+                .collect();
+
+            for token in expired {
+                if let Some(connection) = self.unpark(token) {
+                    log_closing(&connection, logger);
+                }
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.lock().len()
+        }
+
+        /// Closes every parked connection.
+        fn close_all(&self, logger: &Am<Logger>) {
+            let tokens: Vec<u64> = self.lock().keys().copied().collect();
+
+            for token in tokens {
+                if let Some(connection) = self.unpark(token) {
+                    log_closing(&connection, logger);
+                }
+            }
+        }
+    }
+
+    /// Returns once shutdown is requested, after closing parked connections.
+    /// Connections being served are closed by their workers.
+    pub fn run(
+        listener: TcpListener,
+        dispatcher: &Dispatcher,
+        transport: Transport,
+        config: Arc<SharedConfig>,
+        logger: &Am<Logger>,
+        job: DispatcherJob,
+    ) -> Result<(), Error> {
+        let lot = Arc::new(ParkingLot::new()?);
+
+        listener.set_nonblocking(true)?;
+        lot.poller.add(listener.as_raw_fd(), LISTENER_TOKEN)?;
+
+        let mut tokens = Vec::new();
+
+        loop {
+            tokens.clear();
+            lot.poller.wait(&mut tokens, EXPIRY_INTERVAL)?;
+
+            if shutdown_requested() {
+                log_geq!(logger, Verbosity::Debug, "Closing {} parked connections", lot.len());
+
+                lot.close_all(logger);
+                return Ok(());
+            }
+
+            for token in tokens.iter().copied() {
+                if token == LISTENER_TOKEN {
+                    accept_all(&listener, &transport, &config, &lot, logger);
+                    continue;
+                }
+
+                let Some(connection) = lot.unpark(token) else {
+                    continue;
+                };
+
+                let lot_clone = lot.clone();
+                let logger_clone = logger.clone();
+                let job_clone = job.clone();
+
+                dispatcher.dispatch(connection, logger, move |connection| {
+                    serve_ready(connection, lot_clone, logger_clone, job_clone);
+                });
+            }
+
+            lot.expire(Instant::now(), logger);
+        }
+    }
+
+    /// New connections are parked until their first request arrives.
+    fn accept_all(
+        listener: &TcpListener,
+        transport: &Transport,
+        config: &SharedConfig,
+        lot: &ParkingLot,
+        logger: &Am<Logger>,
+    ) {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    log!(logger, "*** An error has occured while receiving stream: {}", err);
+                    return;
+                }
+            };
+
+            let stream = match transport.wrap(stream, logger) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log!(logger, "*** An error has occured while accepting stream: {}", err);
+                    continue;
+                }
+            };
+
+            let connection = HttpConnection::new(stream, config.get());
+
+            log_geq!(logger, Verbosity::Debug, "Parking {:?}, {} are parked",
+                connection.stream(), lot.len() + 1);
+
+            park(lot, connection, logger);
+        }
+    }
+
+    /// New connections get as long as headers may take to arrive, idle ones
+    /// are kept alive for `keep_alive_timeout`.
+    fn park(lot: &ParkingLot, connection: HttpConnection, logger: &Am<Logger>) {
+        // Event loop is gone, nothing would wake the connection up.
+        if shutdown_requested() {
+            return log_closing(&connection, logger);
+        }
+
+        let timeout = if connection.requests_served() == 0 {
+            connection.config().header_timeout
+        } else {
+            connection.config().keep_alive_timeout
+        };
+
+        if let Err(err) = lot.park(connection, timeout) {
+            log!(logger, "*** An error has occured while parking stream: {}", err);
+        }
+    }
+
+    /// Serves requests that already arrived, and parks the connection again.
+    fn serve_ready(mut connection: HttpConnection, lot: Arc<ParkingLot>, logger: Am<Logger>, job: DispatcherJob) {
+        match connection.read_available() {
+            Ok(0) => return log_closing(&connection, &logger),
+            Ok(_) => {}
+            // Handshake went further, but a request has yet to come.
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                return park(&lot, connection, &logger);
+            }
+            Err(_) => return,
+        }
+
+        loop {
+            match serve_request(&mut connection, &logger, &job) {
+                // Pipelined requests never make the socket readable again.
+                Ok(true) if connection.has_buffered_input() => continue,
+                Ok(true) => return park(&lot, connection, &logger),
+                Ok(false) => break,
+                Err(_) => return,
+            }
+        }
 
-        Err(Box::new(err))
+        log_closing(&connection, &logger);
     }
 }
@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::iter::{from_fn, once};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::common::logger::{Log, Logger};
+use crate::common::util::{json_string, Am};
+use crate::http::connection::HttpConnection;
+use crate::http::response::HttpResponse;
+use crate::http::sse::{format_comment, format_event, format_retry, last_event_id, EVENT_STREAM_CONTENT_TYPE};
+use crate::server::signals::shutdown_requested;
+use crate::server::state::ServerState;
+use crate::log;
+
+/// Events older than this many are dropped, and clients that missed them are
+/// asked to resync.
+const EVENT_HISTORY_SIZE: usize = 256;
+/// Idle streams get a comment this often, so proxies don't close them.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// Clients turned away because too many streams are open are asked to retry
+/// after this many seconds.
+const RETRY_AFTER_SECONDS: u32 = 30;
+
+static EVENT_BUS: EventBus = EventBus::new();
+
+/// Bus every part of the server publishes its events to.
+pub fn event_bus() -> &'static EventBus {
+    &EVENT_BUS
+}
+
+#[allow(unused)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerEvent {
+    /// Music index was replaced, and has this many tracks now.
+    IndexReloaded(usize),
+    TrackAdded { id: String, name: String },
+    TrackRemoved { id: String, name: String },
+    NowPlaying { id: String, name: String },
+    /// Server is going away, streams end after this one.
+    ShuttingDown,
+}
+
+impl ServerEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerEvent::IndexReloaded(_) => "index-reloaded",
+            ServerEvent::TrackAdded { .. } => "track-added",
+            ServerEvent::TrackRemoved { .. } => "track-removed",
+            ServerEvent::NowPlaying { .. } => "now-playing",
+            ServerEvent::ShuttingDown => "shutdown",
+        }
+    }
+
+    /// Event data as a JSON object.
+    pub fn data(&self) -> String {
+        match self {
+            ServerEvent::IndexReloaded(count) => format!("{{\"tracks\":{}}}", count),
+            ServerEvent::TrackAdded { id, name }
+            | ServerEvent::TrackRemoved { id, name }
+            | ServerEvent::NowPlaying { id, name } => {
+                format!("{{\"id\":{},\"name\":{}}}", json_string(id), json_string(name))
+            }
+            ServerEvent::ShuttingDown => "{}".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub event: ServerEvent,
+}
+
+#[derive(Debug)]
+struct EventHistory {
+    /// Id of the latest event, ids start from 1.
+    last_id: u64,
+    events: VecDeque<Event>,
+}
+
+impl EventHistory {
+    /// `None` if some of the events after `id` were already dropped, or `id`
+    /// is from the future, e.g. was given out before a restart.
+    fn events_after(&self, id: u64) -> Option<Vec<Event>> {
+        if id > self.last_id {
+            return None;
+        }
+
+        let oldest_id = self.events.front().map_or(self.last_id + 1, |x| x.id);
+
+        if id + 1 < oldest_id {
+            return None;
+        }
+
+        Some(self.events.iter().filter(|x| x.id > id).cloned().collect())
+    }
+}
+
+/// Keeps recent events, so clients that reconnect can get the ones they
+/// missed, and wakes up everyone waiting for new ones.
+#[derive(Debug)]
+pub struct EventBus {
+    history: Mutex<EventHistory>,
+    published: Condvar,
+    /// Streams that are open right now.
+    streams: AtomicUsize,
+}
+
+/// Place of an open stream, given back when dropped.
+pub struct StreamSlot<'a> {
+    streams: &'a AtomicUsize,
+}
+
+impl Drop for StreamSlot<'_> {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl EventBus {
+    pub const fn new() -> Self {
+        EventBus {
+            history: Mutex::new(EventHistory { last_id: 0, events: VecDeque::new() }),
+            published: Condvar::new(),
+            streams: AtomicUsize::new(0),
+        }
+    }
+
+    /// Takes a place for a stream, unless `limit` streams are open already.
+    pub fn open_stream(&self, limit: usize) -> Option<StreamSlot<'_>> {
+        self.streams
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| (x < limit).then_some(x + 1))
+            .ok()?;
+
+        Some(StreamSlot { streams: &self.streams })
+    }
+
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// Returns the id given to the event.
+    pub fn publish(&self, event: ServerEvent) -> u64 {
+        let mut history = self.history.lock().unwrap_or_else(|x| x.into_inner());
+
+        history.last_id += 1;
+        let id = history.last_id;
+
+        if history.events.len() >= EVENT_HISTORY_SIZE {
+            history.events.pop_front();
+        }
+        history.events.push_back(Event { id, event });
+
+        self.published.notify_all();
+
+        id
+    }
+
+    pub fn last_id(&self) -> u64 {
+        self.history.lock().unwrap_or_else(|x| x.into_inner()).last_id
+    }
+
+    /// Waits up to `timeout` for events published after `id`. Returns an
+    /// empty `Vec` on timeout, and `None` when the events can't be replayed.
+    pub fn wait_after(&self, id: u64, timeout: Duration) -> Option<Vec<Event>> {
+        let deadline = Instant::now() + timeout;
+        let mut history = self.history.lock().unwrap_or_else(|x| x.into_inner());
+
+        loop {
+            let events = history.events_after(id)?;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if !events.is_empty() || remaining.is_zero() {
+                return Some(events);
+            }
+
+            history = self.published.wait_timeout(history, remaining)
+                .map(|(x, _)| x)
+                .unwrap_or_else(|x| x.into_inner().0);
+        }
+    }
+}
+
+/// Keeps the connection open, sending events from the bus as they are
+/// published. Clients that reconnect with `Last-Event-ID` first get the events
+/// they missed, or a `resync` event when those are gone. The stream ends on
+/// shutdown.
+///
+/// Every stream holds a worker, so there can be at most `max_event_streams`
+/// of them, and clients past that get 503. Otherwise open streams could take
+/// every worker, and leave none for other requests.
+pub fn events_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), Error> {
+    let bus = event_bus();
+
+    let Some(_slot) = bus.open_stream(state.max_event_streams) else {
+        log!(logger, "{} <= 503 Too many event streams ({} open)", connection.peer_string(), bus.streams());
+
+        return HttpResponse::new(503, "Service Unavailable")
+            .set_header("Retry-After", RETRY_AFTER_SECONDS.to_string())
+            .set_json_body(&"{ \"message\": \"Too many event streams are open, try again later\" }")
+            .send(connection);
+    };
+
+    let mut last_id = last_event_id(connection).unwrap_or_else(|| bus.last_id());
+
+    log!(logger, "{} <= Event stream from #{}", connection.peer_string(), last_id);
+
+    // The stream only ends when the client goes away.
+    connection.close_after_response();
+
+    let events = from_fn(move || {
+        if shutdown_requested() {
+            return None;
+        }
+
+        let text = match bus.wait_after(last_id, KEEP_ALIVE_INTERVAL) {
+            Some(events) if events.is_empty() => format_comment("keep-alive"),
+            Some(events) => {
+                last_id = events.last().map_or(last_id, |x| x.id);
+
+                events.iter()
+                    .map(|x| format_event(Some(x.id), x.event.name(), &x.event.data()))
+                    .collect()
+            }
+            None => {
+                last_id = bus.last_id();
+                format_event(Some(last_id), "resync", "{}")
+            }
+        };
+
+        Some(Ok(text.into_bytes()))
+    });
+
+    let result = HttpResponse::new(200, "OK")
+        .set_header("Content-Type", EVENT_STREAM_CONTENT_TYPE)
+        .set_header("Cache-Control", "no-cache")
+        .set_chunks_body(Box::new(once(Ok(format_retry().into_bytes())).chain(events)))
+        .send(connection);
+
+    match result {
+        Err(err) if is_disconnect(&err) => {
+            log!(logger, "{} <= Event stream closed", connection.peer_string());
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn is_disconnect(err: &Error) -> bool {
+    matches!(err.kind(),
+        ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+        | ErrorKind::TimedOut | ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_bus_replay() {
+        let bus = EventBus::new();
+
+        let first = bus.publish(ServerEvent::TrackAdded { id: "1".into(), name: "a".into() });
+        bus.publish(ServerEvent::TrackRemoved { id: "2".into(), name: "b".into() });
+
+        let events = bus.wait_after(first, Duration::ZERO).unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].event, ServerEvent::TrackRemoved { id: "2".into(), name: "b".into() });
+        assert!(bus.wait_after(first + 1, Duration::ZERO).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_event_bus_missed_events() {
+        let bus = EventBus::new();
+
+        for _ in 0..EVENT_HISTORY_SIZE + 1 {
+            bus.publish(ServerEvent::IndexReloaded(0));
+        }
+
+        assert!(bus.wait_after(0, Duration::ZERO).is_none());
+        assert!(bus.wait_after(1, Duration::ZERO).is_some());
+        // Ids from before a restart.
+        assert!(bus.wait_after(1000, Duration::ZERO).is_none());
+    }
+
+    #[test]
+    fn test_event_bus_stream_limit() {
+        let bus = EventBus::new();
+
+        let first = bus.open_stream(2);
+        let second = bus.open_stream(2);
+
+        assert!(first.is_some() && second.is_some());
+        assert!(bus.open_stream(2).is_none());
+
+        drop(first);
+
+        assert_eq!(bus.streams(), 1);
+        assert!(bus.open_stream(2).is_some());
+        assert!(bus.open_stream(0).is_none());
+    }
+
+    #[test]
+    fn test_server_event_data() {
+        let event = ServerEvent::NowPlaying { id: "1f".into(), name: "\"x\"".into() };
+        assert_eq!(event.data(), "{\"id\":\"1f\",\"name\":\"\\\"x\\\"\"}");
+        assert_eq!(ServerEvent::IndexReloaded(3).data(), "{\"tracks\":3}");
+    }
+}
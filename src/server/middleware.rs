@@ -0,0 +1,109 @@
+use std::io::Error;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::common::logger::{Log, Logger, Verbosity};
+use crate::common::util::Am;
+use crate::http::connection::HttpConnection;
+use crate::log_geq;
+
+/// Anything that can respond to a request: plain functions, closures, or
+/// types carrying their own state, like `Router`.
+///
+/// Before returning `Ok`, handlers should send their own response with
+/// `HttpConnection`. On `Err`, HTTP Code 500 is sent.
+pub trait Handler: Send + Sync {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&mut HttpConnection, &Am<Logger>) -> Result<(), Error> + Send + Sync,
+{
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error> {
+        self(connection, logger)
+    }
+}
+
+/// Wraps handling of every request. A middleware can:
+/// - inspect or change the request through `HttpConnection`, before calling
+///   `next`.
+/// - short-circuit by sending its own response, and not calling `next`.
+/// - change the response with `HttpConnection::add_response_hook`, or act
+///   after it was sent, once `next` returns.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>, next: Next<'_>) -> Result<(), Error>;
+}
+
+/// Rest of the chain, ending with the handler.
+pub struct Next<'a> {
+    middlewares: &'a [Arc<dyn Middleware>],
+    handler: &'a dyn Handler,
+}
+
+impl Next<'_> {
+    pub fn run(self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error> {
+        match self.middlewares.split_first() {
+            Some((middleware, rest)) => {
+                let next = Next { middlewares: rest, handler: self.handler };
+                middleware.handle(connection, logger, next)
+            }
+            None => self.handler.handle(connection, logger),
+        }
+    }
+}
+
+/// Handler wrapped in middlewares. The first one added is the outermost, and
+/// sees the request first.
+pub struct Pipeline {
+    middlewares: Vec<Arc<dyn Middleware>>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Pipeline {
+    pub fn new<H: Handler + 'static>(handler: H) -> Self {
+        Pipeline {
+            middlewares: Vec::new(),
+            handler: Arc::new(handler),
+        }
+    }
+
+    pub fn wrap<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+}
+
+impl Handler for Pipeline {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error> {
+        Next { middlewares: &self.middlewares, handler: self.handler.as_ref() }.run(connection, logger)
+    }
+}
+
+/// Adds CORS headers to every response, according to the connection's policy.
+pub struct Cors;
+
+impl Middleware for Cors {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>, next: Next<'_>) -> Result<(), Error> {
+        connection.add_response_hook(Box::new(|response, connection| {
+            connection.cors().apply(response, connection)
+        }));
+
+        next.run(connection, logger)
+    }
+}
+
+/// Logs status of every response and how long it took to handle the request.
+pub struct Timing;
+
+impl Middleware for Timing {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>, next: Next<'_>) -> Result<(), Error> {
+        let start = Instant::now();
+        let result = next.run(connection, logger);
+
+        log_geq!(logger, Verbosity::Details, "{} <= {} in {:.2?}",
+            connection.peer_string(), connection.response_status(), start.elapsed());
+
+        result
+    }
+}
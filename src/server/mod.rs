@@ -1,2 +1,12 @@
+pub mod config;
 pub mod dispatcher;
-pub mod router;
\ No newline at end of file
+pub mod events;
+pub mod middleware;
+#[cfg(target_os = "linux")]
+pub mod poller;
+pub mod router;
+pub mod routes;
+pub mod signals;
+pub mod state;
+#[cfg(feature = "tls")]
+pub mod tls;
\ No newline at end of file
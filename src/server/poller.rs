@@ -0,0 +1,117 @@
+use std::io::Error;
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use crate::common::util::check_os_result;
+
+/// Thin wrapper around an epoll instance. Sockets are watched for being
+/// readable, and identified by tokens given when they are added.
+///
+/// Safe to use from several threads at once, which is how connections are
+/// handed back to it by workers.
+#[derive(Debug)]
+pub struct Poller {
+    fd: RawFd,
+}
+
+impl Poller {
+    pub fn new() -> Result<Self, Error> {
+        let fd = check_os_result(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?;
+
+        Ok(Poller { fd })
+    }
+
+    fn control(&self, operation: libc::c_int, fd: RawFd, flags: u32, token: u64) -> Result<(), Error> {
+        let mut event = libc::epoll_event { events: flags, u64: token };
+
+        check_os_result(unsafe { libc::epoll_ctl(self.fd, operation, fd, &mut event) })?;
+
+        Ok(())
+    }
+
+    /// Reports every time `fd` is readable, until it is deleted. For
+    /// listeners.
+    pub fn add(&self, fd: RawFd, token: u64) -> Result<(), Error> {
+        self.control(libc::EPOLL_CTL_ADD, fd, libc::EPOLLIN as u32, token)
+    }
+
+    /// Reports once, when `fd` becomes readable or is closed by the peer.
+    /// `fd` has to be deleted before it can be added again.
+    pub fn add_oneshot(&self, fd: RawFd, token: u64) -> Result<(), Error> {
+        let flags = libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLONESHOT;
+
+        self.control(libc::EPOLL_CTL_ADD, fd, flags as u32, token)
+    }
+
+    pub fn delete(&self, fd: RawFd) -> Result<(), Error> {
+        self.control(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    /// Waits up to `timeout` for sockets to become ready, and appends their
+    /// tokens to `tokens`. Interruptions by signals are not errors.
+    pub fn wait(&self, tokens: &mut Vec<u64>, timeout: Duration) -> Result<(), Error> {
+        const MAX_EVENTS: usize = 256;
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+
+        let count = unsafe {
+            libc::epoll_wait(self.fd, events.as_mut_ptr(), MAX_EVENTS as libc::c_int, timeout)
+        };
+
+        if count < 0 {
+            let err = Error::last_os_error();
+
+            return match err.raw_os_error() {
+                Some(libc::EINTR) => Ok(()),
+                _ => Err(err),
+            };
+        }
+
+        tokens.extend(events[..count as usize].iter().map(|x| x.u64));
+
+        Ok(())
+    }
+}
+
+impl Drop for Poller {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_poller_oneshot() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        let poller = Poller::new().unwrap();
+        let mut tokens = Vec::new();
+
+        poller.add_oneshot(server.as_raw_fd(), 7).unwrap();
+        poller.wait(&mut tokens, Duration::ZERO).unwrap();
+        assert!(tokens.is_empty());
+
+        client.write_all(b"GET").unwrap();
+        poller.wait(&mut tokens, Duration::from_secs(1)).unwrap();
+        assert_eq!(tokens, [7]);
+
+        // Disarmed until deleted and added again.
+        tokens.clear();
+        poller.wait(&mut tokens, Duration::ZERO).unwrap();
+        assert!(tokens.is_empty());
+
+        poller.delete(server.as_raw_fd()).unwrap();
+        poller.add_oneshot(server.as_raw_fd(), 8).unwrap();
+        poller.wait(&mut tokens, Duration::from_secs(1)).unwrap();
+        assert_eq!(tokens, [8]);
+    }
+}
@@ -1,27 +1,320 @@
-use std::error::Error;
+use std::io;
+use std::sync::Arc;
 
 use crate::{
     common::logger::Logger,
-    common::util::Am,
-    http::{connection::{HttpConnection, HttpMethod}, response::HttpResponse},
-    music::endpoint::{chunk_handler, list_handler},
+    common::util::{url_decode, Am},
+    http::{
+        connection::{HttpConnection, HttpMethod, PathParams},
+        response::HttpResponse,
+    },
+    server::middleware::Handler,
+    log, Log,
 };
 
-pub fn handle_routes<'a>(
-    connection: &mut HttpConnection,
-    logger: &Am<Logger>,
-) -> Result<(), Box<dyn Error>> {
-    let method_and_path = (connection.method(), connection.path().as_ref());
+/// Order methods are listed in `Allow`.
+const METHOD_ORDER: [HttpMethod; 7] = [
+    HttpMethod::GET,
+    HttpMethod::HEAD,
+    HttpMethod::POST,
+    HttpMethod::PUT,
+    HttpMethod::PATCH,
+    HttpMethod::DELETE,
+    HttpMethod::OPTIONS,
+];
 
-    let result = match method_and_path {
-        (HttpMethod::GET, "/api/v1/music/get") => chunk_handler(connection, logger),
-        (HttpMethod::GET, "/api/v1/music/all") => list_handler(connection, logger),
-        _ => not_found().send(connection),
-    }?;
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `:name`, matches any single segment.
+    Param(String),
+}
+
+/// Segments of a path, ignoring empty ones, so `/a//b/` is the same as `/a/b`.
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|x| !x.is_empty())
+}
+
+/// Segments of a request path as it was sent, decoded one by one, so an
+/// encoded `/` stays within its segment. `None` if one can't be decoded.
+fn decode_path(path: &str) -> Option<Vec<String>> {
+    split_path(path).map(|x| url_decode(x).ok()).collect()
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    split_path(pattern)
+        .map(|x| match x.strip_prefix(':') {
+            Some(name) => Segment::Param(name.to_owned()),
+            None => Segment::Literal(x.to_owned()),
+        })
+        .collect()
+}
+
+struct Route {
+    method: HttpMethod,
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+}
 
-    Ok(result)
+impl Route {
+    fn matches(&self, path: &[String]) -> Option<PathParams> {
+        if self.segments.len() != path.len() {
+            return None;
+        }
+
+        let mut params = PathParams::default();
+
+        for (segment, value) in self.segments.iter().zip(path) {
+            match segment {
+                Segment::Literal(literal) if literal == value => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(name) => params.insert(name.as_str(), value.as_str()),
+            }
+        }
+
+        Some(params)
+    }
+
+    /// Routes with more literal segments win over ones with parameters, so
+    /// `/tracks/all` can coexist with `/tracks/:name`.
+    fn literal_count(&self) -> usize {
+        self.segments.iter().filter(|x| matches!(x, Segment::Literal(_))).count()
+    }
+}
+
+pub enum RouteMatch {
+    Found(Arc<dyn Handler>, PathParams),
+    /// The path exists, but not with this method.
+    MethodNotAllowed,
+    NotFound,
+}
+
+/// Maps methods and path patterns to handlers. Patterns are made of literal
+/// segments and `:name` parameters, e.g. `/tracks/:name/chunks/:n`, values of
+/// which handlers get with `HttpConnection::path_params`.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+#[allow(unused)]
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    fn add_route(&mut self, method: HttpMethod, pattern: &str, handler: Arc<dyn Handler>) -> &mut Self {
+        self.routes.push(Route {
+            method,
+            segments: parse_pattern(pattern),
+            handler,
+        });
+        self
+    }
+
+    pub fn route<H: Handler + 'static>(&mut self, method: HttpMethod, pattern: &str, handler: H) -> &mut Self {
+        self.add_route(method, pattern, Arc::new(handler))
+    }
+
+    /// Registers the handler for both `GET` and `HEAD`.
+    pub fn get<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        let handler: Arc<dyn Handler> = Arc::new(handler);
+
+        self.add_route(HttpMethod::GET, pattern, handler.clone())
+            .add_route(HttpMethod::HEAD, pattern, handler)
+    }
+
+    pub fn post<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.route(HttpMethod::POST, pattern, handler)
+    }
+
+    pub fn put<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.route(HttpMethod::PUT, pattern, handler)
+    }
+
+    pub fn delete<H: Handler + 'static>(&mut self, pattern: &str, handler: H) -> &mut Self {
+        self.route(HttpMethod::DELETE, pattern, handler)
+    }
+
+    /// Registers routes made by `build` under a shared prefix.
+    pub fn group<F: FnOnce(&mut Router)>(&mut self, prefix: &str, build: F) -> &mut Self {
+        let mut group = Router::new();
+        build(&mut group);
+
+        let prefix = parse_pattern(prefix);
+
+        for mut route in group.routes {
+            route.segments.splice(0..0, prefix.iter().cloned());
+            self.routes.push(route);
+        }
+
+        self
+    }
+
+    /// Path is expected undecoded, as in the request target.
+    pub fn find(&self, method: HttpMethod, path: &str) -> RouteMatch {
+        let Some(path) = decode_path(path) else {
+            return RouteMatch::NotFound;
+        };
+
+        let mut path_exists = false;
+        let mut best: Option<(&Route, PathParams)> = None;
+
+        for route in &self.routes {
+            let Some(params) = route.matches(&path) else {
+                continue;
+            };
+
+            path_exists = true;
+
+            if route.method != method {
+                continue;
+            }
+
+            if best.as_ref().is_none_or(|(x, _)| route.literal_count() > x.literal_count()) {
+                best = Some((route, params));
+            }
+        }
+
+        match best {
+            Some((route, params)) => RouteMatch::Found(route.handler.clone(), params),
+            None if path_exists => RouteMatch::MethodNotAllowed,
+            None => RouteMatch::NotFound,
+        }
+    }
+
+    /// Methods the path can be requested with, `OPTIONS` included. Empty if
+    /// there are none. `*` means the whole server.
+    pub fn allowed_methods(&self, path: &str) -> Vec<HttpMethod> {
+        let Some(path) = decode_path(path) else {
+            return Vec::new();
+        };
+
+        let is_allowed = |method: &HttpMethod| {
+            *method == HttpMethod::OPTIONS || self.routes.iter().any(|x| {
+                x.method == *method && (path == ["*"] || x.matches(&path).is_some())
+            })
+        };
+
+        let methods: Vec<HttpMethod> = METHOD_ORDER.into_iter().filter(is_allowed).collect();
+
+        if methods.len() > 1 { methods } else { Vec::new() }
+    }
+
+}
+
+/// Finds the route for a request and calls its handler. `OPTIONS` is answered
+/// for every known path, and requests with a method the path doesn't have get
+/// 405.
+impl Handler for Router {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), io::Error> {
+        let method = connection.method();
+        let path = connection.target().split('?').next().unwrap_or("/").to_owned();
+
+        if method == HttpMethod::OPTIONS {
+            let allowed = self.allowed_methods(&path);
+
+            if allowed.is_empty() {
+                return not_found().send(connection);
+            }
+
+            return HttpResponse::new(204, "No Content")
+                .set_header("Allow", allow_header(&allowed))
+                .send(connection);
+        }
+
+        match self.find(method, &path) {
+            RouteMatch::Found(handler, params) => {
+                connection.set_path_params(params);
+                handler.handle(connection, logger)
+            }
+            RouteMatch::MethodNotAllowed => {
+                let allowed = self.allowed_methods(&path);
+
+                log!(logger, "{} <= 405 Method not allowed", connection.peer_string());
+
+                HttpResponse::new(405, "Method Not Allowed")
+                    .set_header("Allow", allow_header(&allowed))
+                    .set_json_body(&"{ \"message\": \"Method is not allowed\" }")
+                    .send(connection)
+            }
+            RouteMatch::NotFound => not_found().send(connection),
+        }
+    }
+}
+
+fn allow_header(methods: &[HttpMethod]) -> String {
+    methods.iter().map(|x| format!("{:?}", x)).collect::<Vec<_>>().join(", ")
 }
 
 fn not_found<'a>() -> HttpResponse<'a> {
     HttpResponse::new(404, "Not Found").set_json_body(&"{ \"message\": \"Page not found\" }")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn first(_: &mut HttpConnection, _: &Am<Logger>) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn second(_: &mut HttpConnection, _: &Am<Logger>) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+
+        router.group("/api/v2", |api| {
+            api.get("/tracks/all", first)
+                .get("/tracks/:name", second)
+                .post("/tracks/:name/chunks/:n", first);
+        });
+
+        router
+    }
+
+    #[test]
+    fn test_router_path_params() {
+        let RouteMatch::Found(_, params) = router().find(HttpMethod::POST, "/api/v2/tracks/abc/chunks/12/") else {
+            panic!("Route should be found");
+        };
+
+        assert_eq!(params.get_str("name"), Some("abc"));
+        assert_eq!(params.get::<usize>("n"), Some(12));
+        assert_eq!(params.get::<usize>("name"), None);
+    }
+
+    #[test]
+    fn test_router_prefers_literals() {
+        let RouteMatch::Found(_, params) = router().find(HttpMethod::GET, "/api/v2/tracks/all") else {
+            panic!("Route should be found");
+        };
+
+        assert_eq!(params.get_str("name"), None);
+    }
+
+    #[test]
+    fn test_router_encoded_slash_in_param() {
+        let RouteMatch::Found(_, params) = router().find(HttpMethod::GET, "/api/v2/tracks/a%2Fb%20c") else {
+            panic!("Route should be found");
+        };
+
+        assert_eq!(params.get_str("name"), Some("a/b c"));
+
+        assert!(matches!(router().find(HttpMethod::GET, "/api/v2/tracks/a/b"), RouteMatch::NotFound));
+        assert!(matches!(router().find(HttpMethod::GET, "/api/v2/tracks%2Fall"), RouteMatch::NotFound));
+    }
+
+    #[test]
+    fn test_router_method_not_allowed() {
+        let router = router();
+
+        assert!(matches!(router.find(HttpMethod::DELETE, "/api/v2/tracks/a"), RouteMatch::MethodNotAllowed));
+        assert!(matches!(router.find(HttpMethod::GET, "/api/v2/nothing"), RouteMatch::NotFound));
+
+        assert_eq!(allow_header(&router.allowed_methods("/api/v2/tracks/a")), "GET, HEAD, OPTIONS");
+        assert!(router.allowed_methods("/api/v2/nothing").is_empty());
+    }
+}
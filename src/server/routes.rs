@@ -0,0 +1,101 @@
+use std::io;
+use std::sync::Arc;
+
+use crate::{
+    common::logger::Logger,
+    common::util::Am,
+    http::{
+        connection::{HttpConnection, HttpMethod},
+        response::HttpResponse,
+        websocket::{Message, WebSocket},
+    },
+    music::endpoint::{chunk_handler, info_handler, list_handler, playing_handler, stream_handler},
+    server::events::events_handler,
+    server::middleware::{Cors, Pipeline, Timing},
+    server::router::Router,
+    server::state::ServerState,
+    log, Log,
+};
+
+fn make_router(state: &Arc<ServerState>, websocket_echo: bool) -> Router {
+    let mut router = Router::new();
+
+    router.group("/api/v1", |api| {
+        api.group("/music", |music| {
+            music.get("/get", state.bind(chunk_handler))
+                .get("/all", state.bind(list_handler))
+                .get("/stream", state.bind(stream_handler))
+                .get("/info", state.bind(info_handler))
+                .post("/playing", state.bind(playing_handler));
+        });
+
+        api.get("/events", state.bind(events_handler))
+            .get("/status", state.bind(status_handler));
+
+        if websocket_echo {
+            api.route(HttpMethod::GET, "/ws/echo", echo_handler);
+        }
+    });
+
+    router.group("/api/v2/tracks", |tracks| {
+        tracks.get("/", state.bind(list_handler))
+            .get("/:id", state.bind(stream_handler))
+            .get("/:id/chunks/:chunk", state.bind(chunk_handler))
+            .get("/:id/info", state.bind(info_handler))
+            .post("/:id/playing", state.bind(playing_handler));
+    });
+
+    router
+}
+
+/// Job for the dispatcher: routes of the API, wrapped in middlewares every
+/// request goes through. Stats in `state` are of the pool the job runs in.
+///
+/// WebSocket echo is only for debugging, and is left out unless asked for.
+pub fn make_app(state: Arc<ServerState>, websocket_echo: bool) -> Pipeline {
+    Pipeline::new(make_router(&state, websocket_echo))
+        .wrap(Timing)
+        .wrap(Cors)
+}
+
+/// Load of the server, for monitoring.
+fn status_handler(connection: &mut HttpConnection, logger: &Am<Logger>, state: &ServerState) -> Result<(), io::Error> {
+    log!(logger, "{} <= Status", connection.peer_string());
+
+    HttpResponse::new(200, "OK")
+        .set_header("Cache-Control", "no-store")
+        .set_json_body(&state.stats.to_json())
+        .send(connection)
+}
+
+/// Sends every message back, so clients can check that WebSockets work.
+/// Sockets idle for longer than the keep-alive timeout are closed, so they
+/// don't hold a worker forever.
+fn echo_handler(connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), io::Error> {
+    let Some(mut socket) = WebSocket::accept(connection)? else {
+        return Ok(());
+    };
+
+    let idle_timeout = socket.connection().config().keep_alive_timeout;
+    socket.connection().stream().tcp().set_read_timeout(Some(idle_timeout))?;
+
+    log!(logger, "{} <= 101 WebSocket echo", socket.connection().peer_string());
+
+    loop {
+        let message = match socket.read_message() {
+            Ok(message) => message,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                log!(logger, "{} <= WebSocket echo was idle, closing", socket.connection().peer_string());
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        match message {
+            Message::Text(text) => socket.send_text(text)?,
+            Message::Binary(bytes) => socket.send_binary(bytes)?,
+            Message::Close(..) => return Ok(()),
+            Message::Ping(_) | Message::Pong(_) => {}
+        }
+    }
+}
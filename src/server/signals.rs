@@ -0,0 +1,171 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+static SIGNALS: SignalCounters = SignalCounters::new();
+
+/// How often `wait_for_shutdown` checks whether it is time.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often `drain` checks whether requests have finished.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What signals have asked for so far.
+struct SignalCounters {
+    /// Incremented on every SIGHUP.
+    reload: AtomicUsize,
+    /// Incremented on every SIGTERM and SIGINT.
+    shutdown: AtomicUsize,
+}
+
+impl SignalCounters {
+    const fn new() -> Self {
+        SignalCounters { reload: AtomicUsize::new(0), shutdown: AtomicUsize::new(0) }
+    }
+
+    fn request_reload(&self) {
+        self.reload.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns whether shutdown was already requested before.
+    fn request_shutdown(&self) -> bool {
+        self.shutdown.fetch_add(1, Ordering::SeqCst) > 0
+    }
+
+    fn reload_requests(&self) -> usize {
+        self.reload.load(Ordering::SeqCst)
+    }
+
+    fn shutdown_requested(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst) > 0
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_reload_signal(_: libc::c_int) {
+    SIGNALS.request_reload();
+}
+
+#[cfg(unix)]
+extern "C" fn on_shutdown_signal(signal: libc::c_int) {
+    // Another signal while shutting down means there is no time to wait.
+    if SIGNALS.request_shutdown() {
+        // SAFETY: `_exit` is async-signal-safe.
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// Installs signal handlers. Blocking calls are restarted after a signal, so
+/// nothing else has to care about `EINTR`.
+#[cfg(unix)]
+pub fn install_signal_handlers() -> Result<(), std::io::Error> {
+    let handlers: [(libc::c_int, extern "C" fn(libc::c_int)); 3] = [
+        (libc::SIGHUP, on_reload_signal),
+        (libc::SIGTERM, on_shutdown_signal),
+        (libc::SIGINT, on_shutdown_signal),
+    ];
+
+    for (signal, handler) in handlers {
+        // SAFETY: Handlers only touch atomics and call `_exit`, which are
+        // async-signal-safe.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handler as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+
+            if libc::sigaction(signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn install_signal_handlers() -> Result<(), std::io::Error> {
+    Ok(())
+}
+
+/// Number of reloads requested so far. Whoever reloads something remembers
+/// the last value it saw, and reloads once this one differs.
+pub fn reload_requests() -> usize {
+    SIGNALS.reload_requests()
+}
+
+/// Whether the server should stop accepting connections, and exit once the
+/// ones in flight are served.
+pub fn shutdown_requested() -> bool {
+    SIGNALS.shutdown_requested()
+}
+
+/// Blocks until shutdown is requested.
+pub fn wait_for_shutdown() {
+    while !shutdown_requested() {
+        sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+}
+
+/// Waits for requests in flight to finish, calling `tick` in between, e.g. to
+/// flush the log. Returns `false` if `timeout` passed first.
+pub fn drain(timeout: Duration, mut is_finished: impl FnMut() -> bool, mut tick: impl FnMut()) -> bool {
+    let deadline = Instant::now() + timeout;
+
+    while !is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+
+        tick();
+        sleep(DRAIN_POLL_INTERVAL);
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_counters() {
+        let signals = SignalCounters::new();
+
+        assert!(!signals.shutdown_requested());
+        assert!(!signals.request_shutdown());
+        assert!(signals.shutdown_requested());
+        // Second one would exit right away.
+        assert!(signals.request_shutdown());
+
+        signals.request_reload();
+        signals.request_reload();
+        assert_eq!(signals.reload_requests(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sighup_requests_reload() {
+        install_signal_handlers().unwrap();
+
+        let before = reload_requests();
+
+        // SAFETY: The handler only increments a counter.
+        unsafe { libc::raise(libc::SIGHUP) };
+
+        assert!(reload_requests() > before);
+        assert!(!shutdown_requested());
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut polls = 0;
+        assert!(drain(Duration::from_secs(10), || { polls += 1; polls > 3 }, || {}));
+
+        let start = Instant::now();
+        let mut ticks = 0;
+
+        assert!(!drain(Duration::from_millis(50), || false, || ticks += 1));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(ticks > 0);
+    }
+}
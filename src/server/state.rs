@@ -0,0 +1,40 @@
+use std::io::Error;
+use std::sync::Arc;
+
+use crate::common::logger::Logger;
+use crate::common::threads::PoolStats;
+use crate::common::util::Am;
+use crate::http::connection::HttpConnection;
+use crate::music::index::Library;
+use crate::server::middleware::Handler;
+
+/// Everything handlers share while the server runs.
+#[derive(Debug)]
+pub struct ServerState {
+    pub library: Library,
+    /// Load of the pool serving the API.
+    pub stats: Arc<PoolStats>,
+    /// Event streams that may be open at once. Each holds a worker.
+    pub max_event_streams: usize,
+}
+
+/// Handler that needs the server state.
+pub type StateHandler = fn(&mut HttpConnection, &Am<Logger>, &ServerState) -> Result<(), Error>;
+
+impl ServerState {
+    /// Makes a route handler, which calls `handler` with this state.
+    pub fn bind(self: &Arc<Self>, handler: StateHandler) -> WithState {
+        WithState { state: self.clone(), handler }
+    }
+}
+
+pub struct WithState {
+    state: Arc<ServerState>,
+    handler: StateHandler,
+}
+
+impl Handler for WithState {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), Error> {
+        (self.handler)(connection, logger, &self.state)
+    }
+}
@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use rustls::crypto::ring::default_provider;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::common::logger::{Log, Logger};
+use crate::common::util::Am;
+use crate::http::connection::HttpConnection;
+use crate::http::response::HttpResponse;
+use crate::http::stream::ConnectionStream;
+use crate::log;
+use crate::server::middleware::Handler;
+use crate::server::signals::reload_requests;
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, err.to_string())
+}
+
+/// Reads a PEM certificate chain and a private key, and makes a server
+/// configuration out of them.
+fn load_config(cert_path: &str, key_path: &str) -> Result<ServerConfig, io::Error> {
+    let with_path = |path: &str, err: io::Error| {
+        io::Error::new(err.kind(), format!("'{}': {}", path, err))
+    };
+
+    let mut cert_reader = BufReader::new(File::open(cert_path).map_err(|x| with_path(cert_path, x))?);
+    let certs = rustls_pemfile::certs(&mut cert_reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|x| with_path(cert_path, x))?;
+
+    if certs.is_empty() {
+        return Err(with_path(cert_path, invalid_data("No certificates found")));
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path).map_err(|x| with_path(key_path, x))?);
+    let key = rustls_pemfile::private_key(&mut key_reader)
+        .map_err(|x| with_path(key_path, x))?
+        .ok_or_else(|| with_path(key_path, invalid_data("No private key found")))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(config)
+}
+
+/// Wraps accepted sockets into TLS sessions. Certificates are read again
+/// after SIGHUP, and new connections use them from then on.
+pub struct TlsAcceptor {
+    cert_path: String,
+    key_path: String,
+    config: RwLock<Arc<ServerConfig>>,
+    /// Value of `reload_requests()` the current configuration was loaded at.
+    loaded_at: AtomicUsize,
+}
+
+impl TlsAcceptor {
+    pub fn new(cert_path: String, key_path: String) -> Result<Self, io::Error> {
+        let loaded_at = reload_requests();
+        let config = load_config(&cert_path, &key_path)?;
+
+        Ok(TlsAcceptor {
+            cert_path,
+            key_path,
+            config: RwLock::new(Arc::new(config)),
+            loaded_at: AtomicUsize::new(loaded_at),
+        })
+    }
+
+    /// Loads certificates again if SIGHUP was received since they were loaded.
+    /// On failure, the old ones are kept.
+    pub fn reload_if_requested(&self, logger: &Am<Logger>) {
+        let requests = reload_requests();
+
+        if self.loaded_at.swap(requests, Ordering::SeqCst) == requests {
+            return;
+        }
+
+        match load_config(&self.cert_path, &self.key_path) {
+            Ok(config) => {
+                *self.config.write().unwrap_or_else(|x| x.into_inner()) = Arc::new(config);
+                log!(logger, "Reloaded TLS certificate from '{}'.", self.cert_path);
+            }
+            Err(err) => {
+                log!(logger, "*** Could not reload TLS certificate, keeping the old one: {}", err);
+            }
+        }
+    }
+
+    pub fn accept(&self, stream: TcpStream) -> Result<ConnectionStream, io::Error> {
+        let config = self.config.read().unwrap_or_else(|x| x.into_inner()).clone();
+        let connection = ServerConnection::new(config).map_err(invalid_data)?;
+
+        Ok(ConnectionStream::Tls(Box::new(StreamOwned::new(connection, stream))))
+    }
+}
+
+/// `Host` without the port. `None` if it has characters a host can't have,
+/// since it goes straight into `Location`.
+fn host_name(host: &str) -> Option<&str> {
+    let name = if host.starts_with('[') {
+        // IPv6 literal, e.g. [::1]:80.
+        &host[..=host.find(']')?]
+    } else {
+        host.split(':').next()?
+    };
+
+    let is_valid = !name.is_empty() && name.chars()
+        .all(|x| x.is_ascii_alphanumeric() || matches!(x, '.' | '-' | ':' | '[' | ']'));
+
+    is_valid.then_some(name)
+}
+
+/// Job for the plain HTTP port, sending every request to the same path over
+/// HTTPS.
+pub struct HttpsRedirect {
+    /// Port HTTPS is served on.
+    pub port: u32,
+}
+
+impl Handler for HttpsRedirect {
+    fn handle(&self, connection: &mut HttpConnection, logger: &Am<Logger>) -> Result<(), io::Error> {
+        let Some(host) = connection.headers().get("host").and_then(|x| host_name(x.trim())) else {
+            log!(logger, "{} <= 400 No host to redirect to", connection.peer_string());
+
+            return HttpResponse::new(400, "Bad Request")
+                .set_json_body(&"{ \"message\": \"Host header is missing or invalid\" }")
+                .send(connection);
+        };
+
+        // Target is sent back as it came, decoding it would change its meaning.
+        let location = if self.port == 443 {
+            format!("https://{}{}", host, connection.target())
+        } else {
+            format!("https://{}:{}{}", host, self.port, connection.target())
+        };
+
+        log!(logger, "{} <= 308 {}", connection.peer_string(), location);
+
+        HttpResponse::new(308, "Permanent Redirect")
+            .set_header("Location", location)
+            .send(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_name() {
+        assert_eq!(host_name("example.com:80"), Some("example.com"));
+        assert_eq!(host_name("example.com"), Some("example.com"));
+        assert_eq!(host_name("[::1]:8080"), Some("[::1]"));
+        assert_eq!(host_name("evil.com/\"x"), None);
+        assert_eq!(host_name(""), None);
+    }
+}